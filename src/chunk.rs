@@ -1,14 +1,23 @@
 mod codes;
+mod compare;
 mod constants;
 mod disassemble;
+mod global_slots;
 mod lines;
+mod locals;
+mod serialize;
+mod stack_effect;
 
-use crate::opcode::OpCode::Constant;
 use crate::opcode::{Byte, OpCode, Value};
+use crate::vm::CompilationErrorReason::TooManyGlobalSlots;
 use crate::vm::InterpretError;
+use crate::vm::InterpretError::{CompileError, RuntimeErrorWithReason};
 use codes::Codes;
+pub use compare::Difference;
 use constants::Constants;
+use global_slots::GlobalSlots;
 use lines::Lines;
+use locals::Locals;
 
 // static strings part of the binary
 #[derive(Debug)]
@@ -83,6 +92,10 @@ impl Strings {
     pub fn get(&self, index: usize) -> Option<&String> {
         self.0.get(index)
     }
+
+    pub fn iter(&self) -> impl Iterator<Item = &str> {
+        self.0.iter().map(|it| it.as_str())
+    }
 }
 
 #[derive(Debug)]
@@ -90,8 +103,12 @@ pub struct Chunk {
     pub(crate) code: Codes,
     pub(crate) constants: Constants,
     pub(crate) strings: Strings,
+    // Globals the slot instructions refer to
+    pub(crate) global_slots: GlobalSlots,
     // Tracks the src line the corresponding opcode refers to for error reporting
     pub(crate) lines: Lines,
+    // Names of the local vars per stack slot for debugging
+    pub(crate) locals: Locals,
 }
 
 impl Default for Chunk {
@@ -106,20 +123,27 @@ impl Chunk {
             code: Codes::new(),
             constants: Constants::new(),
             strings: Strings::new(),
+            global_slots: GlobalSlots::new(),
             lines: Lines::new(),
+            locals: Locals::new(),
         }
     }
 
     fn write_byte(&mut self, byte: Byte, line: usize) {
         let at = self.code.add(byte);
         // Keeps track which src line this belongs to
-        self.lines.insert(at, line);
+        self.lines.push(at, line);
     }
 
     fn add_constant(&mut self, value: Value) -> usize {
         self.constants.add(value)
     }
 
+    // Writes any byte, even one that isn't valid code, handy to craft malformed chunks
+    pub fn push_raw_byte(&mut self, byte: Byte, line: usize) {
+        self.write_byte(byte, line)
+    }
+
     pub fn write_code(&mut self, op_code: OpCode, line: usize) {
         self.write_byte(op_code as Byte, line)
     }
@@ -152,43 +176,71 @@ impl Chunk {
         Ok(())
     }
 
+    // Indexes past a byte take the long form with a two byte operand
     pub fn write_constant(&mut self, value: Value, line: usize) {
         let index = self.add_constant(value);
 
-        let at = Byte::try_from(index).expect("Constant added at index out of range for byte");
-
-        self.write_code(Constant, line);
-        self.write_byte(at as Byte, line);
+        match Byte::try_from(index) {
+            Ok(at) => {
+                self.write_code(OpCode::Constant, line);
+                self.write_byte(at, line);
+            }
+            Err(_) => {
+                let at = u16::try_from(index).expect("Constant added at index out of range");
+                let [higher, lower] = at.to_be_bytes();
+                self.write_code(OpCode::ConstantLong, line);
+                self.write_byte(higher, line);
+                self.write_byte(lower, line);
+            }
+        }
     }
 
-    pub fn write_define_global_var(&mut self, str: String, line: usize) {
+    // Adds the string and writes the instruction referring to it, the long form when the
+    // index doesn't fit a byte
+    fn write_string_operand(&mut self, op_code: OpCode, long: OpCode, str: String, line: usize) {
         let index = self.strings.add(str);
 
-        let at = Byte::try_from(index)
-            .expect("Global variable name added at index out of range for byte");
+        match Byte::try_from(index) {
+            Ok(at) => {
+                self.write_code(op_code, line);
+                self.write_byte(at, line);
+            }
+            Err(_) => {
+                let at = u16::try_from(index).expect("String added at index out of range");
+                let [higher, lower] = at.to_be_bytes();
+                self.write_code(long, line);
+                self.write_byte(higher, line);
+                self.write_byte(lower, line);
+            }
+        }
+    }
 
-        self.write_code(OpCode::DefineGlobal, line);
-        self.write_byte(at as Byte, line);
+    pub fn write_define_global_var(&mut self, str: String, line: usize) {
+        self.write_string_operand(OpCode::DefineGlobal, OpCode::DefineGlobalLong, str, line)
     }
 
     pub fn write_set_global_var(&mut self, str: String, line: usize) {
-        let index = self.strings.add(str);
-
-        let at = Byte::try_from(index)
-            .expect("Global variable name added at index out of range for byte");
-
-        self.write_code(OpCode::SetGlobal, line);
-        self.write_byte(at as Byte, line);
+        self.write_string_operand(OpCode::SetGlobal, OpCode::SetGlobalLong, str, line)
     }
 
     pub fn write_get_global_var(&mut self, str: String, line: usize) {
-        let index = self.strings.add(str);
+        self.write_string_operand(OpCode::GetGlobal, OpCode::GetGlobalLong, str, line)
+    }
 
-        let at = Byte::try_from(index)
-            .expect("Global variable name added at index out of range for byte");
+    // `op_code` is one of the global slot instructions
+    pub fn write_global_slot(
+        &mut self,
+        op_code: OpCode,
+        name: String,
+        line: usize,
+    ) -> Result<(), InterpretError> {
+        let id = self.global_slots.add(name);
 
-        self.write_code(OpCode::GetGlobal, line);
-        self.write_byte(at as Byte, line);
+        let at = Byte::try_from(id).map_err(|_| CompileError(TooManyGlobalSlots))?;
+
+        self.write_code(op_code, line);
+        self.write_byte(at, line);
+        Ok(())
     }
 
     pub fn write_set_local_var(&mut self, locals_index: usize, line: usize) {
@@ -199,6 +251,43 @@ impl Chunk {
         self.write_byte(at as Byte, line);
     }
 
+    // Like constants, indexes past a byte take the long form
+    pub fn write_function(&mut self, function: Value, line: usize) {
+        let index = self.add_constant(function);
+
+        match Byte::try_from(index) {
+            Ok(at) => {
+                self.write_code(OpCode::Function, line);
+                self.write_byte(at, line);
+            }
+            Err(_) => {
+                let at = u16::try_from(index).expect("Function added at index out of range");
+                let [higher, lower] = at.to_be_bytes();
+                self.write_code(OpCode::FunctionLong, line);
+                self.write_byte(higher, line);
+                self.write_byte(lower, line);
+            }
+        }
+    }
+
+    pub fn write_call(&mut self, arg_count: usize, line: usize) -> Result<(), InterpretError> {
+        let count = Byte::try_from(arg_count)
+            .map_err(|_| RuntimeErrorWithReason("Can't have more than 255 arguments"))?;
+
+        self.write_code(OpCode::Call, line);
+        self.write_byte(count, line);
+        Ok(())
+    }
+
+    pub fn write_build_array(&mut self, len: usize, line: usize) -> Result<(), InterpretError> {
+        let len = Byte::try_from(len)
+            .map_err(|_| RuntimeErrorWithReason("Can't have more than 255 elements"))?;
+
+        self.write_code(OpCode::BuildArray, line);
+        self.write_byte(len, line);
+        Ok(())
+    }
+
     pub fn write_get_local_var(&mut self, locals_index: usize, line: usize) {
         let at = Byte::try_from(locals_index)
             .expect("Local variable name added at index out of range for byte");
@@ -208,24 +297,87 @@ impl Chunk {
     }
 
     pub fn write_string(&mut self, str: String, line: usize) {
-        let index = self.strings.add(str);
+        self.write_string_operand(OpCode::String, OpCode::StringLong, str, line)
+    }
 
-        let at = Byte::try_from(index).expect("String added at index out of range for byte");
+    // Swaps the instruction at `at` for one with the same operands
+    pub(crate) fn patch_code(&mut self, at: usize, op_code: OpCode) {
+        self.code.patch(at, op_code as Byte);
+    }
 
-        self.write_code(OpCode::String, line);
-        self.write_byte(at as Byte, line);
+    // Drops already written code, only safe when no jump crosses the range
+    pub(crate) fn remove_code(&mut self, from: usize, to: usize) {
+        self.code.remove(from, to);
+        self.lines.remove(from, to);
+        self.locals.remove(from, to);
+    }
+
+    // The local var in the slot is alive from the next instruction on
+    pub(crate) fn open_local(&mut self, name: String, slot: usize) {
+        self.locals.open(name, slot, self.code.len());
+    }
+
+    // The latest locals went out of scope
+    pub(crate) fn close_locals(&mut self, count: usize) {
+        self.locals.close(count, self.code.len());
+    }
+
+    pub fn resolve_local(&self, name: &str, at: usize) -> Option<usize> {
+        self.locals.resolve(name, at)
+    }
+
+    pub fn visible_locals(&self, at: usize) -> impl Iterator<Item = (&str, usize)> {
+        self.locals.visible(at)
+    }
+
+    // Functions and global slots are indexed by a byte
+    pub(crate) fn constants_len(&self) -> usize {
+        self.constants.len()
+    }
+
+    pub(crate) fn global_slots_len(&self) -> usize {
+        self.global_slots.len()
+    }
+
+    // Read only views on the tables for tooling, in index order
+    pub fn constants_iter(&self) -> impl Iterator<Item = &Value> {
+        self.constants.iter()
+    }
+
+    pub fn strings_iter(&self) -> impl Iterator<Item = &str> {
+        self.strings.iter()
+    }
+
+    pub fn is_instruction_start(&self, index: usize) -> bool {
+        self.code.is_instruction_start(index)
     }
 
     pub fn read_byte(&self, index: usize) -> Option<Byte> {
         self.code.get(index)
     }
 
+    pub fn read_line(&self, index: usize) -> Option<usize> {
+        self.lines.get(index)
+    }
+
     pub fn read_jump(&self, index: usize) -> Option<Jump> {
         let higher = self.read_byte(index)?;
         let lower = self.read_byte(index + 1)?;
         Some(Jump::from_bytes(higher, lower))
     }
 
+    // Where the jump or loop instruction at the index goes to
+    pub fn read_jump_target(&self, index: usize) -> Option<usize> {
+        let code = OpCode::try_from(self.read_byte(index)?).ok()?;
+        let distance = self.read_jump(index + 1)?.distance as usize;
+        let next = index + 3;
+        match code {
+            OpCode::Jump | OpCode::JumpIfFalse | OpCode::JumpIfTrue => Some(next + distance),
+            OpCode::Loop => next.checked_sub(distance),
+            _ => None,
+        }
+    }
+
     pub fn read_constant(&self, index: usize) -> Option<Value> {
         let i = self.read_byte(index)?;
         let index = i as usize;
@@ -233,6 +385,30 @@ impl Chunk {
         self.constants.get(index)
     }
 
+    pub fn read_constant_long(&self, index: usize) -> Option<Value> {
+        let higher = self.read_byte(index)?;
+        let lower = self.read_byte(index + 1)?;
+        let index = u16::from_be_bytes([higher, lower]) as usize;
+
+        self.constants.get(index)
+    }
+
+    // The name of the global the slot operand at the index refers to
+    pub fn read_global_slot(&self, index: usize) -> Option<&str> {
+        let id = self.read_byte(index)?;
+        self.global_slots.name(id as usize)
+    }
+
+    // Where the global of the slot operand is stored, once linked to the globals
+    pub(crate) fn read_linked_global_slot(&self, index: usize) -> Option<usize> {
+        let id = self.read_byte(index)?;
+        self.global_slots.linked(id as usize)
+    }
+
+    pub(crate) fn link_global_slots(&self, globals: u64, resolve: impl FnMut(&str) -> usize) {
+        self.global_slots.link(globals, resolve)
+    }
+
     pub fn read_string(&self, index: usize) -> Option<&str> {
         let i = self.read_byte(index)?;
         let index = i as usize;
@@ -240,4 +416,13 @@ impl Chunk {
         let it = self.strings.get(index);
         it.map(|it| it.as_str())
     }
+
+    pub fn read_string_long(&self, index: usize) -> Option<&str> {
+        let higher = self.read_byte(index)?;
+        let lower = self.read_byte(index + 1)?;
+        let index = u16::from_be_bytes([higher, lower]) as usize;
+
+        let it = self.strings.get(index);
+        it.map(|it| it.as_str())
+    }
 }
@@ -1,31 +1,61 @@
-use crate::opcode::Byte;
+use crate::opcode::{Byte, OpCode};
+use std::cell::OnceCell;
 
 /// Codes is a byte array of machine code
 
 #[derive(Debug)]
-pub struct Codes(Vec<Byte>);
+pub struct Codes {
+    bytes: Vec<Byte>,
+    // Which bytes start an instruction, worked out on first use and dropped on any change
+    starts: OnceCell<Vec<bool>>,
+}
 
 impl Codes {
     pub fn new() -> Self {
-        Codes(Vec::new())
+        Codes {
+            bytes: Vec::new(),
+            starts: OnceCell::new(),
+        }
     }
 
     pub fn get(&self, index: usize) -> Option<Byte> {
-        self.0.get(index).copied()
+        self.bytes.get(index).copied()
     }
 
     /// Returns the index to lookup the byte again
     pub fn add(&mut self, byte: Byte) -> usize {
-        self.0.push(byte);
-        self.0.len() - 1
+        self.starts.take();
+        self.bytes.push(byte);
+        self.bytes.len() - 1
     }
 
     pub fn patch(&mut self, at: usize, byte: Byte) -> usize {
-        self.0[at] = byte;
+        self.starts.take();
+        self.bytes[at] = byte;
         at
     }
 
+    /// Removes the bytes in the range, shifting the ones after it
+    pub fn remove(&mut self, from: usize, to: usize) {
+        self.starts.take();
+        self.bytes.drain(from..to);
+    }
+
     pub fn len(&self) -> usize {
-        self.0.len()
+        self.bytes.len()
+    }
+
+    /// False for operand bytes, and for anything after a byte that is not an opcode
+    pub fn is_instruction_start(&self, at: usize) -> bool {
+        let starts = self.starts.get_or_init(|| {
+            let mut starts = vec![false; self.bytes.len()];
+            let mut at = 0;
+            while let Some(code) = self.get(at).and_then(|it| OpCode::try_from(it).ok()) {
+                starts[at] = true;
+                at += 1 + code.operand_len();
+            }
+            starts
+        });
+        starts.get(at).copied().unwrap_or(false)
     }
 }
@@ -0,0 +1,166 @@
+use crate::chunk::Chunk;
+use crate::opcode::{Obj, OpCode, Value};
+
+/// The first instruction two chunks disagree on, rendered like the disassembler does
+/// without the offset and line columns. None when the chunk has no code left there.
+#[derive(Debug, PartialEq)]
+pub struct Difference {
+    pub at: usize,
+    pub left: Option<String>,
+    pub right: Option<String>,
+}
+
+impl Chunk {
+    /// Same code referring to the same constants and strings, the lines are ignored. Operands
+    /// are compared by what they refer to rather than by index, so entries an optimization
+    /// left behind in the tables don't count.
+    pub fn structurally_eq(&self, other: &Chunk) -> bool {
+        self.diff(other).is_none()
+    }
+
+    pub fn diff(&self, other: &Chunk) -> Option<Difference> {
+        let mut at = 0;
+        loop {
+            let (left, right) = (self.read_op_code(at), other.read_op_code(at));
+            match (left, right) {
+                (None, None) => return None,
+                (Some(a), Some(b)) if a == b && self.operands_eq(other, a, at) => {
+                    at += 1 + a.operand_len();
+                }
+                _ => {
+                    return Some(Difference {
+                        at,
+                        left: left.map(|_| self.instruction_into_string(at)),
+                        right: right.map(|_| other.instruction_into_string(at)),
+                    })
+                }
+            }
+        }
+    }
+
+    fn read_op_code(&self, at: usize) -> Option<OpCode> {
+        OpCode::try_from(self.read_byte(at)?).ok()
+    }
+
+    fn operands_eq(&self, other: &Chunk, code: OpCode, at: usize) -> bool {
+        use OpCode::*;
+
+        let at = at + 1;
+        match code {
+            Constant | Function => constant_eq(self.read_constant(at), other.read_constant(at)),
+            ConstantLong | FunctionLong => {
+                constant_eq(self.read_constant_long(at), other.read_constant_long(at))
+            }
+            String | DefineGlobal | GetGlobal | SetGlobal => {
+                self.read_string(at) == other.read_string(at)
+            }
+            StringLong | DefineGlobalLong | GetGlobalLong | SetGlobalLong => {
+                self.read_string_long(at) == other.read_string_long(at)
+            }
+            DefineGlobalSlot | GetGlobalSlot | SetGlobalSlot => {
+                self.read_global_slot(at) == other.read_global_slot(at)
+            }
+            _ => (at..at + code.operand_len()).all(|it| self.read_byte(it) == other.read_byte(it)),
+        }
+    }
+
+    fn instruction_into_string(&self, at: usize) -> String {
+        let mut buffer = Vec::new();
+        self.disassemble_instruction_to(&mut buffer, self.read_byte(at).unwrap(), at);
+        let it = String::from_utf8(buffer).unwrap();
+        match it.split_once("| ") {
+            Some((_, instruction)) => instruction.trim_end().to_string(),
+            None => it,
+        }
+    }
+}
+
+// Functions are only equal to themselves as values, compiled twice they are just as good
+fn constant_eq(a: Option<Value>, b: Option<Value>) -> bool {
+    match (a, b) {
+        (Some(Value::Object(a)), Some(Value::Object(b))) => match (a.as_ref(), b.as_ref()) {
+            (
+                Obj::Function {
+                    name: a_name,
+                    arity: a_arity,
+                    chunk: a,
+                },
+                Obj::Function {
+                    name: b_name,
+                    arity: b_arity,
+                    chunk: b,
+                },
+            ) => a_name == b_name && a_arity == b_arity && a.structurally_eq(b),
+            (a, b) => a == b,
+        },
+        (a, b) => a == b,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::{Parser, ParserOptions};
+    use crate::tokenizer::Tokenizer;
+
+    fn optimized(source: &str) -> Chunk {
+        let options = ParserOptions {
+            optimize: true,
+            ..Default::default()
+        };
+        Parser::parse_with_options(Tokenizer::new(source), options).unwrap()
+    }
+
+    #[test]
+    fn compare_optimized_chunk() {
+        let source = "{ var x = 1; x = 2; return x; }";
+
+        // Written on other lines, only the code counts
+        let mut expected = Chunk::new();
+        expected.write_constant(Value::Int(2), 1);
+        expected.write_get_local_var(0, 2);
+        expected.write_code(OpCode::Return, 3);
+        expected.write_code(OpCode::Pop, 4);
+        expected.write_code(OpCode::Nil, 4);
+        expected.write_code(OpCode::Return, 4);
+        assert!(optimized(source).structurally_eq(&expected));
+
+        let plain = Parser::parse(Tokenizer::new(source)).unwrap();
+        assert_eq!(
+            plain.diff(&expected),
+            Some(Difference {
+                at: 0,
+                left: Some("Constant 1".to_string()),
+                right: Some("Constant 2".to_string()),
+            })
+        );
+
+        expected.write_code(OpCode::Pop, 4);
+        assert_eq!(
+            optimized(source).diff(&expected),
+            Some(Difference {
+                at: 8,
+                left: None,
+                right: Some("Pop".to_string()),
+            })
+        );
+    }
+
+    #[test]
+    fn compare_functions_by_their_code() {
+        let source = "fun twice(x) { return x * 2; } print twice(2);";
+        let it = Parser::parse(Tokenizer::new(source)).unwrap();
+        assert!(it.structurally_eq(&Parser::parse(Tokenizer::new(source)).unwrap()));
+
+        let other = "fun twice(x) { return x * 3; } print twice(2);";
+        let other = Parser::parse(Tokenizer::new(other)).unwrap();
+        let difference = it.diff(&other).unwrap();
+        assert_eq!(
+            (difference.left, difference.right),
+            (
+                Some("Function <fn twice>".to_string()),
+                Some("Function <fn twice>".to_string())
+            )
+        );
+    }
+}
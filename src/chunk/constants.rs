@@ -16,6 +16,14 @@ impl Constants {
         self.0.len() - 1
     }
 
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Value> {
+        self.0.iter()
+    }
+
     pub fn get(&self, index: usize) -> Option<Value> {
         // Since we are using an rc, we can no longer use copied().
         self.0.get(index).cloned()
@@ -1,17 +1,26 @@
 use crate::chunk::Chunk;
-use crate::opcode::{Byte, OpCode};
+use crate::opcode::{Byte, Obj, OpCode, Value};
+use std::collections::{BTreeSet, HashMap};
 use std::io;
 use std::io::{Cursor, Write};
 
 impl Chunk {
     pub fn disassemble(&self, name: &str) {
         let mut buffer = io::stdout();
-        self.disassemble_buffer(&mut buffer, name)
+        self.disassemble_to(&mut buffer, name)
     }
 
     pub fn disassemble_into_string(&self, name: &str) -> String {
         let mut buffer: Cursor<Vec<u8>> = Cursor::new(Vec::new());
-        self.disassemble_buffer(&mut buffer, name);
+        self.disassemble_to(&mut buffer, name);
+
+        String::from_utf8(buffer.into_inner()).unwrap()
+    }
+
+    // Jump targets get a label instead of an offset, easier to follow in large functions
+    pub fn disassemble_labeled_into_string(&self, name: &str) -> String {
+        let mut buffer: Cursor<Vec<u8>> = Cursor::new(Vec::new());
+        self.disassemble_labeled_to(&mut buffer, name);
 
         String::from_utf8(buffer.into_inner()).unwrap()
     }
@@ -21,19 +30,90 @@ impl Chunk {
         self.disassemble_instruction_buffer(&mut buffer, byte, at)
     }
 
-    fn disassemble_buffer<W: Write>(&self, buffer: &mut W, name: &str) {
+    // Like `disassemble_instruction`, the vm traces to a sink of its own
+    pub fn disassemble_instruction_to<W: Write>(
+        &self,
+        buffer: &mut W,
+        byte: Byte,
+        at: usize,
+    ) -> usize {
+        self.disassemble_instruction_buffer(buffer, byte, at)
+    }
+
+    // Writes the disassembly to any sink like a file or a socket
+    pub fn disassemble_to<W: Write>(&self, buffer: &mut W, name: &str) {
         writeln!(buffer).unwrap();
         writeln!(buffer, "== {} ==", name).unwrap();
 
+        let mut n = 0;
+        while let Some(code) = self.read_byte(n) {
+            n = self.disassemble_instruction_buffer(buffer, code, n);
+        }
+
+        // Functions have their own chunk, list them after the code using them
+        for constant in self.constants_iter() {
+            if let Value::Object(it) = constant {
+                if let Obj::Function { name, chunk, .. } = it.as_ref() {
+                    chunk.disassemble_to(buffer, &format!("<fn {}>", name));
+                }
+            }
+        }
+    }
+
+    // Prints `Jump L0` for jumps and the `L0:` label right before the instruction it targets
+    pub fn disassemble_labeled_to<W: Write>(&self, buffer: &mut W, name: &str) {
+        writeln!(buffer).unwrap();
+        writeln!(buffer, "== {} ==", name).unwrap();
+
+        let labels = self.jump_labels();
         let mut n = 0;
         loop {
+            if let Some(label) = labels.get(&n) {
+                writeln!(buffer, "L{}:", label).unwrap();
+            }
             let Some(code) = self.read_byte(n) else {
                 break;
             };
-            n = self.disassemble_instruction_buffer(buffer, code, n);
+            n = match self.read_jump_target(n) {
+                Some(to) => {
+                    let name = match OpCode::try_from(code) {
+                        Ok(OpCode::JumpIfFalse) => "If (false) jump",
+                        Ok(OpCode::JumpIfTrue) => "If (true) jump",
+                        Ok(OpCode::Loop) => "Loop",
+                        _ => "Jump",
+                    };
+                    let line = self.lines.at(n);
+                    writeln!(buffer, "{:8} {:8} | {} L{}", n, line, name, labels[&to]).unwrap();
+                    n + 3
+                }
+                None => self.disassemble_instruction_buffer(buffer, code, n),
+            };
+        }
+
+        for constant in self.constants_iter() {
+            if let Value::Object(it) = constant {
+                if let Obj::Function { name, chunk, .. } = it.as_ref() {
+                    chunk.disassemble_labeled_to(buffer, &format!("<fn {}>", name));
+                }
+            }
         }
     }
 
+    // Numbers the jump targets in the order they appear in the code
+    fn jump_labels(&self) -> HashMap<usize, usize> {
+        let mut targets = BTreeSet::new();
+        let mut n = 0;
+        while let Some(code) = self.read_byte(n).and_then(|it| OpCode::try_from(it).ok()) {
+            targets.extend(self.read_jump_target(n));
+            n += 1 + code.operand_len();
+        }
+        targets
+            .into_iter()
+            .enumerate()
+            .map(|(i, it)| (it, i))
+            .collect()
+    }
+
     // Returns the next instruction location
     fn disassemble_instruction_buffer<W: Write>(
         &self,
@@ -55,6 +135,15 @@ impl Chunk {
 
                 at + 2
             }
+            ConstantLong => {
+                let c = self
+                    .read_constant_long(at + 1)
+                    .unwrap_or_else(|| panic!("Constant at index {:?} should exist", at + 1));
+
+                writeln!(buffer, "{:8} {:8} | ConstantLong {:?}", at, line, c).unwrap();
+
+                at + 3
+            }
 
             // literals
             False => Self::simple_instruction("False", buffer, at, line),
@@ -69,6 +158,7 @@ impl Chunk {
 
                 at + 2
             }
+            StringLong => self.string_long_instruction("StringLong", buffer, at, line),
 
             // comparison
             Equal => Self::simple_instruction("Equal", buffer, at, line),
@@ -83,6 +173,8 @@ impl Chunk {
             Subtract => Self::simple_instruction("Subtract", buffer, at, line),
             Multiply => Self::simple_instruction("Multiply", buffer, at, line),
             Divide => Self::simple_instruction("Divide", buffer, at, line),
+            Modulo => Self::simple_instruction("Modulo", buffer, at, line),
+            IntDivide => Self::simple_instruction("IntDivide", buffer, at, line),
             Negate => Self::simple_instruction("Negate", buffer, at, line),
 
             // bindings
@@ -113,6 +205,14 @@ impl Chunk {
 
                 at + 2
             }
+            DefineGlobalLong => {
+                self.string_long_instruction("Global define long", buffer, at, line)
+            }
+            GetGlobalLong => self.string_long_instruction("Global get long", buffer, at, line),
+            SetGlobalLong => self.string_long_instruction("Global set long", buffer, at, line),
+            DefineGlobalSlot => self.global_slot_instruction("define", buffer, at, line),
+            GetGlobalSlot => self.global_slot_instruction("get", buffer, at, line),
+            SetGlobalSlot => self.global_slot_instruction("set", buffer, at, line),
             GetLocal => {
                 let index = self.read_byte(at + 1).unwrap();
 
@@ -141,8 +241,48 @@ impl Chunk {
             Jump => self.jump_instruction("Jump", buffer, at, line),
             Loop => self.loop_instruction(buffer, at, line),
 
+            // functions
+            Function => {
+                let c = self
+                    .read_constant(at + 1)
+                    .unwrap_or_else(|| panic!("Function at index {:?} should exist", at + 1));
+
+                writeln!(buffer, "{:8} {:8} | Function {}", at, line, c).unwrap();
+
+                at + 2
+            }
+            FunctionLong => {
+                let c = self
+                    .read_constant_long(at + 1)
+                    .unwrap_or_else(|| panic!("Function at index {:?} should exist", at + 1));
+
+                writeln!(buffer, "{:8} {:8} | FunctionLong {}", at, line, c).unwrap();
+
+                at + 3
+            }
+            Call => {
+                let count = self.read_byte(at + 1).unwrap();
+                writeln!(buffer, "{:8} {:8} | Call (argc {})", at, line, count).unwrap();
+                at + 2
+            }
+            TailCall => {
+                let count = self.read_byte(at + 1).unwrap();
+                writeln!(buffer, "{:8} {:8} | Tail call (argc {})", at, line, count).unwrap();
+                at + 2
+            }
+
+            // collections
+            BuildArray => {
+                let count = self.read_byte(at + 1).unwrap();
+                writeln!(buffer, "{:8} {:8} | Build array (len {})", at, line, count).unwrap();
+                at + 2
+            }
+            Index => Self::simple_instruction("Index", buffer, at, line),
+            SetIndex => Self::simple_instruction("Set index", buffer, at, line),
+
             // statements
             Print => Self::simple_instruction("Print", buffer, at, line),
+            EPrint => Self::simple_instruction("EPrint", buffer, at, line),
             Pop => Self::simple_instruction("Pop", buffer, at, line),
             Return => Self::simple_instruction("Return", buffer, at, line),
         }
@@ -154,6 +294,42 @@ impl Chunk {
         at + 1
     }
 
+    fn string_long_instruction<W: Write>(
+        &self,
+        name: &str,
+        buffer: &mut W,
+        at: usize,
+        line: usize,
+    ) -> usize {
+        let c = self
+            .read_string_long(at + 1)
+            .unwrap_or_else(|| panic!("String at index {:?} should exist", at + 1));
+
+        writeln!(buffer, "{:8} {:8} | {} {:?}", at, line, name, c).unwrap();
+        at + 3
+    }
+
+    fn global_slot_instruction<W: Write>(
+        &self,
+        name: &str,
+        buffer: &mut W,
+        at: usize,
+        line: usize,
+    ) -> usize {
+        let id = self.read_byte(at + 1).unwrap();
+        let global = self
+            .read_global_slot(at + 1)
+            .unwrap_or_else(|| panic!("Global slot at index {:?} should exist", at + 1));
+
+        writeln!(
+            buffer,
+            "{:8} {:8} | Global slot {} {:?} ({})",
+            at, line, name, global, id
+        )
+        .unwrap();
+        at + 2
+    }
+
     fn jump_instruction<W: Write>(
         &self,
         name: &str,
@@ -184,14 +360,72 @@ impl Chunk {
             .unwrap_or_else(|| panic!("Jump at index {:?} should exist", at + 1));
         let adjust_for_jump_byte_width = 2;
         let adjust_for_ip_points_to_next = 1;
-        writeln!(
-            buffer,
-            "{:8} {:8} | Loop back to {:?}",
-            at,
-            line,
-            at - it.distance as usize + adjust_for_jump_byte_width + adjust_for_ip_points_to_next
-        )
+        // A malformed loop could point before the start of the code
+        let to = (at + adjust_for_jump_byte_width + adjust_for_ip_points_to_next)
+            .checked_sub(it.distance as usize);
+        match to {
+            Some(to) => writeln!(buffer, "{:8} {:8} | Loop back to {:?}", at, line, to),
+            None => writeln!(buffer, "{:8} {:8} | Loop back out of bounds", at, line),
+        }
         .unwrap();
         at + 3
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn disassemble_to_writer() {
+        let mut chunk = Chunk::new();
+        chunk.write_constant(Value::Number(1.2), 1);
+        chunk.write_code(OpCode::Negate, 1);
+        chunk.write_code(OpCode::Return, 2);
+
+        let mut buffer: Vec<u8> = Vec::new();
+        chunk.disassemble_to(&mut buffer, "writer");
+
+        let output = String::from_utf8(buffer).unwrap();
+        assert_eq!(output, chunk.disassemble_into_string("writer"));
+        assert_eq!(
+            output,
+            r#"
+== writer ==
+       0        1 | Constant 1.2
+       2        1 | Negate
+       3        2 | Return
+"#
+        );
+    }
+
+    #[test]
+    fn disassemble_every_op_code() {
+        // Every operand is 0, so give each table an entry at 0
+        let mut chunk = Chunk::new();
+        chunk.write_constant(Value::Number(1.2), 0);
+        chunk.write_string("s".to_string(), 0);
+        chunk
+            .write_global_slot(OpCode::GetGlobalSlot, "g".to_string(), 0)
+            .unwrap();
+
+        let codes = (0..=Byte::MAX)
+            .filter_map(|it| OpCode::try_from(it).ok())
+            .collect::<Vec<_>>();
+        for code in codes.iter() {
+            chunk.write_code(*code, 1);
+            for _ in 0..code.operand_len() {
+                chunk.push_raw_byte(0, 1);
+            }
+        }
+
+        for output in [
+            chunk.disassemble_into_string("every"),
+            chunk.disassemble_labeled_into_string("every"),
+        ] {
+            // The setup and every op code each get their own line
+            let instructions = output.lines().filter(|it| it.contains(" | "));
+            assert_eq!(instructions.count(), 3 + codes.len(), "{}", output);
+        }
+    }
+}
@@ -0,0 +1,87 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+/// GlobalSlots is the compile time symbol table for globals accessed by slot. The slot
+/// instructions refer to a global by its id in this table. Before running, the ids get
+/// linked to where the globals are stored, so the name is not hashed on every access.
+
+#[derive(Debug)]
+pub(crate) struct GlobalSlots {
+    names: Vec<String>,
+    ids: HashMap<String, usize>,
+    // Where each id lives in the globals the chunk was linked to, tagged with their id
+    links: RefCell<Option<(u64, Vec<usize>)>>,
+}
+
+impl GlobalSlots {
+    pub fn new() -> Self {
+        Self {
+            names: Vec::new(),
+            ids: HashMap::new(),
+            links: RefCell::new(None),
+        }
+    }
+
+    /// Returns the id of the global, the same name always gets the same id
+    pub fn add(&mut self, name: String) -> usize {
+        match self.ids.get(&name) {
+            Some(id) => *id,
+            None => {
+                let id = self.names.len();
+                self.names.push(name.clone());
+                self.ids.insert(name, id);
+                id
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    pub fn name(&self, id: usize) -> Option<&str> {
+        self.names.get(id).map(|it| it.as_str())
+    }
+
+    /// Resolves where the globals are stored, unless already linked to the same globals
+    pub fn link(&self, globals: u64, mut resolve: impl FnMut(&str) -> usize) {
+        let mut links = self.links.borrow_mut();
+        if !matches!(links.as_ref(), Some((linked, _)) if *linked == globals) {
+            let slots = self.names.iter().map(|it| resolve(it)).collect();
+            *links = Some((globals, slots));
+        }
+    }
+
+    pub fn linked(&self, id: usize) -> Option<usize> {
+        let links = self.links.borrow();
+        links.as_ref().and_then(|(_, slots)| slots.get(id).copied())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn links_once_per_globals() {
+        let mut slots = GlobalSlots::new();
+        assert_eq!(slots.add("a".to_string()), 0);
+        assert_eq!(slots.add("b".to_string()), 1);
+        assert_eq!(slots.add("a".to_string()), 0);
+        assert_eq!(slots.linked(0), None);
+
+        let mut resolved = 0;
+        let mut resolve = |_: &str| {
+            resolved += 1;
+            resolved + 10
+        };
+        slots.link(1, &mut resolve);
+        slots.link(1, &mut resolve);
+        assert_eq!(resolved, 2);
+        assert_eq!((slots.linked(0), slots.linked(1)), (Some(11), Some(12)));
+
+        // Other globals store them elsewhere
+        slots.link(2, |it| if it == "a" { 5 } else { 6 });
+        assert_eq!((slots.linked(0), slots.linked(1)), (Some(5), Some(6)));
+    }
+}
@@ -1,23 +1,125 @@
 /// Lines keep track of the line number corresponding to the opcode
+///
+/// Consecutive opcodes mostly share a line, so the lines are run-length encoded:
+/// each run is the line together with how many bytes in a row are on it.
 
 #[derive(Debug)]
-pub struct Lines(Vec<usize>);
+pub struct Lines {
+    runs: Vec<(usize, usize)>,
+    len: usize, // bytes covered by all the runs together
+}
 
 impl Lines {
     pub fn new() -> Self {
-        Self(Vec::new())
+        Self {
+            runs: Vec::new(),
+            len: 0,
+        }
+    }
+
+    /// Adds the line of the byte at the index. The code only grows at the end, so the
+    /// index has to be the next one, anything else would shift the lines of the bytes
+    /// after it away from their code.
+    pub fn push(&mut self, index: usize, element: usize) {
+        assert_eq!(
+            index, self.len,
+            "Lines are written in the order of the code"
+        );
+        self.len += 1;
+        match self.runs.last_mut() {
+            Some((line, count)) if *line == element => *count += 1,
+            _ => self.runs.push((element, 1)),
+        }
+    }
+
+    pub fn remove(&mut self, from: usize, to: usize) {
+        let mut start = 0;
+        for (_, count) in self.runs.iter_mut() {
+            let end = start + *count;
+            let removed = end.min(to).saturating_sub(start.max(from));
+            start = end;
+            *count -= removed;
+            self.len -= removed;
+        }
+        self.runs.retain(|(_, count)| *count > 0);
+        // The runs on both sides of the range might be on the same line
+        self.runs
+            .dedup_by(|(line, count), (previous_line, previous_count)| {
+                let is_same = line == previous_line;
+                if is_same {
+                    *previous_count += *count;
+                }
+                is_same
+            });
     }
 
-    pub fn insert(&mut self, index: usize, element: usize) {
-        self.0.insert(index, element)
+    /// The line of each run together with how many bytes are on it, in code order
+    pub fn runs(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
+        self.runs.iter().copied()
+    }
+
+    pub fn get(&self, index: usize) -> Option<usize> {
+        let mut start = 0;
+        for (line, count) in self.runs.iter() {
+            start += count;
+            if index < start {
+                return Some(*line);
+            }
+        }
+        None
     }
 
     pub fn at(&self, index: usize) -> usize {
-        let line = self
-            .0
-            .get(index)
-            .unwrap_or_else(|| panic!("Line at index {:?} should exist", index));
+        self.get(index)
+            .unwrap_or_else(|| panic!("Line at index {:?} should exist", index))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(of: &[usize]) -> Lines {
+        let mut lines = Lines::new();
+        for (index, line) in of.iter().enumerate() {
+            lines.push(index, *line);
+        }
+        lines
+    }
+
+    #[test]
+    fn encodes_runs() {
+        let lines = encode(&[1, 1, 1, 2, 2, 4, 1, 1]);
+        assert_eq!(lines.runs, vec![(1, 3), (2, 2), (4, 1), (1, 2)]);
+
+        let found = (0..8).map(|it| lines.at(it)).collect::<Vec<_>>();
+        assert_eq!(found, vec![1, 1, 1, 2, 2, 4, 1, 1]);
+        assert_eq!(lines.get(8), None);
+        assert_eq!(Lines::new().get(0), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "Lines are written in the order of the code")]
+    fn push_out_of_order() {
+        let mut lines = encode(&[1, 1, 2]);
+        // Patching a byte must not add a line, it would shift the ones after it
+        lines.push(1, 3);
+    }
+
+    #[test]
+    fn removes_across_runs() {
+        let mut lines = encode(&[1, 1, 2, 3, 3, 1, 1]);
+        lines.remove(1, 6);
+        assert_eq!(lines.runs, vec![(1, 2)]);
+
+        let mut lines = encode(&[1, 1, 2, 3, 3]);
+        lines.remove(2, 4);
+        assert_eq!(lines.runs, vec![(1, 2), (3, 1)]);
+        assert_eq!((lines.at(1), lines.at(2), lines.get(3)), (1, 3, None));
 
-        *line
+        // Writing carries on right after what is left
+        lines.push(3, 3);
+        lines.push(4, 4);
+        assert_eq!(lines.runs, vec![(1, 2), (3, 2), (4, 1)]);
     }
 }
@@ -0,0 +1,68 @@
+/// Locals keep track of the names of local variables and the code range they live in,
+/// the vm itself only knows about stack slots
+
+#[derive(Debug)]
+struct LocalName {
+    name: String,
+    slot: usize,
+    start: usize,
+    end: Option<usize>, // None while the scope is still open
+}
+
+#[derive(Debug)]
+pub struct Locals(Vec<LocalName>);
+
+impl Locals {
+    pub fn new() -> Self {
+        Self(Vec::new())
+    }
+
+    pub fn open(&mut self, name: String, slot: usize, start: usize) {
+        self.0.push(LocalName {
+            name,
+            slot,
+            start,
+            end: None,
+        })
+    }
+
+    /// Closes the most recently opened locals, like a scope popping them from the stack
+    pub fn close(&mut self, count: usize, end: usize) {
+        self.0
+            .iter_mut()
+            .rev()
+            .filter(|it| it.end.is_none())
+            .take(count)
+            .for_each(|it| it.end = Some(end));
+    }
+
+    /// Keeps the ranges in sync when code is removed
+    pub fn remove(&mut self, from: usize, to: usize) {
+        let shift = |at: usize| match at {
+            at if at >= to => at - (to - from),
+            at if at > from => from,
+            at => at,
+        };
+        for it in self.0.iter_mut() {
+            it.start = shift(it.start);
+            it.end = it.end.map(shift);
+        }
+    }
+
+    /// Names and slots of the locals visible at the instruction, shadowed ones first
+    pub fn visible(&self, at: usize) -> impl Iterator<Item = (&str, usize)> {
+        self.0
+            .iter()
+            .filter(move |it| it.start <= at && it.end.is_none_or(|end| at < end))
+            .map(|it| (it.name.as_str(), it.slot))
+    }
+
+    /// Slot of the local with this name visible at the instruction, shadowing ones first
+    pub fn resolve(&self, name: &str, at: usize) -> Option<usize> {
+        self.0
+            .iter()
+            .rev()
+            .find(|it| it.name == name && it.start <= at && it.end.is_none_or(|end| at < end))
+            .map(|it| it.slot)
+    }
+}
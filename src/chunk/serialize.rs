@@ -0,0 +1,346 @@
+use crate::chunk::Chunk;
+use crate::opcode::{Obj, Value};
+use crate::vm::InterpretError;
+use std::io::{ErrorKind, Read, Write};
+use std::rc::Rc;
+
+// Compiled chunks are stored as `.loxc` files starting with this header. The format follows
+// the opcodes, bump the version whenever they or the layout below change.
+const MAGIC: &[u8; 4] = b"loxc";
+const VERSION: u16 = 4;
+
+// Tags of the constants
+const NUMBER: u8 = 0;
+const BOOL: u8 = 1;
+const NIL: u8 = 2;
+const STRING: u8 = 3;
+const FUNCTION: u8 = 4;
+const INT: u8 = 5;
+
+// Functions in functions in ... are read recursively, deeper ones are rejected before they
+// overflow the stack
+const MAX_NESTING: usize = 256;
+
+impl Chunk {
+    /// Writes the code, constants, strings, global slots and lines. The names of locals are
+    /// debug information for the debugger and are left out.
+    pub fn serialize<W: Write>(&self, w: &mut W) -> Result<(), InterpretError> {
+        w.write_all(MAGIC)?;
+        w.write_all(&VERSION.to_le_bytes())?;
+        self.write_tables(w)
+    }
+
+    /// Reads a chunk written by `serialize`, files of another version are a `LoadError`. So is
+    /// code the vm can't run safely: operands out of range or an unbalanced stack.
+    pub fn deserialize<R: Read>(r: &mut R) -> Result<Chunk, InterpretError> {
+        let mut magic = [0; 4];
+        read_exact(r, &mut magic)?;
+        let mut version = [0; 2];
+        read_exact(r, &mut version)?;
+        if &magic != MAGIC || u16::from_le_bytes(version) != VERSION {
+            return Err(InterpretError::LoadError);
+        }
+        let chunk = Self::read_tables(r, 0)?;
+        chunk.check_loaded(0)?;
+        Ok(chunk)
+    }
+
+    // The parser only emits balanced code, the bytes of a file can be anything
+    fn check_loaded(&self, start_depth: usize) -> Result<(), InterpretError> {
+        self.check_stack_balance(start_depth)
+            .map_err(|_| InterpretError::LoadError)?;
+        for constant in self.constants.iter() {
+            if let Some(Obj::Function { arity, chunk, .. }) = constant.as_object() {
+                // The callee and its arguments are on the stack when the body starts
+                chunk.check_loaded(arity + 1)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn write_tables<W: Write>(&self, w: &mut W) -> Result<(), InterpretError> {
+        write_len(w, self.code.len())?;
+        for at in 0..self.code.len() {
+            w.write_all(&[self.code.get(at).unwrap()])?;
+        }
+
+        let runs = self.lines.runs().collect::<Vec<_>>();
+        write_len(w, runs.len())?;
+        for (line, count) in runs {
+            write_len(w, line)?;
+            write_len(w, count)?;
+        }
+
+        write_len(w, self.constants.len())?;
+        for constant in self.constants.iter() {
+            write_constant(w, constant)?;
+        }
+
+        write_len(w, self.strings.0.len())?;
+        for it in self.strings.iter() {
+            write_str(w, it)?;
+        }
+
+        write_len(w, self.global_slots.len())?;
+        for id in 0..self.global_slots.len() {
+            write_str(w, self.global_slots.name(id).unwrap())?;
+        }
+        Ok(())
+    }
+
+    fn read_tables<R: Read>(r: &mut R, nesting: usize) -> Result<Chunk, InterpretError> {
+        let mut chunk = Chunk::new();
+
+        let len = read_len(r)?;
+        let code = read_bytes(r, len)?;
+
+        // Never more lines than there is code, whatever the counts say
+        let mut lines = Vec::new();
+        for _ in 0..read_len(r)? {
+            let (line, count) = (read_len(r)?, read_len(r)?);
+            if count > code.len() - lines.len() {
+                return Err(InterpretError::LoadError);
+            }
+            lines.extend(std::iter::repeat_n(line, count));
+        }
+        if lines.len() != code.len() {
+            return Err(InterpretError::LoadError);
+        }
+        for (byte, line) in code.into_iter().zip(lines) {
+            chunk.push_raw_byte(byte, line);
+        }
+
+        for _ in 0..read_len(r)? {
+            chunk.add_constant(read_constant(r, nesting)?);
+        }
+        for _ in 0..read_len(r)? {
+            chunk.strings.add(read_str(r)?);
+        }
+        for _ in 0..read_len(r)? {
+            chunk.global_slots.add(read_str(r)?);
+        }
+        Ok(chunk)
+    }
+}
+
+fn write_len<W: Write>(w: &mut W, len: usize) -> Result<(), InterpretError> {
+    let len = u32::try_from(len).map_err(|_| InterpretError::LoadError)?;
+    w.write_all(&len.to_le_bytes())?;
+    Ok(())
+}
+
+fn write_str<W: Write>(w: &mut W, str: &str) -> Result<(), InterpretError> {
+    write_len(w, str.len())?;
+    w.write_all(str.as_bytes())?;
+    Ok(())
+}
+
+fn write_constant<W: Write>(w: &mut W, constant: &Value) -> Result<(), InterpretError> {
+    match constant {
+        Value::Number(it) => {
+            w.write_all(&[NUMBER])?;
+            w.write_all(&it.to_le_bytes())?;
+        }
+        Value::Int(it) => {
+            w.write_all(&[INT])?;
+            w.write_all(&it.to_le_bytes())?;
+        }
+        Value::Bool(it) => w.write_all(&[BOOL, *it as u8])?,
+        Value::Nil => w.write_all(&[NIL])?,
+        Value::Object(it) => match it.as_ref() {
+            Obj::String { str } => {
+                w.write_all(&[STRING])?;
+                write_str(w, str)?;
+            }
+            Obj::Function { name, arity, chunk } => {
+                w.write_all(&[FUNCTION])?;
+                write_str(w, name)?;
+                write_len(w, *arity)?;
+                chunk.write_tables(w)?;
+            }
+            // Natives are defined by the vm, compiled code never holds one
+            Obj::Native { .. } => Err(InterpretError::RuntimeErrorWithReason(
+                "Can't serialize a native function",
+            ))?,
+            // Arrays are built while running, never a constant
+            Obj::Array { .. } => Err(InterpretError::RuntimeErrorWithReason(
+                "Can't serialize an array",
+            ))?,
+        },
+    }
+    Ok(())
+}
+
+// A file that ends early is just as broken as one with a wrong header
+fn read_exact<R: Read>(r: &mut R, buffer: &mut [u8]) -> Result<(), InterpretError> {
+    r.read_exact(buffer).map_err(|it| match it.kind() {
+        ErrorKind::UnexpectedEof => InterpretError::LoadError,
+        _ => it.into(),
+    })
+}
+
+fn read_len<R: Read>(r: &mut R) -> Result<usize, InterpretError> {
+    let mut bytes = [0; 4];
+    read_exact(r, &mut bytes)?;
+    Ok(u32::from_le_bytes(bytes) as usize)
+}
+
+// Grows with what is actually read, a length field alone can't make us allocate gigabytes
+fn read_bytes<R: Read>(r: &mut R, len: usize) -> Result<Vec<u8>, InterpretError> {
+    let mut bytes = Vec::new();
+    r.take(len as u64).read_to_end(&mut bytes)?;
+    match bytes.len() == len {
+        true => Ok(bytes),
+        false => Err(InterpretError::LoadError),
+    }
+}
+
+fn read_str<R: Read>(r: &mut R) -> Result<String, InterpretError> {
+    let len = read_len(r)?;
+    String::from_utf8(read_bytes(r, len)?).map_err(|_| InterpretError::LoadError)
+}
+
+fn read_constant<R: Read>(r: &mut R, nesting: usize) -> Result<Value, InterpretError> {
+    let mut tag = [0; 1];
+    read_exact(r, &mut tag)?;
+    let it = match tag[0] {
+        NUMBER => {
+            let mut bytes = [0; 8];
+            read_exact(r, &mut bytes)?;
+            Value::Number(f64::from_le_bytes(bytes))
+        }
+        INT => {
+            let mut bytes = [0; 8];
+            read_exact(r, &mut bytes)?;
+            Value::Int(i64::from_le_bytes(bytes))
+        }
+        BOOL => {
+            let mut byte = [0; 1];
+            read_exact(r, &mut byte)?;
+            Value::Bool(byte[0] != 0)
+        }
+        NIL => Value::Nil,
+        STRING => Value::Object(Rc::new(Obj::String { str: read_str(r)? })),
+        FUNCTION if nesting < MAX_NESTING => {
+            let name = read_str(r)?;
+            let arity = read_len(r)?;
+            let chunk = Rc::new(Chunk::read_tables(r, nesting + 1)?);
+            Value::Object(Rc::new(Obj::Function { name, arity, chunk }))
+        }
+        _ => Err(InterpretError::LoadError)?,
+    };
+    Ok(it)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::opcode::OpCode;
+    use crate::parser::Parser;
+    use crate::tokenizer::Tokenizer;
+    use crate::vm::interpret;
+
+    #[test]
+    fn round_trip() {
+        let source = "var greeting = \"hi\";
+fun twice(x) {
+  var y = x * 2;
+  return y;
+}
+var i = 0;
+while (i < 3) { i = i + 1; }
+return twice(i) + 0.5;";
+        let chunk = Parser::parse(Tokenizer::new(source)).unwrap();
+
+        let mut bytes = Vec::new();
+        chunk.serialize(&mut bytes).unwrap();
+        let loaded = Chunk::deserialize(&mut bytes.as_slice()).unwrap();
+
+        assert_eq!(
+            loaded.disassemble_into_string("loaded"),
+            chunk.disassemble_into_string("loaded")
+        );
+        assert_eq!(
+            interpret(&loaded).unwrap(),
+            crate::opcode::Returned::Number(6.5)
+        );
+    }
+
+    #[test]
+    fn rejects_other_versions() {
+        let chunk = Parser::parse(Tokenizer::new("return 1;")).unwrap();
+        let mut bytes = Vec::new();
+        chunk.serialize(&mut bytes).unwrap();
+
+        let mut other = bytes.clone();
+        other[4..6].copy_from_slice(&(VERSION + 1).to_le_bytes());
+        assert!(matches!(
+            Chunk::deserialize(&mut other.as_slice()),
+            Err(InterpretError::LoadError)
+        ));
+
+        // Not a compiled chunk at all, or cut short
+        for it in [&b"return 1;"[..], &bytes[..bytes.len() - 1]] {
+            assert!(matches!(
+                Chunk::deserialize(&mut &it[..]),
+                Err(InterpretError::LoadError)
+            ));
+        }
+    }
+
+    #[test]
+    fn rejects_code_the_vm_cant_run() {
+        let serialized = |chunk: Chunk| {
+            let mut bytes = Vec::new();
+            chunk.serialize(&mut bytes).unwrap();
+            bytes
+        };
+
+        // Calls with more arguments than there are on the stack
+        let mut chunk = Chunk::new();
+        chunk.write_constant(Value::Int(0), 0);
+        chunk.write_call(8, 0).unwrap();
+        chunk.write_code(OpCode::Return, 0);
+        let mut broken = vec![serialized(chunk)];
+
+        // Sets a local in a slot that isn't on the stack
+        let mut chunk = Chunk::new();
+        chunk.write_code(OpCode::Nil, 0);
+        chunk.write_set_local_var(9, 0);
+        chunk.write_code(OpCode::Return, 0);
+        broken.push(serialized(chunk));
+
+        // The same in the body of a function
+        let mut function = Chunk::new();
+        function.write_get_local_var(2, 0);
+        function.write_code(OpCode::Return, 0);
+        let mut chunk = Chunk::new();
+        chunk.write_function(
+            Value::Object(Rc::new(Obj::Function {
+                name: "f".to_string(),
+                arity: 1,
+                chunk: Rc::new(function),
+            })),
+            0,
+        );
+        chunk.write_code(OpCode::Return, 0);
+        broken.push(serialized(chunk));
+
+        let header = [&MAGIC[..], &VERSION.to_le_bytes()].concat();
+        // Lengths far beyond what follows
+        broken.push([&header[..], &u32::MAX.to_le_bytes()].concat());
+        let one_byte_of_code = [&header[..], &[1, 0, 0, 0, OpCode::Nil as u8]].concat();
+        broken.push([&one_byte_of_code[..], &[1, 0, 0, 0], &[0xff; 8]].concat());
+
+        for it in broken {
+            assert!(
+                matches!(
+                    Chunk::deserialize(&mut it.as_slice()),
+                    Err(InterpretError::LoadError)
+                ),
+                "{:?}",
+                it
+            );
+        }
+    }
+}
@@ -0,0 +1,143 @@
+use crate::chunk::Chunk;
+use crate::opcode::OpCode;
+use crate::vm::CompilationErrorReason::UnbalancedStack;
+use crate::vm::InterpretError;
+use crate::vm::InterpretError::CompileError;
+
+// Static check of the stack depth the code runs with, to catch codegen bugs
+// like values left behind on the stack
+
+impl Chunk {
+    /// Walks every path through the code starting with `start_depth` values on the stack.
+    /// Every instruction must see the same depth on all paths, never pop more than there is
+    /// or read a local slot that isn't on the stack, and the final return must leave exactly
+    /// the returned value on top of the start.
+    pub fn check_stack_balance(&self, start_depth: usize) -> Result<(), InterpretError> {
+        let end = self.code.len();
+        let mut depths: Vec<Option<usize>> = vec![None; end];
+        let mut pending = vec![(0, start_depth)];
+
+        while let Some((at, depth)) = pending.pop() {
+            match depths.get(at) {
+                Some(Some(seen)) if *seen == depth => continue,
+                Some(Some(_)) | None => Err(CompileError(UnbalancedStack(at)))?,
+                Some(None) => depths[at] = Some(depth),
+            }
+
+            let code = self
+                .read_byte(at)
+                .and_then(|it| OpCode::try_from(it).ok())
+                .ok_or(CompileError(UnbalancedStack(at)))?;
+            // A local lives in a slot below the top of the stack
+            if matches!(code, OpCode::GetLocal | OpCode::SetLocal) {
+                let slot = self.read_byte(at + 1).unwrap_or(u8::MAX) as usize;
+                if slot >= depth {
+                    Err(CompileError(UnbalancedStack(at)))?
+                }
+            }
+            let (pops, pushes) = self.stack_effect(code, at);
+            let depth = depth
+                .checked_sub(pops)
+                .ok_or(CompileError(UnbalancedStack(at)))?
+                + pushes;
+            let next = at + 1 + code.operand_len();
+
+            match code {
+                OpCode::Return => {
+                    let is_last = next == end;
+                    if is_last && depth != start_depth {
+                        Err(CompileError(UnbalancedStack(at)))?
+                    }
+                }
+                OpCode::Jump => pending.push((self.jump_target(at)?, depth)),
+                OpCode::Loop => pending.push((self.jump_target(at)?, depth)),
+                OpCode::JumpIfFalse | OpCode::JumpIfTrue => {
+                    pending.push((self.jump_target(at)?, depth));
+                    pending.push((next, depth));
+                }
+                _ => pending.push((next, depth)),
+            }
+        }
+        Ok(())
+    }
+
+    // How many values the instruction takes from the stack and how many it puts back
+    fn stack_effect(&self, code: OpCode, at: usize) -> (usize, usize) {
+        use OpCode::*;
+
+        match code {
+            Constant | ConstantLong | Nil | True | False | String | StringLong | GetGlobal
+            | GetGlobalLong | GetGlobalSlot | GetLocal | Function | FunctionLong => (0, 1),
+            Equal | Greater | Less | Add | Subtract | Multiply | Divide | Modulo | IntDivide
+            | Index => (2, 1),
+            Not | Negate => (1, 1),
+            SetGlobal | SetGlobalLong | SetGlobalSlot | SetLocal => (1, 1),
+            // Conditional jumps only peek at the condition
+            JumpIfFalse | JumpIfTrue | Jump | Loop => (0, 0),
+            Call | TailCall => {
+                let arg_count = self.read_byte(at + 1).unwrap_or(0) as usize;
+                (arg_count + 1, 1)
+            }
+            BuildArray => (self.read_byte(at + 1).unwrap_or(0) as usize, 1),
+            SetIndex => (3, 1),
+            DefineGlobal | DefineGlobalLong | DefineGlobalSlot | Print | EPrint | Pop | Return => {
+                (1, 0)
+            }
+        }
+    }
+
+    fn jump_target(&self, at: usize) -> Result<usize, InterpretError> {
+        self.read_jump_target(at)
+            .ok_or(CompileError(UnbalancedStack(at)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::opcode::Value;
+
+    #[test]
+    fn balanced_chunk() {
+        let mut chunk = Chunk::new();
+        chunk.write_constant(Value::Number(1.0), 0);
+        chunk.write_code(OpCode::Print, 0);
+        chunk.write_code(OpCode::Nil, 0);
+        chunk.write_code(OpCode::Return, 0);
+
+        assert!(chunk.check_stack_balance(0).is_ok());
+    }
+
+    #[test]
+    fn unbalanced_chunk() {
+        // A value is left behind below the returned one
+        let mut chunk = Chunk::new();
+        chunk.write_constant(Value::Number(1.0), 0);
+        chunk.write_code(OpCode::Nil, 0);
+        chunk.write_code(OpCode::Return, 0);
+        assert!(matches!(
+            chunk.check_stack_balance(0),
+            Err(CompileError(UnbalancedStack(3)))
+        ));
+
+        // Popping more than there is
+        let mut chunk = Chunk::new();
+        chunk.write_code(OpCode::Pop, 0);
+        chunk.write_code(OpCode::Nil, 0);
+        chunk.write_code(OpCode::Return, 0);
+        assert!(matches!(
+            chunk.check_stack_balance(0),
+            Err(CompileError(UnbalancedStack(0)))
+        ));
+
+        // Setting a local slot above the top of the stack
+        let mut chunk = Chunk::new();
+        chunk.write_code(OpCode::Nil, 0);
+        chunk.write_set_local_var(1, 0);
+        chunk.write_code(OpCode::Return, 0);
+        assert!(matches!(
+            chunk.check_stack_balance(0),
+            Err(CompileError(UnbalancedStack(1)))
+        ));
+    }
+}
@@ -7,14 +7,49 @@ use crate::vm::InterpretError::{CompileError, RuntimeErrorWithReason};
 pub struct LocalVar {
     name: String,
     scope_depth: i32,
+    line: usize,   // where it is declared, for diagnostics
+    is_used: bool, // has it been read at least once
 }
 
 impl LocalVar {
-    pub fn new(name: String, scope_depth: i32) -> Self {
-        Self { name, scope_depth }
+    pub fn new(name: String, scope_depth: i32, line: usize) -> Self {
+        Self {
+            name,
+            scope_depth,
+            line,
+            is_used: false,
+        }
+    }
+}
+
+// A non-fatal problem found while compiling, like an unused local variable
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub line: usize,
+    pub message: String,
+}
+
+impl Diagnostic {
+    pub fn new(line: usize, message: String) -> Self {
+        Self { line, message }
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StoreKind {
+    Initializer, // `var x = 1;` the value becomes the local's stack slot
+    Assignment,  // `x = 1;` sets the slot and pops the value
+}
+
+// The code of the last store to a local, it is dead when it is overwritten before being read
+#[derive(Debug, Clone, Copy)]
+pub struct LocalStore {
+    pub slot: usize,
+    pub kind: StoreKind,
+    pub start: usize,
+    pub end: usize,
+}
+
 pub enum LocalVarResolution {
     NotFound,
     FoundAt(usize),
@@ -25,6 +60,8 @@ pub enum LocalVarResolution {
 pub struct Compiler {
     locals: Vec<LocalVar>,
     scope_depth: i32,
+    diagnostics: Vec<Diagnostic>,
+    last_store: Option<LocalStore>, // candidate for dead store elimination
 }
 
 impl Compiler {
@@ -32,6 +69,8 @@ impl Compiler {
         Self {
             locals: Vec::with_capacity(u8::MAX as usize),
             scope_depth: 0,
+            diagnostics: Vec::new(),
+            last_store: None,
         }
     }
 
@@ -55,7 +94,11 @@ impl Compiler {
         let mut pop = 0;
 
         while pop < count {
-            self.locals.pop();
+            if let Some(v) = self.locals.pop() {
+                if !v.is_used {
+                    self.warn(v.line, format!("Local variable '{}' is never used", v.name));
+                }
+            }
             pop += 1;
         }
 
@@ -64,18 +107,42 @@ impl Compiler {
         Ok(count)
     }
 
+    // Back at the top level, keeps what was gathered so far like the diagnostics
+    pub fn reset_locals(&mut self) {
+        self.locals.clear();
+        self.scope_depth = 0;
+        self.last_store = None;
+    }
+
+    // After an error, drops the scopes deeper than `scope_depth` that never got closed. Their
+    // locals are not checked, the code using them is thrown away anyway.
+    pub fn unwind_to(&mut self, scope_depth: i32) {
+        self.locals.retain(|it| it.scope_depth <= scope_depth);
+        self.scope_depth = scope_depth;
+        self.last_store = None;
+    }
+
+    pub fn scope_depth(&self) -> i32 {
+        self.scope_depth
+    }
+
+    pub fn local_count(&self) -> usize {
+        self.locals.len()
+    }
+
     pub fn in_local_scope(&mut self) -> bool {
         self.scope_depth > 0
     }
 
-    pub fn add_local_var(&mut self, name: String) -> Result<usize, InterpretError> {
+    pub fn add_local_var(&mut self, name: String, line: usize) -> Result<usize, InterpretError> {
         if self.is_in_scope_name_collision(name.as_str()) {
             Err(RuntimeErrorWithReason(
                 "Already a variable with this name in this scope",
             ))?
         }
         let at = self.locals.len();
-        self.locals.push(LocalVar::new(name, self.scope_depth));
+        self.locals
+            .push(LocalVar::new(name, self.scope_depth, line));
         Ok(at)
     }
 
@@ -107,4 +174,70 @@ impl Compiler {
         }
         LocalVarResolution::NotFound
     }
+
+    // Name of the local in the slot, for messages about a resolved local
+    pub fn local_name_at(&self, at: usize) -> Option<&str> {
+        self.locals.get(at).map(|it| it.name.as_str())
+    }
+
+    pub fn mark_local_used(&mut self, at: usize) {
+        if let Some(v) = self.locals.get_mut(at) {
+            v.is_used = true;
+        }
+    }
+
+    pub fn track_store(&mut self, store: Option<LocalStore>) {
+        self.last_store = store;
+    }
+
+    pub fn take_store(&mut self) -> Option<LocalStore> {
+        self.last_store.take()
+    }
+
+    pub fn warn(&mut self, line: usize, message: String) {
+        self.diagnostics.push(Diagnostic::new(line, message));
+    }
+
+    pub fn take_diagnostics(&mut self) -> Vec<Diagnostic> {
+        std::mem::take(&mut self.diagnostics)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reset_locals_keeps_diagnostics() {
+        let mut compiler = Compiler::new();
+        compiler.begin_scope().unwrap();
+        compiler.begin_scope().unwrap();
+        compiler.add_local_var("foo".to_string(), 0).unwrap();
+        compiler.warn(0, "careful".to_string());
+
+        compiler.reset_locals();
+
+        assert!(!compiler.in_local_scope());
+        assert!(compiler.locals.is_empty());
+        assert!(matches!(
+            compiler.resolve_local_variable("foo"),
+            LocalVarResolution::NotFound
+        ));
+        assert_eq!(compiler.take_diagnostics().len(), 1);
+    }
+
+    #[test]
+    fn local_name_at_resolved_slot() {
+        let mut compiler = Compiler::new();
+        compiler.begin_scope().unwrap();
+        compiler.add_local_var("bar".to_string(), 0).unwrap();
+        compiler.add_local_var("foo".to_string(), 0).unwrap();
+
+        let at = match compiler.resolve_local_variable("foo") {
+            LocalVarResolution::FoundAt(at) => at,
+            LocalVarResolution::NotFound => panic!("foo should resolve"),
+        };
+        assert_eq!(compiler.local_name_at(at), Some("foo"));
+        assert_eq!(compiler.local_name_at(at + 1), None);
+    }
 }
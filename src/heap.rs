@@ -1,3 +1,127 @@
+use crate::opcode::{Obj, Value};
+use std::borrow::Borrow;
+use std::hash::{Hash, Hasher};
+use std::mem;
+
 pub mod offset;
 pub mod pointer;
 pub mod rc;
+
+/// Where objects are allocated, each implementation documents its tradeoffs. The handle is
+/// how the heap refers to an object it allocated. Values hold an `Rc<Obj>`, so only the
+/// `RcHeap` can back the vm; the others stay to compare the approaches.
+pub trait Heap {
+    type Handle;
+
+    fn alloc(&mut self, object: Obj) -> Self::Handle;
+
+    /// Allocates the string once, the same content gives the same handle
+    fn intern(&mut self, str: String) -> Self::Handle;
+
+    fn free_all(&mut self);
+
+    /// Objects alive on the heap
+    fn size(&self) -> usize;
+
+    /// Estimated bytes the objects alive on the heap take up
+    fn bytes_allocated(&self) -> usize;
+
+    /// Objects allocated since the heap was created, including the ones freed since
+    fn allocation_count(&self) -> usize;
+
+    /// Frees the objects the roots can't reach. Heaps whose handles values don't hold can't
+    /// tell which objects are still used, they keep all of them.
+    fn collect<'v, I: Iterator<Item = &'v Value>>(&mut self, roots: I) {
+        drop(roots)
+    }
+}
+
+// The handle of an interned string, hashed and looked up by the content of its object so the
+// table doesn't keep a second copy of it
+pub(crate) struct Interned<T>(pub T);
+
+impl<T: AsRef<Obj>> Borrow<str> for Interned<T> {
+    fn borrow(&self) -> &str {
+        self.0.as_ref().as_string()
+    }
+}
+
+impl<T: AsRef<Obj>> Hash for Interned<T> {
+    fn hash<S: Hasher>(&self, state: &mut S) {
+        Borrow::<str>::borrow(self).hash(state)
+    }
+}
+
+impl<T: AsRef<Obj>> PartialEq for Interned<T> {
+    fn eq(&self, other: &Self) -> bool {
+        Borrow::<str>::borrow(self) == Borrow::<str>::borrow(other)
+    }
+}
+
+impl<T: AsRef<Obj>> Eq for Interned<T> {}
+
+// The object itself plus what it owns, the chunk of a function is shared so it doesn't count
+pub(crate) fn estimated_size(object: &Obj) -> usize {
+    let owned = match object {
+        Obj::String { str } => str.len(),
+        Obj::Function { name, .. } | Obj::Native { name, .. } => name.len(),
+        Obj::Array { elements } => elements.borrow().len() * mem::size_of::<Value>(),
+    };
+    mem::size_of::<Obj>() + owned
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use offset::OffsetHeap;
+    use pointer::PointerHeap;
+    use rc::RcHeap;
+    use std::rc::Rc;
+
+    #[test]
+    fn intern_strings_once() {
+        let mut heap = RcHeap::new();
+        let (a, b) = (heap.intern("ok".to_string()), heap.intern("ok".to_string()));
+        assert!(Rc::ptr_eq(&a, &b));
+        assert!(!Rc::ptr_eq(&a, &heap.intern("ko".to_string())));
+        assert_eq!(heap.size(), 2);
+
+        let mut heap = PointerHeap::new();
+        let (a, b) = (heap.intern("ok".to_string()), heap.intern("ok".to_string()));
+        assert!(a == b);
+        assert_eq!(heap.size(), 1);
+
+        let mut heap = OffsetHeap::new();
+        let (a, b) = (heap.intern("ok".to_string()), heap.intern("ok".to_string()));
+        assert_eq!(a, b);
+        assert_eq!(heap.size(), 1);
+    }
+
+    #[test]
+    fn count_allocations() {
+        let mut heap = RcHeap::new();
+        let kept = Value::Object(heap.intern("a".to_string()));
+        heap.intern("bb".to_string());
+        heap.intern("a".to_string());
+        heap.alloc(Obj::String {
+            str: "ccc".to_string(),
+        });
+
+        assert_eq!((heap.size(), heap.allocation_count()), (3, 3));
+        let objects = 3 * mem::size_of::<Obj>() + 6;
+        assert!((objects..objects + 100).contains(&heap.bytes_allocated()));
+
+        heap.collect([&kept].into_iter());
+        assert_eq!((heap.size(), heap.allocation_count()), (1, 3));
+        let object = mem::size_of::<Obj>() + 1;
+        assert!((object..object + 40).contains(&heap.bytes_allocated()));
+
+        let mut heap = OffsetHeap::new();
+        heap.intern("a".to_string());
+        heap.intern("a".to_string());
+        assert_eq!(heap.allocation_count(), 1);
+        assert_eq!(heap.bytes_allocated(), mem::size_of::<Obj>() + 1);
+        heap.free_all();
+        assert_eq!((heap.bytes_allocated(), heap.allocation_count()), (0, 1));
+    }
+}
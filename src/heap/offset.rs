@@ -1,6 +1,10 @@
 #![allow(dead_code)]
 
+use crate::heap::{estimated_size, Heap};
 use crate::opcode::Obj;
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::BuildHasher;
 
 // Heap implementation that just returns an offset into the heap (it 'address')
 // - Pro:
@@ -10,26 +14,66 @@ use crate::opcode::Obj;
 
 pub struct OffsetHeap {
     objects: Vec<Obj>,
+    // Interned strings by the hash of their content, the offsets alone can't be looked up by it
+    strings: HashMap<u64, Vec<usize>>,
+    hasher: RandomState,
+    bytes: usize,
+    allocations: usize,
 }
 
 impl OffsetHeap {
     pub fn new() -> Self {
         Self {
             objects: Vec::new(),
+            strings: HashMap::new(),
+            hasher: RandomState::new(),
+            bytes: 0,
+            allocations: 0,
         }
     }
+}
+
+impl Heap for OffsetHeap {
+    type Handle = usize;
 
-    pub fn alloc(&mut self, object: Obj) -> usize {
+    fn alloc(&mut self, object: Obj) -> usize {
+        self.bytes += estimated_size(&object);
+        self.allocations += 1;
         let at = self.objects.len();
         self.objects.push(object);
         at
     }
 
-    pub fn free_all(&mut self) {
+    fn intern(&mut self, str: String) -> usize {
+        let hash = self.hasher.hash_one(&str);
+        let same = self.strings.get(&hash).and_then(|offsets| {
+            offsets
+                .iter()
+                .find(|it| self.objects[**it].as_string() == str)
+        });
+        if let Some(it) = same {
+            return *it;
+        }
+        let it = self.alloc(Obj::String { str });
+        self.strings.entry(hash).or_default().push(it);
+        it
+    }
+
+    fn free_all(&mut self) {
         self.objects.clear();
+        self.strings.clear();
+        self.bytes = 0;
     }
 
-    pub fn size(&self) -> usize {
+    fn size(&self) -> usize {
         self.objects.len()
     }
+
+    fn bytes_allocated(&self) -> usize {
+        self.bytes
+    }
+
+    fn allocation_count(&self) -> usize {
+        self.allocations
+    }
 }
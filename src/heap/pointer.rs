@@ -1,6 +1,8 @@
 #![allow(dead_code)]
 
+use crate::heap::{estimated_size, Heap, Interned};
 use crate::opcode::Obj;
+use std::collections::HashSet;
 use std::ptr::NonNull;
 
 // Unsafe pointer heap implementation that works with raw pointers.
@@ -13,6 +15,9 @@ use std::ptr::NonNull;
 
 pub struct PointerHeap {
     objects: Vec<Pointer>,
+    strings: HashSet<Interned<Pointer>>,
+    bytes: usize,
+    allocations: usize,
 }
 
 // NewType around NonNull to make dereferencing easier
@@ -28,33 +33,72 @@ impl Pointer {
         Self { pointer }
     }
 
-    pub fn as_ref(&self) -> &Obj {
-        unsafe { self.pointer.as_ref() }
-    }
-
     pub fn as_mut(&mut self) -> &mut Obj {
         unsafe { self.pointer.as_mut() }
     }
 }
 
+impl AsRef<Obj> for Pointer {
+    fn as_ref(&self) -> &Obj {
+        unsafe { self.pointer.as_ref() }
+    }
+}
+
 impl PointerHeap {
     pub fn new() -> Self {
         Self {
             objects: Vec::new(),
+            strings: HashSet::new(),
+            bytes: 0,
+            allocations: 0,
         }
     }
+}
+
+impl Heap for PointerHeap {
+    type Handle = Pointer;
 
-    pub fn alloc(&mut self, object: Obj) -> Pointer {
+    fn alloc(&mut self, object: Obj) -> Pointer {
+        self.bytes += estimated_size(&object);
+        self.allocations += 1;
         let it = Pointer::new(object);
         self.objects.push(it);
         it
     }
 
-    pub fn free_all(&mut self) {
-        self.objects.clear();
+    fn intern(&mut self, str: String) -> Pointer {
+        if let Some(it) = self.strings.get(str.as_str()) {
+            return it.0;
+        }
+        let it = self.alloc(Obj::String { str });
+        self.strings.insert(Interned(it));
+        it
     }
 
-    pub fn size(&self) -> usize {
+    fn free_all(&mut self) {
+        self.strings.clear();
+        self.bytes = 0;
+        for it in self.objects.drain(..) {
+            // Every pointer came from a box in `Pointer::new`
+            drop(unsafe { Box::from_raw(it.pointer.as_ptr()) });
+        }
+    }
+
+    fn size(&self) -> usize {
         self.objects.len()
     }
+
+    fn bytes_allocated(&self) -> usize {
+        self.bytes
+    }
+
+    fn allocation_count(&self) -> usize {
+        self.allocations
+    }
+}
+
+impl Drop for PointerHeap {
+    fn drop(&mut self) {
+        self.free_all()
+    }
 }
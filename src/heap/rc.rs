@@ -1,4 +1,7 @@
-use crate::opcode::Obj;
+use crate::heap::{estimated_size, Heap, Interned};
+use crate::opcode::{Obj, Value};
+use std::collections::HashSet;
+use std::mem;
 use std::rc::Rc;
 
 // Safe pointer heap implementation that works with rc.
@@ -14,22 +17,86 @@ use std::rc::Rc;
 
 pub struct RcHeap {
     objects: Vec<Rc<Obj>>,
+    strings: HashSet<Interned<Rc<Obj>>>,
+    bytes: usize,
+    allocations: usize,
 }
 
 impl RcHeap {
     pub fn new() -> Self {
         Self {
             objects: Vec::new(),
+            strings: HashSet::new(),
+            bytes: 0,
+            allocations: 0,
         }
     }
 
-    pub fn alloc(&mut self, object: Obj) -> Rc<Obj> {
+    // The rc box adds the strong and weak counts to the object
+    fn estimated_size(object: &Obj) -> usize {
+        estimated_size(object) + 2 * mem::size_of::<usize>()
+    }
+}
+
+impl Heap for RcHeap {
+    type Handle = Rc<Obj>;
+
+    fn alloc(&mut self, object: Obj) -> Rc<Obj> {
+        self.bytes += Self::estimated_size(&object);
+        self.allocations += 1;
         let it = Rc::new(object);
         self.objects.push(Rc::clone(&it));
         Rc::clone(&it)
     }
 
-    pub fn free_all(&mut self) {
+    fn intern(&mut self, str: String) -> Rc<Obj> {
+        if let Some(it) = self.strings.get(str.as_str()) {
+            return Rc::clone(&it.0);
+        }
+        let it = self.alloc(Obj::String { str });
+        self.strings.insert(Interned(Rc::clone(&it)));
+        it
+    }
+
+    fn free_all(&mut self) {
         self.objects.clear();
+        self.strings.clear();
+        self.bytes = 0;
+    }
+
+    fn size(&self) -> usize {
+        self.objects.len()
+    }
+
+    fn bytes_allocated(&self) -> usize {
+        self.bytes
+    }
+
+    fn allocation_count(&self) -> usize {
+        self.allocations
+    }
+
+    // Mark and sweep. An object only really goes away once no value holds its rc anymore,
+    // the heap just stops keeping it alive.
+    fn collect<'v, I: Iterator<Item = &'v Value>>(&mut self, roots: I) {
+        let mut marked = HashSet::new();
+        let mut pending = roots.cloned().collect::<Vec<_>>();
+        while let Some(it) = pending.pop() {
+            if let Value::Object(object) = it {
+                if !marked.insert(Rc::as_ptr(&object)) {
+                    continue;
+                }
+                // The constants of a function and the elements of an array refer to objects too
+                match object.as_ref() {
+                    Obj::Function { chunk, .. } => pending.extend(chunk.constants_iter().cloned()),
+                    Obj::Array { elements } => pending.extend(elements.borrow().iter().cloned()),
+                    _ => {}
+                }
+            }
+        }
+        self.objects.retain(|it| marked.contains(&Rc::as_ptr(it)));
+        self.strings
+            .retain(|it| marked.contains(&Rc::as_ptr(&it.0)));
+        self.bytes = self.objects.iter().map(|it| Self::estimated_size(it)).sum();
     }
 }
@@ -7,5 +7,5 @@ pub mod opcode;
 mod parser;
 pub mod reader;
 pub mod repl;
-mod tokenizer;
+pub mod tokenizer;
 pub mod vm;
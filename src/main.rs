@@ -1,15 +1,92 @@
-use rlox::reader::run_file;
+use rlox::reader::{
+    compile_file, print_bytecode, print_tokens, run_file, run_file_streaming, run_files, run_source,
+};
 use rlox::repl::repl;
 use rlox::vm::InterpretError;
 use std::env::args;
 
+const USAGE: &str = "Usage: rlox [options] [path...]
+
+Runs the files one after the other, they share their globals. Starts a repl without any.
+Files compiled with --compile end in .loxc and run without parsing them again.
+
+Options:
+  -e <source>          Runs the source
+  --stream <path>      Compiles the file one statement at a time
+  --tokens <path>      Prints the tokens of the file
+  --bytecode <path>    Prints the bytecode of the file without running it
+  --compile <path>     Compiles the file to a .loxc file next to it
+  -h, --help           Prints this help
+  --version            Prints the version";
+
+// What the command line asks for
+#[derive(Debug, PartialEq)]
+enum Action {
+    Repl,
+    Run(Vec<String>),
+    Eval(String),
+    Stream(String),
+    Tokens(String),
+    Bytecode(String),
+    Compile(String),
+    Help,
+    Version,
+    Usage,
+}
+
+fn action(arguments: &[String]) -> Action {
+    // Asking for help or the version wins over anything else on the command line
+    if arguments.iter().any(|it| it == "-h" || it == "--help") {
+        return Action::Help;
+    }
+    if arguments.iter().any(|it| it == "--version") {
+        return Action::Version;
+    }
+    match arguments {
+        [] => Action::Repl,
+        [flag, source] if flag == "-e" => Action::Eval(source.clone()),
+        [flag, path] if flag == "--stream" => Action::Stream(path.clone()),
+        [flag, path] if flag == "--tokens" => Action::Tokens(path.clone()),
+        [flag, path] if flag == "--bytecode" => Action::Bytecode(path.clone()),
+        [flag, path] if flag == "--compile" => Action::Compile(path.clone()),
+        paths if !paths.iter().any(|it| it.starts_with('-')) => Action::Run(paths.to_vec()),
+        _ => Action::Usage,
+    }
+}
+
 fn main() -> Result<(), InterpretError> {
-    let arguments = args().collect::<Vec<String>>();
-    match &arguments[..] {
-        [_] => repl(),
-        [_, path] => run_file(path),
-        _ => {
-            println!("Usage: rlox [path]");
+    let arguments = args().skip(1).collect::<Vec<String>>();
+    match action(&arguments) {
+        Action::Repl => repl(),
+        Action::Run(paths) => {
+            let result = match &paths[..] {
+                [path] => run_file(path)?,
+                paths => run_files(paths)?,
+            };
+            println!("{}", result);
+            Ok(())
+        }
+        Action::Eval(source) => {
+            println!("{}", run_source(&source)?);
+            Ok(())
+        }
+        Action::Stream(path) => run_file_streaming(&path),
+        Action::Tokens(path) => print_tokens(&path),
+        Action::Bytecode(path) => print_bytecode(&path),
+        Action::Compile(path) => {
+            println!("Compiled to {}", compile_file(&path)?);
+            Ok(())
+        }
+        Action::Help => {
+            println!("{}", USAGE);
+            Ok(())
+        }
+        Action::Version => {
+            println!("rlox {}", env!("CARGO_PKG_VERSION"));
+            Ok(())
+        }
+        Action::Usage => {
+            println!("Usage: rlox [options] [path...], see --help");
             Ok(())
         }
     }
@@ -37,3 +114,63 @@ fn main() -> Result<(), InterpretError> {
     //
     // interpret(&x)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn action_for(arguments: &[&str]) -> Action {
+        let arguments = arguments
+            .iter()
+            .map(|it| it.to_string())
+            .collect::<Vec<_>>();
+        action(&arguments)
+    }
+
+    #[test]
+    fn help_and_version() {
+        assert_eq!(action_for(&["--help"]), Action::Help);
+        assert_eq!(action_for(&["-h"]), Action::Help);
+        assert_eq!(action_for(&["main.lox", "--help"]), Action::Help);
+        assert_eq!(action_for(&["--version"]), Action::Version);
+        assert_eq!(
+            action_for(&["--stream", "main.lox", "--version"]),
+            Action::Version
+        );
+    }
+
+    #[test]
+    fn dispatch_arguments() {
+        assert_eq!(action_for(&[]), Action::Repl);
+        assert_eq!(
+            action_for(&["a.lox"]),
+            Action::Run(vec!["a.lox".to_string()])
+        );
+        assert_eq!(
+            action_for(&["a.lox", "b.lox"]),
+            Action::Run(vec!["a.lox".to_string(), "b.lox".to_string()])
+        );
+        assert_eq!(
+            action_for(&["-e", "print 1;"]),
+            Action::Eval("print 1;".to_string())
+        );
+        assert_eq!(
+            action_for(&["--stream", "a.lox"]),
+            Action::Stream("a.lox".to_string())
+        );
+        assert_eq!(
+            action_for(&["--tokens", "a.lox"]),
+            Action::Tokens("a.lox".to_string())
+        );
+        assert_eq!(
+            action_for(&["--bytecode", "a.lox"]),
+            Action::Bytecode("a.lox".to_string())
+        );
+        assert_eq!(
+            action_for(&["--compile", "a.lox"]),
+            Action::Compile("a.lox".to_string())
+        );
+        assert_eq!(action_for(&["--unknown"]), Action::Usage);
+        assert_eq!(action_for(&["--tokens"]), Action::Usage);
+    }
+}
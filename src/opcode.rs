@@ -1,16 +1,54 @@
-use std::fmt::{Debug, Formatter};
+use crate::chunk::Chunk;
+use crate::vm::InterpretError;
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::fmt::{Debug, Display, Formatter};
 use std::mem;
 use std::rc::Rc;
 
-/// OpCodes used by our vm.
+#[cfg(feature = "nan-boxing")]
+pub mod nan_boxed;
+
+// OpCodes used by our vm.
 
 // Each opcode is a byte
 pub type Byte = u8;
 
-#[derive(Debug, PartialEq, Clone)]
+// A function implemented in Rust, gets the vm running it and the arguments it is called with
+pub type NativeFn = fn(&mut dyn Runtime, &[Value]) -> Result<Value, InterpretError>;
+
+// What a native can ask of the vm running it
+pub trait Runtime {
+    // Calls a function or native value and runs it until it returns
+    fn call_value(&mut self, callee: &Value, arguments: &[Value]) -> Result<Value, InterpretError>;
+
+    // Allocates the object on the heap of the vm
+    fn alloc_value(&mut self, object: Obj) -> Value;
+
+    // The string as a value, interned like every other string in the vm
+    fn intern_value(&mut self, str: String) -> Value;
+}
+
+#[derive(Clone)]
 pub enum Obj {
     // str itself is heap allocated
-    String { str: String },
+    String {
+        str: String,
+    },
+    // The compiled body of a `fun` declaration
+    Function {
+        name: String,
+        arity: usize,
+        chunk: Rc<Chunk>,
+    },
+    Native {
+        name: String,
+        function: NativeFn,
+    },
+    // Values are shared by an rc, setting an element has to go through the cell
+    Array {
+        elements: RefCell<Vec<Value>>,
+    },
 }
 
 impl Obj {
@@ -18,9 +56,87 @@ impl Obj {
         matches!(self, Obj::String { str: _ })
     }
 
+    pub fn is_function(&self) -> bool {
+        matches!(self, Obj::Function { .. })
+    }
+
     pub fn as_string(&self) -> &str {
-        let Obj::String { str } = self;
-        str.as_ref()
+        match self {
+            Obj::String { str } => str.as_ref(),
+            _ => panic!("Object is not a string"),
+        }
+    }
+
+    // `seen` are the arrays being rendered, one containing itself shows up as `[...]`
+    fn render(&self, f: &mut Formatter<'_>, seen: &mut Vec<*const Obj>) -> std::fmt::Result {
+        match self {
+            Obj::String { str } => write!(f, "{}", str),
+            Obj::Function { name, .. } => write!(f, "<fn {}>", name),
+            Obj::Native { .. } => write!(f, "<native fn>"),
+            Obj::Array { .. } if seen.contains(&(self as *const Obj)) => write!(f, "[...]"),
+            Obj::Array { elements } => {
+                seen.push(self);
+                write!(f, "[")?;
+                for (i, it) in elements.borrow().iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    match it {
+                        Value::Object(it) => it.render(f, seen)?,
+                        it => write!(f, "{}", it)?,
+                    }
+                }
+                seen.pop();
+                write!(f, "]")
+            }
+        }
+    }
+}
+
+impl PartialEq for Obj {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Obj::String { str: a }, Obj::String { str: b }) => a == b,
+            // A function is only equal to itself
+            (Obj::Function { chunk: a, .. }, Obj::Function { chunk: b, .. }) => Rc::ptr_eq(a, b),
+            (Obj::Native { function: a, .. }, Obj::Native { function: b, .. }) => {
+                std::ptr::fn_addr_eq(*a, *b)
+            }
+            // Like `==` in lox, an array is only equal to itself
+            (Obj::Array { .. }, Obj::Array { .. }) => std::ptr::eq(self, other),
+            _ => false,
+        }
+    }
+}
+
+// User facing rendering of an object, never exposes how it is represented internally
+impl Display for Obj {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        self.render(f, &mut Vec::new())
+    }
+}
+
+// Arrays show their elements as rendered for the user, they can contain themselves
+impl Debug for Obj {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Obj::String { str } => f.debug_struct("String").field("str", str).finish(),
+            Obj::Function { name, arity, chunk } => f
+                .debug_struct("Function")
+                .field("name", name)
+                .field("arity", arity)
+                .field("chunk", chunk)
+                .finish(),
+            Obj::Native { name, function } => f
+                .debug_struct("Native")
+                .field("name", name)
+                .field("function", function)
+                .finish(),
+            Obj::Array { .. } => f
+                .debug_tuple("Array")
+                .field(&format_args!("{}", self))
+                .finish(),
+        }
     }
 }
 
@@ -28,6 +144,8 @@ impl Obj {
 #[derive(Clone, PartialEq)]
 pub enum Value {
     Number(f64),
+    // Whole number literals, stays whole until mixed with a number or divided
+    Int(i64),
     Bool(bool),
     Object(Rc<Obj>),
     Nil,
@@ -37,8 +155,11 @@ pub enum Value {
 #[derive(Clone, PartialEq, Debug)]
 pub enum Returned {
     Number(f64),
+    Int(i64),
     Bool(bool),
     Object(Obj),
+    // Just what tells a function apart, not its chunk
+    Function { name: String, arity: usize },
     Nil,
 }
 
@@ -46,11 +167,15 @@ impl From<Value> for Returned {
     fn from(value: Value) -> Self {
         match value {
             Value::Number(it) => Returned::Number(it),
+            Value::Int(it) => Returned::Int(it),
             Value::Bool(it) => Returned::Bool(it),
-            Value::Object(it) => {
-                let it = it.as_ref();
-                Returned::Object(it.clone())
-            }
+            Value::Object(it) => match it.as_ref() {
+                Obj::Function { name, arity, .. } => Returned::Function {
+                    name: name.clone(),
+                    arity: *arity,
+                },
+                it => Returned::Object(it.clone()),
+            },
             Value::Nil => Returned::Nil,
         }
     }
@@ -70,16 +195,36 @@ impl From<f64> for Returned {
     }
 }
 
+impl From<i64> for Returned {
+    fn from(it: i64) -> Self {
+        Self::Int(it)
+    }
+}
+
 impl From<bool> for Returned {
     fn from(it: bool) -> Self {
         Self::Bool(it)
     }
 }
 
+impl Display for Returned {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Returned::Number(it) => write!(f, "{}", it),
+            Returned::Int(it) => write!(f, "{}", it),
+            Returned::Bool(it) => write!(f, "{}", it),
+            Returned::Object(it) => write!(f, "{}", it),
+            Returned::Function { name, .. } => write!(f, "<fn {}>", name),
+            Returned::Nil => write!(f, "nil"),
+        }
+    }
+}
+
 impl Debug for Value {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
             Value::Number(it) => write!(f, "{:?}", it),
+            Value::Int(it) => write!(f, "{}", it),
             Value::Bool(it) => write!(f, "{:?}", it),
             Value::Object(it) => write!(f, "Object({:?})", *it.as_ref()),
             Value::Nil => write!(f, "nil"),
@@ -87,9 +232,27 @@ impl Debug for Value {
     }
 }
 
+// User facing rendering of a value, use Debug to inspect its internals
+impl Display for Value {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Value::Number(it) => write!(f, "{}", it),
+            Value::Int(it) => write!(f, "{}", it),
+            Value::Bool(it) => write!(f, "{}", it),
+            Value::Object(it) => write!(f, "{}", it.as_ref()),
+            Value::Nil => write!(f, "nil"),
+        }
+    }
+}
+
 impl Value {
+    // Ints are numbers too, use `is_int` to tell them apart
     pub fn is_number(&self) -> bool {
-        matches!(self, Value::Number(_))
+        matches!(self, Value::Number(_) | Value::Int(_))
+    }
+
+    pub fn is_int(&self) -> bool {
+        matches!(self, Value::Int(_))
     }
 
     pub fn is_string(&self) -> bool {
@@ -109,18 +272,35 @@ impl Value {
             Value::Nil => false,
             Value::Bool(it) => *it,
             Value::Number(it) => *it != 0.0, // all number are truthy expect for 0
-            Value::Object(_) => false,       // @TODO revisit it
+            Value::Int(it) => *it != 0,
+            Value::Object(_) => false, // @TODO revisit it
         }
     }
     pub fn is_nil(&self) -> bool {
         matches!(self, Value::Nil)
     }
 
+    // Ints are promoted, large ones lose precision
     pub fn as_number(&self) -> f64 {
-        if let Value::Number(it) = self {
+        match self {
+            Value::Number(it) => *it,
+            Value::Int(it) => *it as f64,
+            _ => panic!("Value is not a number"),
+        }
+    }
+
+    pub fn as_int(&self) -> i64 {
+        if let Value::Int(it) = self {
             *it
         } else {
-            panic!("Value is not a number")
+            panic!("Value is not an int")
+        }
+    }
+
+    pub fn as_object(&self) -> Option<&Obj> {
+        match self {
+            Value::Object(it) => Some(it.as_ref()),
+            _ => None,
         }
     }
 
@@ -132,6 +312,26 @@ impl Value {
         }
     }
 
+    // The number as an index in a collection of `len` elements, negative ones count from
+    // the end so -1 is the last element
+    pub fn as_index(&self, len: usize) -> Result<usize, InterpretError> {
+        let it = match self {
+            Value::Number(it) if it.fract() == 0.0 => *it,
+            Value::Int(it) => *it as f64,
+            _ => Err(InterpretError::RuntimeErrorWithReason(
+                "Index must be a whole number",
+            ))?,
+        };
+        let at = match it < 0.0 {
+            true => len as f64 + it,
+            false => it,
+        };
+        match at >= 0.0 && at < len as f64 {
+            true => Ok(at as usize),
+            false => Err(InterpretError::RuntimeErrorWithReason("Index out of range")),
+        }
+    }
+
     pub fn as_bool(&self) -> bool {
         if let Value::Bool(it) = self {
             *it
@@ -140,6 +340,56 @@ impl Value {
         }
     }
 
+    // Equality as `==` in the language sees it. Numbers, bools, nil and strings are equal
+    // when their values are. Any other object, like a function, is only equal to itself
+    // even when an other one looks the same.
+    pub fn is_equal(&self, other: &Value) -> bool {
+        match (self, other) {
+            // Interned strings are the same object, no need to look at their content
+            (Value::Object(a), Value::Object(b)) if Rc::ptr_eq(a, b) => true,
+            (Value::Object(a), Value::Object(b)) if a.is_string() && b.is_string() => a == b,
+            (Value::Object(a), Value::Object(b)) => Rc::ptr_eq(a, b),
+            // 1 == 1.0, whatever the literal looked like
+            (Value::Int(_), Value::Number(_)) | (Value::Number(_), Value::Int(_)) => {
+                self.ordering(other) == Some(Ordering::Equal)
+            }
+            _ => self == other,
+        }
+    }
+
+    // Numbers order numerically and strings lexicographically. Anything else, mixed types
+    // and NaN included, has no order and is a runtime error instead of a silent wrong order.
+    pub fn compare(&self, other: &Value) -> Result<Ordering, InterpretError> {
+        self.ordering(other)
+            .ok_or(InterpretError::RuntimeErrorWithReason(
+                "Values can not be ordered",
+            ))
+    }
+
+    // Not a PartialOrd, that would have to agree with the derived PartialEq where 1 != 1.0
+    pub fn ordering(&self, other: &Value) -> Option<Ordering> {
+        match (self, other) {
+            (Value::Number(a), Value::Number(b)) => a.partial_cmp(b),
+            (Value::Int(a), Value::Int(b)) => a.partial_cmp(b),
+            (Value::Int(a), Value::Number(b)) => (*a as f64).partial_cmp(b),
+            (Value::Number(a), Value::Int(b)) => a.partial_cmp(&(*b as f64)),
+            (Value::Object(a), Value::Object(b)) if a.is_string() && b.is_string() => {
+                Some(a.as_string().cmp(b.as_string()))
+            }
+            _ => None,
+        }
+    }
+
+    // Sorts in place with `compare`, leaves the values untouched when they can not be ordered
+    pub fn sort(values: &mut [Value]) -> Result<(), InterpretError> {
+        for pair in values.windows(2) {
+            pair[0].compare(&pair[1])?;
+        }
+        // Every value is now known to be of one orderable type without NaN
+        values.sort_by(|a, b| a.ordering(b).unwrap());
+        Ok(())
+    }
+
     pub fn as_nil(&self) {
         if self.is_nil() {
         } else {
@@ -148,10 +398,12 @@ impl Value {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Clone, Copy)]
 #[repr(u8)]
 pub enum OpCode {
     Constant,
+    // Takes a two byte index for chunks with more than 256 constants
+    ConstantLong,
 
     // literals
     Nil,
@@ -161,6 +413,7 @@ pub enum OpCode {
     // static strings
     // not in book, might be a bad idea
     String,
+    StringLong,
 
     // comparison
     Equal,
@@ -175,12 +428,22 @@ pub enum OpCode {
     Subtract,
     Multiply,
     Divide,
+    Modulo,
+    IntDivide, // floors the quotient
     Negate,
 
     // binding
     DefineGlobal,
     GetGlobal,
     SetGlobal,
+    // Take a two byte index for chunks with more than 256 strings
+    DefineGlobalLong,
+    GetGlobalLong,
+    SetGlobalLong,
+    // Like the ones above but the operand is a slot resolved at compile time
+    DefineGlobalSlot,
+    GetGlobalSlot,
+    SetGlobalSlot,
 
     SetLocal,
     GetLocal,
@@ -191,13 +454,41 @@ pub enum OpCode {
     Jump,
     Loop,
 
+    // functions
+    Function,     // operand is the constant holding the function
+    FunctionLong, // operand is a 2 byte index of the constant holding the function
+    Call,         // operand is the argument count
+    TailCall,     // a call whose result is returned, operand is the argument count
+
+    // collections
+    BuildArray, // operand is the element count, pops the elements and pushes the array
+    Index,      // pops the index and the value indexed, pushes the element
+    SetIndex,   // pops the array, index and value, pushes the value back
+
     // Statements
     Print,
-    Pop, // pops a value from the stack to throw it away
+    EPrint, // print to the error output
+    Pop,    // pops a value from the stack to throw it away
 
     Return, // needs to be last
 }
 
+impl OpCode {
+    // Number of bytes following the opcode that belong to the instruction
+    pub fn operand_len(&self) -> usize {
+        use OpCode::*;
+        match self {
+            Constant | String | DefineGlobal | GetGlobal | SetGlobal | SetLocal | GetLocal => 1,
+            DefineGlobalSlot | GetGlobalSlot | SetGlobalSlot => 1,
+            Function | Call | TailCall | BuildArray => 1,
+            ConstantLong | StringLong | DefineGlobalLong | GetGlobalLong | SetGlobalLong => 2,
+            FunctionLong => 2,
+            JumpIfFalse | JumpIfTrue | Jump | Loop => 2,
+            _ => 0,
+        }
+    }
+}
+
 impl TryFrom<Byte> for OpCode {
     type Error = ();
 
@@ -208,3 +499,178 @@ impl TryFrom<Byte> for OpCode {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn string(it: &str) -> Value {
+        Value::Object(Rc::new(Obj::String {
+            str: it.to_string(),
+        }))
+    }
+
+    #[test]
+    fn sort_numbers() {
+        let mut values = vec![Value::Number(3.0), Value::Number(-1.0), Value::Number(2.5)];
+        Value::sort(&mut values).unwrap();
+        assert_eq!(
+            values,
+            vec![Value::Number(-1.0), Value::Number(2.5), Value::Number(3.0)]
+        );
+    }
+
+    #[test]
+    fn sort_strings() {
+        let mut values = vec![string("pear"), string("apple"), string("Zoo")];
+        Value::sort(&mut values).unwrap();
+        assert_eq!(values, vec![string("Zoo"), string("apple"), string("pear")]);
+    }
+
+    #[test]
+    fn compare_ints_and_numbers() {
+        let (one, one_and_a_half) = (Value::Int(1), Value::Number(1.5));
+        assert_eq!(one.compare(&one_and_a_half).unwrap(), Ordering::Less);
+        assert_eq!(one.compare(&Value::Number(1.0)).unwrap(), Ordering::Equal);
+        // Equal by value, though not the same value
+        assert!(one.is_equal(&Value::Number(1.0)));
+        assert_ne!(one, Value::Number(1.0));
+    }
+
+    #[test]
+    fn sort_unorderable_values() {
+        for mut values in [
+            vec![Value::Number(1.0), string("a")],
+            vec![Value::Number(1.0), Value::Number(f64::NAN)],
+            vec![Value::Bool(true), Value::Bool(false)],
+            vec![Value::Nil, Value::Nil],
+        ] {
+            // NaN is not equal to itself, compare the rendering instead
+            let before = format!("{:?}", values);
+            assert!(matches!(
+                Value::sort(&mut values),
+                Err(InterpretError::RuntimeErrorWithReason(
+                    "Values can not be ordered"
+                ))
+            ));
+            assert_eq!(format!("{:?}", values), before);
+        }
+    }
+
+    // Every opcode in declaration order, keep in sync with `OpCode`
+    const ALL_OP_CODES: &[OpCode] = {
+        use OpCode::*;
+        &[
+            Constant,
+            ConstantLong,
+            Nil,
+            True,
+            False,
+            String,
+            StringLong,
+            Equal,
+            Greater,
+            Less,
+            Not,
+            Add,
+            Subtract,
+            Multiply,
+            Divide,
+            Modulo,
+            IntDivide,
+            Negate,
+            DefineGlobal,
+            GetGlobal,
+            SetGlobal,
+            DefineGlobalLong,
+            GetGlobalLong,
+            SetGlobalLong,
+            DefineGlobalSlot,
+            GetGlobalSlot,
+            SetGlobalSlot,
+            SetLocal,
+            GetLocal,
+            JumpIfFalse,
+            JumpIfTrue,
+            Jump,
+            Loop,
+            Function,
+            FunctionLong,
+            Call,
+            TailCall,
+            BuildArray,
+            Index,
+            SetIndex,
+            Print,
+            EPrint,
+            Pop,
+            Return,
+        ]
+    };
+
+    #[test]
+    fn all_op_codes_are_listed() {
+        // Return is the last opcode so all of them are listed if the count matches
+        assert_eq!(ALL_OP_CODES.len(), OpCode::Return as usize + 1);
+        for (i, code) in ALL_OP_CODES.iter().enumerate() {
+            assert_eq!(*code as usize, i, "{:?} is listed out of order", code);
+        }
+    }
+
+    #[test]
+    fn op_code_round_trip() {
+        for &code in ALL_OP_CODES {
+            assert_eq!(OpCode::try_from(code as Byte), Ok(code));
+        }
+    }
+
+    #[test]
+    fn op_code_decode_table() {
+        for byte in 0..=Byte::MAX {
+            match OpCode::try_from(byte) {
+                Ok(code) => {
+                    assert_eq!(code as Byte, byte);
+                    assert_eq!(ALL_OP_CODES[byte as usize], code);
+                }
+                Err(()) => assert!(byte as usize >= ALL_OP_CODES.len()),
+            }
+        }
+    }
+
+    #[test]
+    fn display_values() {
+        let string = Value::Object(Rc::new(Obj::String {
+            str: "hello world".to_string(),
+        }));
+
+        assert_eq!(format!("{}", Value::Number(7.0)), "7");
+        assert_eq!(format!("{}", Value::Number(2.5)), "2.5");
+        assert_eq!(format!("{}", Value::Bool(true)), "true");
+        assert_eq!(format!("{}", Value::Nil), "nil");
+        assert_eq!(format!("{}", string), "hello world");
+        assert_eq!(format!("{}", Returned::from(string)), "hello world");
+        assert_eq!(format!("{}", Returned::from(7.0)), "7");
+        assert_eq!(format!("{}", Returned::Nil), "nil");
+    }
+
+    #[test]
+    fn display_does_not_expose_internals() {
+        let string = Value::Object(Rc::new(Obj::String {
+            str: "it".to_string(),
+        }));
+
+        for output in [
+            format!("{}", string),
+            format!("{}", Returned::from(string.clone())),
+            format!(
+                "{}",
+                Obj::String {
+                    str: "it".to_string()
+                }
+            ),
+        ] {
+            assert!(!output.contains("Object("), "{} leaks internals", output);
+            assert!(!output.contains("String {"), "{} leaks internals", output);
+        }
+    }
+}
@@ -0,0 +1,386 @@
+use crate::opcode::{Obj, Value};
+use std::fmt::{Debug, Display, Formatter};
+use std::rc::Rc;
+
+// NaN-boxed representation of a `Value` packed in a single u64
+// - Pro:
+//   1. 8 bytes instead of 16 for the enum
+//   2. numbers are stored as is, no tag to check before doing math
+// - Cons:
+//   1. unsafe, we manage the object reference count ourselves
+//   2. relies on pointers fitting in 48 bits
+//   3. ints beyond 48 bits take an allocation
+//
+// A quiet NaN has all exponent bits and the highest mantissa bits set, the remaining
+// mantissa bits are free to use. Singletons (nil, true, false) are tagged in the lowest bits,
+// objects set the sign bit and store the pointer in the mantissa. Ints set the int bit and
+// keep the lowest 48 bits, larger ones don't fit and are boxed: they set both the sign and the
+// int bit and store a pointer to the int.
+
+const QNAN: u64 = 0x7ffc_0000_0000_0000;
+const SIGN_BIT: u64 = 0x8000_0000_0000_0000;
+
+const TAG_NIL: u64 = 1;
+const TAG_FALSE: u64 = 2;
+const TAG_TRUE: u64 = 3;
+
+const INT_BIT: u64 = 0x0002_0000_0000_0000;
+const INT_PAYLOAD: u64 = 0x0000_ffff_ffff_ffff;
+const INT_BITS: u32 = 48;
+
+const NIL: u64 = QNAN | TAG_NIL;
+const FALSE: u64 = QNAN | TAG_FALSE;
+const TRUE: u64 = QNAN | TAG_TRUE;
+
+pub struct NanBoxed(u64);
+
+impl NanBoxed {
+    pub fn number(it: f64) -> Self {
+        // NaNs can carry a payload that would look like one of our tags
+        if it.is_nan() {
+            Self(f64::NAN.to_bits())
+        } else {
+            Self(it.to_bits())
+        }
+    }
+
+    pub fn int(it: i64) -> Self {
+        let max = (1 << (INT_BITS - 1)) - 1;
+        match (-max - 1..=max).contains(&it) {
+            true => Self(QNAN | INT_BIT | (it as u64 & INT_PAYLOAD)),
+            false => Self(SIGN_BIT | QNAN | INT_BIT | Self::fitting(Rc::into_raw(Rc::new(it)))),
+        }
+    }
+
+    pub fn bool(it: bool) -> Self {
+        if it {
+            Self(TRUE)
+        } else {
+            Self(FALSE)
+        }
+    }
+
+    pub fn nil() -> Self {
+        Self(NIL)
+    }
+
+    pub fn object(it: Rc<Obj>) -> Self {
+        Self(SIGN_BIT | QNAN | Self::fitting(Rc::into_raw(it)))
+    }
+
+    // The pointer can't touch the bits we tag with
+    fn fitting<T>(pointer: *const T) -> u64 {
+        let it = pointer as u64;
+        debug_assert!(
+            it & (SIGN_BIT | QNAN | INT_BIT) == 0,
+            "pointer does not fit"
+        );
+        it
+    }
+
+    // Ints are numbers too, like for `Value`
+    pub fn is_number(&self) -> bool {
+        self.is_float() || self.is_int()
+    }
+
+    fn is_float(&self) -> bool {
+        self.0 & QNAN != QNAN
+    }
+
+    pub fn is_int(&self) -> bool {
+        self.is_small_int() || self.is_boxed_int()
+    }
+
+    fn is_small_int(&self) -> bool {
+        self.0 & (SIGN_BIT | QNAN | INT_BIT) == QNAN | INT_BIT
+    }
+
+    fn is_boxed_int(&self) -> bool {
+        self.0 & (SIGN_BIT | QNAN | INT_BIT) == SIGN_BIT | QNAN | INT_BIT
+    }
+
+    pub fn is_bool(&self) -> bool {
+        self.0 | 1 == TRUE
+    }
+
+    pub fn is_nil(&self) -> bool {
+        self.0 == NIL
+    }
+
+    fn is_object(&self) -> bool {
+        self.0 & (SIGN_BIT | QNAN | INT_BIT) == SIGN_BIT | QNAN
+    }
+
+    pub fn is_string(&self) -> bool {
+        self.as_object().is_some_and(|it| it.is_string())
+    }
+
+    // Same definition as `Value::is_truthy`
+    pub fn is_truthy(&self) -> bool {
+        if self.is_number() {
+            self.as_number() != 0.0
+        } else {
+            self.0 == TRUE
+        }
+    }
+
+    pub fn as_number(&self) -> f64 {
+        if self.is_float() {
+            f64::from_bits(self.0)
+        } else if self.is_int() {
+            self.as_int() as f64
+        } else {
+            panic!("Value is not a number")
+        }
+    }
+
+    pub fn as_int(&self) -> i64 {
+        if self.is_small_int() {
+            // Shifting back down extends the sign of the payload
+            ((self.0 << (64 - INT_BITS)) as i64) >> (64 - INT_BITS)
+        } else if self.is_boxed_int() {
+            // Valid for as long as we hold on to our strong count, like objects
+            unsafe { *self.pointer::<i64>() }
+        } else {
+            panic!("Value is not an int")
+        }
+    }
+
+    pub fn as_string(&self) -> &str {
+        match self.as_object() {
+            Some(it) => it.as_string(),
+            None => panic!("Value is not a string"),
+        }
+    }
+
+    pub fn as_bool(&self) -> bool {
+        if self.is_bool() {
+            self.0 == TRUE
+        } else {
+            panic!("Value is not a bool")
+        }
+    }
+
+    pub fn as_nil(&self) {
+        if !self.is_nil() {
+            panic!("Value is not a nil")
+        }
+    }
+
+    fn pointer<T>(&self) -> *const T {
+        (self.0 & !(SIGN_BIT | QNAN | INT_BIT)) as *const T
+    }
+
+    fn as_object(&self) -> Option<&Obj> {
+        if self.is_object() {
+            // The pointer stays valid as long as we hold on to our strong count
+            Some(unsafe { &*self.pointer::<Obj>() })
+        } else {
+            None
+        }
+    }
+}
+
+impl Clone for NanBoxed {
+    fn clone(&self) -> Self {
+        if self.is_object() {
+            unsafe { Rc::increment_strong_count(self.pointer::<Obj>()) }
+        } else if self.is_boxed_int() {
+            unsafe { Rc::increment_strong_count(self.pointer::<i64>()) }
+        }
+        Self(self.0)
+    }
+}
+
+impl Drop for NanBoxed {
+    fn drop(&mut self) {
+        if self.is_object() {
+            unsafe { Rc::decrement_strong_count(self.pointer::<Obj>()) }
+        } else if self.is_boxed_int() {
+            unsafe { Rc::decrement_strong_count(self.pointer::<i64>()) }
+        }
+    }
+}
+
+impl PartialEq for NanBoxed {
+    fn eq(&self, other: &Self) -> bool {
+        Value::from(self) == Value::from(other)
+    }
+}
+
+impl From<Value> for NanBoxed {
+    fn from(value: Value) -> Self {
+        match value {
+            Value::Number(it) => NanBoxed::number(it),
+            Value::Int(it) => NanBoxed::int(it),
+            Value::Bool(it) => NanBoxed::bool(it),
+            Value::Object(it) => NanBoxed::object(it),
+            Value::Nil => NanBoxed::nil(),
+        }
+    }
+}
+
+impl From<&NanBoxed> for Value {
+    fn from(value: &NanBoxed) -> Self {
+        if value.is_float() {
+            Value::Number(value.as_number())
+        } else if value.is_int() {
+            Value::Int(value.as_int())
+        } else if value.is_object() {
+            let it = value.clone();
+            let pointer = it.pointer::<Obj>();
+            // Hand our strong count over to the Rc
+            std::mem::forget(it);
+            Value::Object(unsafe { Rc::from_raw(pointer) })
+        } else if value.is_bool() {
+            Value::Bool(value.as_bool())
+        } else {
+            Value::Nil
+        }
+    }
+}
+
+impl Debug for NanBoxed {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", Value::from(self))
+    }
+}
+
+impl Display for NanBoxed {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", Value::from(self))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn string(it: &str) -> Value {
+        Value::Object(Rc::new(Obj::String {
+            str: it.to_string(),
+        }))
+    }
+
+    fn values() -> Vec<Value> {
+        vec![
+            Value::Number(0.0),
+            Value::Number(-0.0),
+            Value::Number(1.5),
+            Value::Number(-42.0),
+            Value::Number(f64::INFINITY),
+            Value::Number(f64::MAX),
+            Value::Int(0),
+            Value::Int(-42),
+            Value::Int((1 << 47) - 1),
+            Value::Int(-(1 << 47)),
+            Value::Int(1 << 47),
+            Value::Int(-(1 << 47) - 1),
+            Value::Int(i64::MAX),
+            Value::Int(i64::MIN),
+            Value::Bool(true),
+            Value::Bool(false),
+            Value::Nil,
+            string(""),
+            string("hello world"),
+        ]
+    }
+
+    #[test]
+    fn is_smaller_than_value() {
+        assert_eq!(std::mem::size_of::<NanBoxed>(), 8);
+        assert!(std::mem::size_of::<NanBoxed>() < std::mem::size_of::<Value>());
+    }
+
+    #[test]
+    fn behaves_like_value() {
+        for value in values() {
+            let boxed = NanBoxed::from(value.clone());
+
+            assert_eq!(boxed.is_number(), value.is_number(), "{:?}", value);
+            assert_eq!(boxed.is_int(), value.is_int(), "{:?}", value);
+            assert_eq!(boxed.is_bool(), value.is_bool(), "{:?}", value);
+            assert_eq!(boxed.is_nil(), value.is_nil(), "{:?}", value);
+            assert_eq!(boxed.is_string(), value.is_string(), "{:?}", value);
+            assert_eq!(boxed.is_truthy(), value.is_truthy(), "{:?}", value);
+
+            if value.is_number() {
+                assert_eq!(boxed.as_number(), value.as_number());
+            }
+            if value.is_int() {
+                assert_eq!(boxed.as_int(), value.as_int());
+            }
+            if value.is_bool() {
+                assert_eq!(boxed.as_bool(), value.as_bool());
+            }
+            if value.is_string() {
+                assert_eq!(boxed.as_string(), value.as_string());
+            }
+            if value.is_nil() {
+                boxed.as_nil();
+            }
+
+            assert_eq!(format!("{}", boxed), format!("{}", value));
+            assert_eq!(format!("{:?}", boxed), format!("{:?}", value));
+            assert_eq!(Value::from(&boxed), value);
+        }
+    }
+
+    #[test]
+    fn equality_like_value() {
+        for lhs in values() {
+            for rhs in values() {
+                let boxed_lhs = NanBoxed::from(lhs.clone());
+                let boxed_rhs = NanBoxed::from(rhs.clone());
+                assert_eq!(boxed_lhs == boxed_rhs, lhs == rhs, "{:?} == {:?}", lhs, rhs);
+            }
+        }
+    }
+
+    #[test]
+    fn nan_stays_a_number() {
+        let boxed = NanBoxed::number(f64::NAN);
+        assert!(boxed.is_number());
+        assert!(boxed.as_number().is_nan());
+
+        let payload = NanBoxed::number(f64::from_bits(TRUE));
+        assert!(payload.is_number());
+        assert!(!payload.is_bool());
+    }
+
+    #[test]
+    fn large_ints_stay_ints() {
+        for it in [1 << 47, -(1 << 47) - 1, i64::MAX, i64::MIN] {
+            let boxed = NanBoxed::int(it);
+            assert!(boxed.is_int() && boxed.is_number(), "{}", it);
+            assert!(!boxed.is_object() && !boxed.is_nil(), "{}", it);
+            assert_eq!(boxed.as_int(), it);
+            assert_eq!(Value::from(&boxed), Value::Int(it));
+
+            let cloned = boxed.clone();
+            drop(boxed);
+            assert_eq!(cloned.as_int(), it);
+        }
+    }
+
+    #[test]
+    fn manages_reference_count() {
+        let it = Rc::new(Obj::String {
+            str: "counted".to_string(),
+        });
+
+        let boxed = NanBoxed::object(Rc::clone(&it));
+        assert_eq!(Rc::strong_count(&it), 2);
+
+        let cloned = boxed.clone();
+        assert_eq!(Rc::strong_count(&it), 3);
+
+        let value = Value::from(&cloned);
+        assert_eq!(Rc::strong_count(&it), 4);
+
+        drop(value);
+        drop(cloned);
+        drop(boxed);
+        assert_eq!(Rc::strong_count(&it), 1);
+    }
+}
@@ -1,15 +1,59 @@
 use crate::chunk::Chunk;
-use crate::compiler::{Compiler, LocalVarResolution};
+use crate::compiler::{Compiler, Diagnostic, LocalStore, LocalVarResolution, StoreKind};
 use crate::opcode::OpCode::{False, Nil, Return, True};
-use crate::opcode::Value::Number;
-use crate::opcode::{OpCode, Value};
+use crate::opcode::Value::{Int, Number};
+use crate::opcode::{Byte, Obj, OpCode, Value};
 use crate::tokenizer::{Token, TokenKind, Tokenizer};
 use crate::vm::CompilationErrorReason::{
-    ExpectedBinaryOperator, ExpectedPrefix, ExpectedRightParen, NotEnoughTokens, ParseFloatError,
-    TooMayTokens,
+    BreakOutsideLoop, ContinueOutsideLoop, DeniedWarning, ExpectedBinaryOperator, ExpectedPrefix,
+    ExpectedRightParen, InvalidCharLiteral, InvalidCodePoint, InvalidEscapeSequence,
+    MalformedEscapeSequence, MissingInitializer, NotEnoughTokens, ParseFloatError,
+    StringAndNumberOperands, TooMayTokens, UnexpectedDeclaration,
 };
 use crate::vm::InterpretError;
 use crate::vm::InterpretError::{CompileError, RuntimeErrorWithReason};
+use std::rc::Rc;
+use std::str::Chars;
+
+// Warn when a table indexed by a byte grows past this, the hard limit is 256. Plain
+// constants and strings switch to a two byte index, functions and global slots still
+// need a byte.
+const TABLE_SIZE_WARNING: usize = 200;
+
+// Knobs to tweak how the source gets compiled
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ParserOptions {
+    // Turns every diagnostic into a compile error
+    pub deny_warnings: bool,
+    // Runs the compile time optimizations like dead store elimination
+    pub optimize: bool,
+    // A bare expression ending the script becomes its result, like a notebook cell
+    pub keep_last_expression: bool,
+    // Rejects `var a;`, every variable has to be initialized
+    pub require_init: bool,
+    // Debug aid, verifies the emitted code keeps the stack balanced
+    pub check_stack: bool,
+    // Resolves globals to slots at compile time so running doesn't look them up by name
+    pub global_slots: bool,
+    // `return f(x);` reuses the frame of the caller, deep tail recursion doesn't overflow
+    pub tail_calls: bool,
+}
+
+// Where the code of the latest `x = value` to a local was written
+#[derive(Debug, Clone, Copy)]
+struct LocalAssignment {
+    slot: usize,
+    value_start: usize,
+    set_at: usize,
+}
+
+// The loop being compiled, to jump out of it or to its next iteration
+#[derive(Debug)]
+struct LoopContext {
+    locals: usize, // locals declared before the loop, the ones after are popped on exit
+    breaks: Vec<usize>, // jumps to patch to the exit of the loop
+    next: usize,   // where the next iteration starts
+}
 
 #[derive(Debug)]
 pub struct Parser<'a> {
@@ -20,6 +64,11 @@ pub struct Parser<'a> {
     chunk: Chunk,
     current: Option<Token<'a>>,
     line: usize, // cache latest line
+    options: ParserOptions,
+    local_assignment: Option<LocalAssignment>,
+    last_call: Option<usize>, // where the latest `Call` was written
+    loops: Vec<LoopContext>,  // innermost last
+    errors: Vec<InterpretError>,
 }
 
 impl<'a> Parser<'a> {
@@ -30,36 +79,129 @@ impl<'a> Parser<'a> {
             chunk: Chunk::new(),
             current: None,
             line: 0,
+            options: ParserOptions::default(),
+            local_assignment: None,
+            last_call: None,
+            loops: Vec::new(),
+            errors: Vec::new(),
         }
     }
 
+    pub fn with_options(mut self, options: ParserOptions) -> Self {
+        self.options = options;
+        self
+    }
+
     pub fn parse(tokenizer: Tokenizer) -> Result<Chunk, InterpretError> {
-        let mut it = Parser::new(tokenizer);
-        it.advance(); // Loads the first token in current
-        while it.current.as_ref().is_some() {
-            it.parse_declaration()?;
+        Self::parse_with_options(tokenizer, ParserOptions::default())
+    }
+
+    pub fn parse_with_options(
+        tokenizer: Tokenizer,
+        options: ParserOptions,
+    ) -> Result<Chunk, InterpretError> {
+        let (chunk, _) = Self::parse_with_diagnostics(tokenizer, options)?;
+        Ok(chunk)
+    }
+
+    // Hands back every error found instead of a single one, for reporting them all at once
+    pub fn parse_all_errors(
+        tokenizer: Tokenizer,
+        options: ParserOptions,
+    ) -> Result<Chunk, Vec<InterpretError>> {
+        Self::parse_with_options(tokenizer, options).map_err(|it| match it {
+            InterpretError::CompileErrors(errors) => errors,
+            it => vec![it],
+        })
+    }
+
+    // Also returns the warnings found while compiling, unless they are denied
+    // in which case the first one becomes the error
+    pub fn parse_with_diagnostics(
+        tokenizer: Tokenizer,
+        options: ParserOptions,
+    ) -> Result<(Chunk, Vec<Diagnostic>), InterpretError> {
+        let mut it = Parser::new(tokenizer).with_options(options);
+        it.parse_declarations()?;
+        it.finish()
+    }
+
+    // Parses source handed in as separate top level statements together with the line they
+    // start on, so only a single statement needs to be kept in memory at a time
+    pub fn parse_statements<I>(
+        statements: I,
+        options: ParserOptions,
+    ) -> Result<Chunk, InterpretError>
+    where
+        I: IntoIterator<Item = Result<(usize, String), InterpretError>>,
+    {
+        let mut it = Parser::new(Tokenizer::new("")).with_options(options);
+        for statement in statements {
+            let (line, source) = statement?;
+            let mut parser =
+                Parser::new(Tokenizer::new(&source).with_line(line)).with_options(options);
+            // Continue compiling into what we got so far
+            std::mem::swap(&mut parser.compiler, &mut it.compiler);
+            std::mem::swap(&mut parser.chunk, &mut it.chunk);
+            let result = parser.parse_declarations();
+            std::mem::swap(&mut parser.compiler, &mut it.compiler);
+            std::mem::swap(&mut parser.chunk, &mut it.chunk);
+            it.line = parser.line;
+            result?;
+            // Every piece is a top level statement, no local outlives it
+            it.compiler.reset_locals();
         }
-        it.expect_done()?;
-        it.end()?;
-        Ok(it.chunk)
+        let (chunk, _) = it.finish()?;
+        Ok(chunk)
+    }
+
+    fn parse_declarations(&mut self) -> Result<(), InterpretError> {
+        self.advance(); // Loads the first token in current
+        while !self.is_at_end() {
+            self.parse_declaration()?;
+        }
+        self.expect_done()?;
+
+        match self.errors.is_empty() {
+            true => Ok(()),
+            false => Err(std::mem::take(&mut self.errors).into()),
+        }
+    }
+
+    fn finish(mut self) -> Result<(Chunk, Vec<Diagnostic>), InterpretError> {
+        self.end(0)?;
+
+        let mut diagnostics = self.compiler.take_diagnostics();
+        if self.options.deny_warnings && !diagnostics.is_empty() {
+            Err(CompileError(DeniedWarning(diagnostics.remove(0))))?
+        }
+        Ok((self.chunk, diagnostics))
     }
 
     fn current(&self) -> Result<&Token<'a>, InterpretError> {
-        self.current.as_ref().ok_or(CompileError(NotEnoughTokens))
+        match self.current.as_ref() {
+            // The tokenizer could not make sense of the source
+            Some(Token {
+                kind: TokenKind::Error(error),
+                ..
+            }) => Err(CompileError((*error).into())),
+            Some(it) => Ok(it),
+            None => Err(CompileError(NotEnoughTokens)),
+        }
     }
 
     fn expect_done(&self) -> Result<(), InterpretError> {
-        if self.current.is_none() {
+        if self.is_at_end() {
             Ok(())
         } else {
             Err(CompileError(TooMayTokens))
         }
     }
 
-    fn expect(&self, expected: TokenKind, error: &'static str) -> Result<(), InterpretError> {
-        match self.current()?.kind {
-            it if it == expected => Ok(()),
-            _ => Err(RuntimeErrorWithReason(error)),
+    fn is_at_end(&self) -> bool {
+        match self.current.as_ref() {
+            Some(it) => it.is_kind(TokenKind::Eof),
+            None => true,
         }
     }
 
@@ -86,23 +228,25 @@ impl<'a> Parser<'a> {
     }
 
     fn parse_expression(&mut self, precedence: i32) -> Result<(), InterpretError> {
+        let start = self.mark_code();
         // prefix / nud position
         match self.current()?.kind {
             TokenKind::Number => self.parse_number(),
             TokenKind::String => self.parse_string(),
+            TokenKind::Char => self.parse_char(),
             TokenKind::False | TokenKind::True | TokenKind::Nil => self.parse_literal(),
             TokenKind::LeftParen => self.parse_grouping(),
+            TokenKind::LeftBracket => self.parse_array(),
             TokenKind::Minus | TokenKind::Bang => self.parse_unary(),
             TokenKind::Identifier => self.parse_named_variable(precedence),
-            it => {
-                println!("token not handled: {:?}", it);
-                todo!()
-            }
+            TokenKind::Eof => Err(CompileError(NotEnoughTokens)),
+            // Leaves the token for the error recovery to skip
+            _ => Err(CompileError(ExpectedPrefix)),
         }?;
 
         while let Some(op) = self.current.as_ref() {
             if self.precedence(op.kind) > precedence {
-                self.parse_binary()?;
+                self.parse_binary(start, precedence)?;
             } else {
                 break;
             }
@@ -122,30 +266,40 @@ impl<'a> Parser<'a> {
             | TokenKind::LessEqual
             | TokenKind::GreaterEqual => 60,
             TokenKind::Minus | TokenKind::Plus => 70,
-            TokenKind::Star | TokenKind::Slash => 80,
-            TokenKind::Bang => 90, // missing -
+            TokenKind::Star | TokenKind::Slash | TokenKind::Percent | TokenKind::Div => 80,
+            TokenKind::Bang => 90, // prefix - too
             // UNARY,       // ! -
-            // CALL,        // . ()
+            TokenKind::LeftParen | TokenKind::LeftBracket => 100, // CALL . () []
             // PRIMARY
             _ => 0,
         }
     }
 
-    fn end(&mut self) -> Result<(), InterpretError> {
-        // We no longer automatically emit return
-        // self.emit_return(self.line)?;
+    // The code started with `start_depth` values on the stack
+    fn end(&mut self, start_depth: usize) -> Result<(), InterpretError> {
+        // Scripts don't need a trailing `return`, falling of the end of the program
+        // implicitly returns nil so the vm always has a value to pop
+        self.emit_op_codes(Nil, Return, self.line)?;
+        if self.options.check_stack {
+            self.chunk.check_stack_balance(start_depth)?;
+        }
         Ok(())
     }
 
+    // Literals without a fraction or exponent are ints, unless they are too large for one
     fn parse_number(&mut self) -> Result<(), InterpretError> {
-        let it = self
-            .current()?
-            .source
-            .parse::<f64>()
-            .map_err(|_| CompileError(ParseFloatError))?;
+        let source = self.current()?.source.replace('_', ""); // digit separators, f64 does not understand them
+        let it = match source.parse::<i64>() {
+            Ok(it) => Int(it),
+            Err(_) => Number(
+                source
+                    .parse::<f64>()
+                    .map_err(|_| CompileError(ParseFloatError))?,
+            ),
+        };
         let line = self.line;
         self.advance();
-        self.emit_constant(Number(it), line)?;
+        self.emit_constant(it, line)?;
         Ok(())
     }
 
@@ -156,14 +310,38 @@ impl<'a> Parser<'a> {
             .strip_prefix('"')
             .expect("source strings start with \"")
             .strip_suffix('"')
-            .expect("source strings start with \"")
-            .to_string();
+            .expect("source strings start with \"");
+        let it = unescape_string(it)?;
         let line = self.line;
         self.advance();
         self.emit_string(it, line)?;
         Ok(())
     }
 
+    // A char literal like 'A' is just the number of its code point
+    fn parse_char(&mut self) -> Result<(), InterpretError> {
+        let source = self
+            .current()?
+            .source
+            .strip_prefix('\'')
+            .expect("source chars start with '")
+            .strip_suffix('\'')
+            .expect("source chars end with '");
+
+        let mut chars = source.chars();
+        let it = match (chars.next(), chars.next(), chars.next()) {
+            (Some('\\'), Some(escaped), None) => {
+                unescape(escaped).ok_or(CompileError(InvalidCharLiteral))?
+            }
+            (Some(it), None, None) if it != '\\' => it,
+            _ => Err(CompileError(InvalidCharLiteral))?,
+        };
+
+        let line = self.line;
+        self.advance();
+        self.emit_constant(Int(it as u32 as i64), line)
+    }
+
     fn parse_named_variable(&mut self, precedence: i32) -> Result<(), InterpretError> {
         let name = self.parse_var_name()?;
         let line = self.line;
@@ -178,16 +356,59 @@ impl<'a> Parser<'a> {
         match self.current()?.kind {
             TokenKind::Equal if can_assign => {
                 self.advance();
+                let value_start = self.mark_code();
+                self.parse_expression(0)?;
+                match is_local_var {
+                    LocalVarResolution::FoundAt(at) => {
+                        self.local_assignment = Some(LocalAssignment {
+                            slot: at,
+                            value_start,
+                            set_at: self.mark_code(),
+                        });
+                        self.emit_set_local_var(at, line)?
+                    }
+                    LocalVarResolution::NotFound => self.emit_set_global_var(name, line)?,
+                }
+            }
+            // `x += 1` is `x = x + 1`
+            TokenKind::PlusEqual
+            | TokenKind::MinusEqual
+            | TokenKind::StarEqual
+            | TokenKind::SlashEqual
+                if can_assign =>
+            {
+                let op_code = match self.current()?.kind {
+                    TokenKind::PlusEqual => OpCode::Add,
+                    TokenKind::MinusEqual => OpCode::Subtract,
+                    TokenKind::StarEqual => OpCode::Multiply,
+                    _ => OpCode::Divide,
+                };
+                self.advance();
+                match is_local_var {
+                    LocalVarResolution::FoundAt(at) => {
+                        self.compiler.mark_local_used(at);
+                        self.emit_get_local_var(at, line)?
+                    }
+                    LocalVarResolution::NotFound => self.emit_get_global_var(name.clone(), line)?,
+                }
                 self.parse_expression(0)?;
+                self.emit_op_code(op_code, line)?;
                 match is_local_var {
                     LocalVarResolution::FoundAt(at) => self.emit_set_local_var(at, line)?,
                     LocalVarResolution::NotFound => self.emit_set_global_var(name, line)?,
                 }
             }
             // Not allowed to assign
-            TokenKind::Equal => Err(RuntimeErrorWithReason("Invalid assignment target"))?,
+            TokenKind::Equal
+            | TokenKind::PlusEqual
+            | TokenKind::MinusEqual
+            | TokenKind::StarEqual
+            | TokenKind::SlashEqual => Err(RuntimeErrorWithReason("Invalid assignment target"))?,
             _ => match is_local_var {
-                LocalVarResolution::FoundAt(at) => self.emit_get_local_var(at, line)?,
+                LocalVarResolution::FoundAt(at) => {
+                    self.compiler.mark_local_used(at);
+                    self.emit_get_local_var(at, line)?
+                }
                 LocalVarResolution::NotFound => self.emit_get_global_var(name, line)?,
             },
         }
@@ -198,6 +419,13 @@ impl<'a> Parser<'a> {
     fn parse_grouping(&mut self) -> Result<(), InterpretError> {
         self.advance(); // consume '('
         self.parse_expression(0)?;
+        // Comma operator `(a, b, c)`: evaluates all, keeps the last. Only inside a grouping so
+        // it does not clash with the commas separating call arguments.
+        while self.current()?.is_kind(TokenKind::Comma) {
+            self.advance();
+            self.emit_op_code(OpCode::Pop, self.line)?;
+            self.parse_expression(0)?;
+        }
         match self.current()?.kind {
             TokenKind::RightParen => self.advance(), // consume ')'
             _ => Err(CompileError(ExpectedRightParen))?,
@@ -212,7 +440,8 @@ impl<'a> Parser<'a> {
         match kind {
             TokenKind::Minus => {
                 self.advance();
-                self.parse_expression(self.precedence(kind))?;
+                // As a prefix `-` binds as tight as `!`, not like the binary minus
+                self.parse_expression(self.precedence(TokenKind::Bang))?;
                 self.emit_op_code(OpCode::Negate, line)?
             }
             TokenKind::Bang => {
@@ -246,30 +475,40 @@ impl<'a> Parser<'a> {
         Ok(())
     }
 
-    fn parse_binary(&mut self) -> Result<(), InterpretError> {
+    // The code of the left operand starts at `lhs_start`
+    // `precedence` is the one of the expression the operator is in, to tell if it can assign
+    fn parse_binary(&mut self, lhs_start: usize, precedence: i32) -> Result<(), InterpretError> {
         let kind = self.current()?.kind;
         let line = self.line;
 
         match kind {
             TokenKind::Plus => {
                 self.advance();
+                let rhs_start = self.mark_code();
                 self.parse_expression(self.precedence(kind))?;
-                self.emit_op_code(OpCode::Add, line)
-            }
-            TokenKind::Minus => {
-                self.advance();
-                self.parse_expression(self.precedence(kind))?;
-                self.emit_op_code(OpCode::Subtract, line)
-            }
-            TokenKind::Star => {
-                self.advance();
-                self.parse_expression(self.precedence(kind))?;
-                self.emit_op_code(OpCode::Multiply, line)
+                self.check_literal_operands(lhs_start, rhs_start)?;
+                match self.options.optimize {
+                    true => self.emit_add_folding_strings(lhs_start, rhs_start, line),
+                    false => self.emit_op_code(OpCode::Add, line),
+                }
             }
-            TokenKind::Slash => {
+            TokenKind::Minus
+            | TokenKind::Star
+            | TokenKind::Slash
+            | TokenKind::Percent
+            | TokenKind::Div => {
                 self.advance();
+                let rhs_start = self.mark_code();
                 self.parse_expression(self.precedence(kind))?;
-                self.emit_op_code(OpCode::Divide, line)
+                self.check_literal_operands(lhs_start, rhs_start)?;
+                let code = match kind {
+                    TokenKind::Minus => OpCode::Subtract,
+                    TokenKind::Star => OpCode::Multiply,
+                    TokenKind::Slash => OpCode::Divide,
+                    TokenKind::Percent => OpCode::Modulo,
+                    _ => OpCode::IntDivide,
+                };
+                self.emit_op_code(code, line)
             }
             TokenKind::EqualEqual => {
                 self.advance();
@@ -303,6 +542,8 @@ impl<'a> Parser<'a> {
             }
             TokenKind::And => self.parse_and_expression(),
             TokenKind::Or => self.parse_or_expression(),
+            TokenKind::LeftParen => self.parse_call(),
+            TokenKind::LeftBracket => self.parse_index(precedence),
             _ => Err(CompileError(ExpectedBinaryOperator))?,
         }?;
 
@@ -326,24 +567,111 @@ impl<'a> Parser<'a> {
         Ok(())
     }
 
+    // Tables only grow one entry at a time, so each warning fires once
+    fn warn_table_size(&mut self, table: &str, size: usize, line: usize) {
+        if size == TABLE_SIZE_WARNING + 1 {
+            let message = format!(
+                "More than {} {}, the limit is 256",
+                TABLE_SIZE_WARNING, table
+            );
+            self.compiler.warn(line, message);
+        }
+    }
+
+    fn emit_function(&mut self, function: Value, line: usize) -> Result<(), InterpretError> {
+        self.chunk.write_function(function, line);
+        self.warn_table_size("constants", self.chunk.constants_len(), line);
+        Ok(())
+    }
+
     fn emit_constant(&mut self, constant: Value, line: usize) -> Result<(), InterpretError> {
-        // @TODO error handling out of range
         self.chunk.write_constant(constant, line);
         Ok(())
     }
 
     fn emit_string(&mut self, str: std::string::String, line: usize) -> Result<(), InterpretError> {
-        // @TODO error handling out of range
         self.chunk.write_string(str, line);
         Ok(())
     }
 
+    // Arithmetic on a string and a number literal can only fail at runtime, so it is
+    // reported while compiling. Operands that aren't a single literal are checked at runtime.
+    fn check_literal_operands(
+        &self,
+        lhs_start: usize,
+        rhs_start: usize,
+    ) -> Result<(), InterpretError> {
+        let lhs = self.literal_value(lhs_start, rhs_start);
+        let rhs = self.literal_value(rhs_start, self.mark_code());
+        match (lhs, rhs) {
+            (Some(lhs), Some(rhs))
+                if lhs.is_string() && rhs.is_number() || lhs.is_number() && rhs.is_string() =>
+            {
+                Err(CompileError(StringAndNumberOperands))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // The value of the code in the range when it is just a number or string literal
+    fn literal_value(&self, from: usize, to: usize) -> Option<Value> {
+        let code = OpCode::try_from(self.chunk.read_byte(from)?).ok()?;
+        if from + 1 + code.operand_len() != to {
+            return None;
+        }
+        let str = |it: &str| {
+            Value::Object(Rc::new(Obj::String {
+                str: it.to_string(),
+            }))
+        };
+        match code {
+            OpCode::Constant => self.chunk.read_constant(from + 1),
+            OpCode::ConstantLong => self.chunk.read_constant_long(from + 1),
+            OpCode::String => self.chunk.read_string(from + 1).map(str),
+            OpCode::StringLong => self.chunk.read_string_long(from + 1).map(str),
+            _ => None,
+        }
+    }
+
+    // `"a" + "b"` becomes `"ab"` when both operands are a single string literal
+    fn emit_add_folding_strings(
+        &mut self,
+        lhs_start: usize,
+        rhs_start: usize,
+        line: usize,
+    ) -> Result<(), InterpretError> {
+        let end = self.mark_code();
+        let is_string = |at: usize| self.chunk.read_byte(at) == Some(OpCode::String as u8);
+        let is_foldable = rhs_start == lhs_start + 2
+            && end == rhs_start + 2
+            && is_string(lhs_start)
+            && is_string(rhs_start);
+        let folded = match is_foldable {
+            true => self
+                .chunk
+                .read_string(lhs_start + 1)
+                .zip(self.chunk.read_string(rhs_start + 1))
+                .map(|(lhs, rhs)| format!("{}{}", lhs, rhs)),
+            false => None,
+        };
+
+        match folded {
+            Some(it) => {
+                self.chunk.remove_code(lhs_start, end);
+                self.emit_string(it, line)
+            }
+            None => self.emit_op_code(OpCode::Add, line),
+        }
+    }
+
     fn emit_define_global_var(
         &mut self,
         str: std::string::String,
         line: usize,
     ) -> Result<(), InterpretError> {
-        // @TODO error handling out of range
+        if self.options.global_slots {
+            return self.emit_global_slot(OpCode::DefineGlobalSlot, str, line);
+        }
         self.chunk.write_define_global_var(str, line);
         Ok(())
     }
@@ -353,11 +681,29 @@ impl<'a> Parser<'a> {
         str: std::string::String,
         line: usize,
     ) -> Result<(), InterpretError> {
-        // @TODO error handling out of range
+        if self.options.global_slots {
+            return self.emit_global_slot(OpCode::SetGlobalSlot, str, line);
+        }
         self.chunk.write_set_global_var(str, line);
         Ok(())
     }
 
+    fn emit_global_slot(
+        &mut self,
+        op_code: OpCode,
+        str: std::string::String,
+        line: usize,
+    ) -> Result<(), InterpretError> {
+        self.chunk.write_global_slot(op_code, str, line)?;
+        self.warn_table_size("global slots", self.chunk.global_slots_len(), line);
+        Ok(())
+    }
+
+    fn emit_call(&mut self, arg_count: usize, line: usize) -> Result<(), InterpretError> {
+        self.last_call = Some(self.mark_code());
+        self.chunk.write_call(arg_count, line)
+    }
+
     fn emit_set_local_var(&mut self, at: usize, line: usize) -> Result<(), InterpretError> {
         self.chunk.write_set_local_var(at, line);
         Ok(())
@@ -368,7 +714,9 @@ impl<'a> Parser<'a> {
         str: std::string::String,
         line: usize,
     ) -> Result<(), InterpretError> {
-        // @TODO error handling out of range
+        if self.options.global_slots {
+            return self.emit_global_slot(OpCode::GetGlobalSlot, str, line);
+        }
         self.chunk.write_get_global_var(str, line);
         Ok(())
     }
@@ -393,23 +741,64 @@ impl<'a> Parser<'a> {
 
     // declarations: statements that bind a new name (variable) to a value
     // If nothing find, starts parsing statements
+    // On an error it skips to the next statement so the errors after it are reported as well
     fn parse_declaration(&mut self) -> Result<(), InterpretError> {
-        match self.current()?.kind {
-            TokenKind::Var => self.parse_var_declaration(),
-            _ => self.parse_statement(),
+        let (loops, scope_depth) = (self.loops.len(), self.compiler.scope_depth());
+        let result = self
+            .current()
+            .map(|it| it.kind)
+            .and_then(|kind| match kind {
+                TokenKind::Var => self.parse_var_declaration(),
+                TokenKind::Fun => self.parse_fun_declaration(),
+                _ => self.parse_statement(),
+            });
+        if let Err(error) = result {
+            self.errors.push(error);
+            // The loops and blocks the error jumped out of are left open
+            self.loops.truncate(loops);
+            self.compiler.unwind_to(scope_depth);
+            self.synchronize();
+        }
+        Ok(())
+    }
+
+    // Skips tokens up to the end of the statement or the start of the next one
+    fn synchronize(&mut self) {
+        loop {
+            match self.current.as_ref().map(|it| it.kind) {
+                None | Some(TokenKind::Eof) => return,
+                Some(TokenKind::Semicolon) => {
+                    self.advance();
+                    return;
+                }
+                Some(
+                    TokenKind::Var
+                    | TokenKind::Fun
+                    | TokenKind::If
+                    | TokenKind::While
+                    | TokenKind::For
+                    | TokenKind::Print
+                    | TokenKind::Return,
+                ) => return,
+                _ => self.advance(),
+            }
         }
-        // @TODO implement synchronize to recover from errors
     }
 
     // all other statements
     fn parse_statement(&mut self) -> Result<(), InterpretError> {
         match self.current()?.kind {
             TokenKind::Print => self.parse_print_statement(),
+            TokenKind::EPrint => self.parse_eprint_statement(),
             TokenKind::LeftBrace => self.parse_block_statement(),
             TokenKind::If => self.parse_if_statement(),
             TokenKind::While => self.parse_while_statement(),
             TokenKind::For => self.parse_for_loop_statement(),
             TokenKind::Return => self.parse_return_statement(),
+            TokenKind::Break => self.parse_break_statement(),
+            TokenKind::Continue => self.parse_continue_statement(),
+            // Declarations are only allowed in blocks, not as the body of `while (x) var a;`
+            TokenKind::Var | TokenKind::Fun => Err(CompileError(UnexpectedDeclaration)),
             _ => self.parse_expression_statement(),
         }
     }
@@ -421,22 +810,129 @@ impl<'a> Parser<'a> {
         self.emit_op_code(OpCode::Print, self.line)
     }
 
+    // Like print but for diagnostics, goes to the error output
+    fn parse_eprint_statement(&mut self) -> Result<(), InterpretError> {
+        self.advance();
+        self.parse_expression(0)?;
+        self.expect_advance(TokenKind::Semicolon, "Expected ';' after value")?;
+        self.emit_op_code(OpCode::EPrint, self.line)
+    }
+
     // Evaluates the expression and throws away the result
     fn parse_expression_statement(&mut self) -> Result<(), InterpretError> {
+        let start = self.mark_code();
         self.parse_expression(0)?;
+        let can_keep = self.options.keep_last_expression && !self.compiler.in_local_scope();
+        // The trailing `;` is optional for the final expression
+        if can_keep && self.is_at_end() {
+            return self.emit_op_code(OpCode::Return, self.line);
+        }
         self.expect_advance(TokenKind::Semicolon, "Expected ';' after value")?;
-        self.emit_op_code(OpCode::Pop, self.line)
+        if can_keep && self.is_at_end() {
+            // Nothing follows, the value becomes the result of the script
+            return self.emit_op_code(OpCode::Return, self.line);
+        }
+        match self.options.optimize {
+            true => self.emit_pop_eliminating_dead_store(start),
+            false => self.emit_op_code(OpCode::Pop, self.line),
+        }
+    }
+
+    // A statement `x = value;` directly following a store to local x makes that store dead:
+    // nothing in between could have read it. Drops the dead store's code instead of running it.
+    fn emit_pop_eliminating_dead_store(&mut self, start: usize) -> Result<(), InterpretError> {
+        let previous = self.compiler.take_store();
+        let assignment = match self.local_assignment.take() {
+            // The whole statement is a single assignment to a local
+            Some(it) if it.value_start == start && it.set_at + 2 == self.mark_code() => it,
+            _ => return self.emit_op_code(OpCode::Pop, self.line),
+        };
+
+        let mut start = start;
+        let mut value_end = assignment.set_at;
+        if let Some(store) = previous.filter(|it| {
+            it.slot == assignment.slot
+                && it.end == start
+                && self.is_straight_line_without_access(it.slot, start, value_end)
+        }) {
+            let removed = store.end - store.start;
+            self.chunk.remove_code(store.start, store.end);
+            start -= removed;
+            value_end -= removed;
+
+            if store.kind == StoreKind::Initializer {
+                // The value takes the place of the initializer on the stack, no need to set it
+                self.chunk.remove_code(value_end, value_end + 2);
+                self.track_pure_store(assignment.slot, StoreKind::Initializer, start, value_end);
+                return Ok(());
+            }
+        }
+
+        self.emit_op_code(OpCode::Pop, self.line)?;
+        self.track_pure_store(assignment.slot, StoreKind::Assignment, start, value_end);
+        Ok(())
+    }
+
+    // Only stores of a single literal can be dropped without losing side effects
+    fn track_pure_store(&mut self, slot: usize, kind: StoreKind, start: usize, value_end: usize) {
+        let is_pure = match self.chunk.read_byte(start).map(OpCode::try_from) {
+            Some(Ok(
+                code @ (OpCode::Constant
+                | OpCode::ConstantLong
+                | OpCode::String
+                | OpCode::StringLong
+                | OpCode::Nil
+                | OpCode::True
+                | OpCode::False),
+            )) => start + 1 + code.operand_len() == value_end,
+            _ => false,
+        };
+        let end = self.mark_code();
+        self.compiler.track_store(match is_pure {
+            true => Some(LocalStore {
+                slot,
+                kind,
+                start,
+                end,
+            }),
+            false => None,
+        });
+    }
+
+    fn is_straight_line_without_access(&self, slot: usize, start: usize, end: usize) -> bool {
+        let mut at = start;
+        while at < end {
+            let code = match self.chunk.read_byte(at).map(OpCode::try_from) {
+                Some(Ok(code)) => code,
+                _ => return false,
+            };
+            match code {
+                OpCode::GetLocal | OpCode::SetLocal
+                    if self.chunk.read_byte(at + 1) == Some(slot as u8) =>
+                {
+                    return false
+                }
+                OpCode::JumpIfFalse | OpCode::JumpIfTrue | OpCode::Jump | OpCode::Loop => {
+                    return false
+                }
+                _ => (),
+            }
+            at += 1 + code.operand_len();
+        }
+        true
     }
 
     fn parse_var_declaration(&mut self) -> Result<(), InterpretError> {
         self.advance();
         let name = self.parse_var_name()?;
+        let start = self.mark_code();
 
         match self.current()?.kind {
             TokenKind::Equal => {
                 self.advance();
                 self.parse_expression(0)
             }
+            _ if self.options.require_init => Err(CompileError(MissingInitializer)),
             // var a; becomes var a = nil;
             _ => self.emit_op_code(OpCode::Nil, self.line),
         }?;
@@ -447,11 +943,99 @@ impl<'a> Parser<'a> {
         )?;
 
         match self.compiler.in_local_scope() {
-            true => self.declare_local_var(name),
+            true => {
+                let slot = self.declare_local_var(name)?;
+                if self.options.optimize {
+                    let end = self.mark_code();
+                    self.track_pure_store(slot, StoreKind::Initializer, start, end);
+                }
+                Ok(())
+            }
             false => self.emit_define_global_var(name, self.line),
         }
     }
 
+    // `fun name(a, b) { ... }` binds the compiled function like a variable
+    fn parse_fun_declaration(&mut self) -> Result<(), InterpretError> {
+        self.advance();
+        let line = self.line;
+        let name = self.parse_var_name()?;
+        let function = self.parse_function(name.clone())?;
+        self.emit_function(function, line)?;
+
+        match self.compiler.in_local_scope() {
+            true => self.declare_local_var(name).map(|_| ()),
+            false => self.emit_define_global_var(name, line),
+        }
+    }
+
+    // Compiles the parameters and body into a chunk of their own
+    fn parse_function(&mut self, name: String) -> Result<Value, InterpretError> {
+        let compiler = std::mem::replace(&mut self.compiler, Compiler::new());
+        let chunk = std::mem::take(&mut self.chunk);
+        let local_assignment = self.local_assignment.take();
+        let last_call = self.last_call.take();
+        // A function body can't jump out of the loop it is declared in
+        let loops = std::mem::take(&mut self.loops);
+
+        let result = self.parse_function_body();
+
+        let mut function_compiler = std::mem::replace(&mut self.compiler, compiler);
+        let function_chunk = std::mem::replace(&mut self.chunk, chunk);
+        self.local_assignment = local_assignment;
+        self.last_call = last_call;
+        self.loops = loops;
+        // Problems in the body are problems of the program
+        for it in function_compiler.take_diagnostics() {
+            self.compiler.warn(it.line, it.message);
+        }
+
+        Ok(Value::Object(Rc::new(Obj::Function {
+            name,
+            arity: result?,
+            chunk: Rc::new(function_chunk),
+        })))
+    }
+
+    // Returns the arity
+    fn parse_function_body(&mut self) -> Result<usize, InterpretError> {
+        self.compiler.begin_scope()?;
+        // Slot zero holds the function being called
+        let slot = self.compiler.add_local_var(String::new(), self.line)?;
+        self.compiler.mark_local_used(slot);
+
+        self.expect_advance(TokenKind::LeftParen, "Expect '(' after function name")?;
+        let mut arity = 0;
+        if !self.current()?.is_kind(TokenKind::RightParen) {
+            loop {
+                arity += 1;
+                if arity > u8::MAX as usize {
+                    Err(RuntimeErrorWithReason(
+                        "Can't have more than 255 parameters",
+                    ))?
+                }
+                let name = self.parse_var_name()?;
+                self.declare_local_var(name)?;
+                if !self.current()?.is_kind(TokenKind::Comma) {
+                    break;
+                }
+                self.advance();
+            }
+        }
+        self.expect_advance(TokenKind::RightParen, "Expect ')' after parameters")?;
+
+        if !self.current()?.is_kind(TokenKind::LeftBrace) {
+            Err(RuntimeErrorWithReason("Expect '{' before function body"))?
+        }
+        self.parse_block_statement()?;
+        // No need to pop the parameters, returning discards them
+        self.compiler.end_scope()?;
+        // The callee and its arguments are on the stack when the body starts
+        self.end(arity + 1)?;
+
+        Ok(arity)
+    }
+
     fn parse_var_name(&mut self) -> Result<String, InterpretError> {
         let it = if self.current()?.kind == TokenKind::Identifier {
             Ok(self.current()?.source.to_string())
@@ -469,13 +1053,23 @@ impl<'a> Parser<'a> {
         self.advance();
         self.compiler.begin_scope()?;
 
+        // Anything following a return in the same block can never run
+        let mut has_returned = false;
+        let mut has_warned = false;
         while !self.current()?.is_kind(TokenKind::RightBrace)
             && !self.current()?.is_kind(TokenKind::Eof)
         {
+            if has_returned && !has_warned {
+                self.compiler
+                    .warn(self.line, "Unreachable code after return".to_string());
+                has_warned = true;
+            }
+            has_returned |= self.current()?.is_kind(TokenKind::Return);
             self.parse_declaration()?;
         }
 
         let mut local_vars_to_pop = self.compiler.end_scope()?;
+        self.chunk.close_locals(local_vars_to_pop);
         // Pop the local vars from the stack as they are out of scope
         // becomes more complicated once we work with real stack frames
         while local_vars_to_pop > 0 {
@@ -488,9 +1082,12 @@ impl<'a> Parser<'a> {
         Ok(())
     }
 
-    fn declare_local_var(&mut self, name: String) -> Result<(), InterpretError> {
-        self.compiler.add_local_var(name)?;
-        Ok(())
+    fn declare_local_var(&mut self, name: String) -> Result<usize, InterpretError> {
+        let slot = self.compiler.add_local_var(name, self.line)?;
+        // Keep the name in the chunk for debuggers
+        let name = self.compiler.local_name_at(slot).unwrap_or_default();
+        self.chunk.open_local(name.to_string(), slot);
+        Ok(slot)
     }
 
     fn parse_return_statement(&mut self) -> Result<(), InterpretError> {
@@ -505,9 +1102,71 @@ impl<'a> Parser<'a> {
             TokenKind::Semicolon,
             "Expected ';' after variable declaration",
         )?;
+        // Only a call whose result is returned as is, `return f(x) + 1;` still needs the frame.
+        // Nothing follows the call but the return, jumps over it land on the return too.
+        let is_tail_call = self.last_call.is_some_and(|it| it + 2 == self.mark_code());
+        if self.options.tail_calls && is_tail_call {
+            if let Some(at) = self.last_call {
+                self.chunk.patch_code(at, OpCode::TailCall);
+            }
+        }
         self.emit_op_code(Return, self.line)
     }
 
+    fn parse_break_statement(&mut self) -> Result<(), InterpretError> {
+        self.advance();
+        // Report it before the `;` so recovering from the error starts at the end of the statement
+        if self.loops.is_empty() {
+            Err(CompileError(BreakOutsideLoop))?
+        }
+        self.expect_advance(TokenKind::Semicolon, "Expect ';' after break")?;
+
+        let context = self.loops.last().ok_or(CompileError(BreakOutsideLoop))?;
+        // Leave the stack as it is when the loop ends normally
+        for _ in context.locals..self.compiler.local_count() {
+            self.emit_op_code(OpCode::Pop, self.line)?;
+        }
+        let jump = self.emit_jump(OpCode::Jump)?;
+        if let Some(context) = self.loops.last_mut() {
+            context.breaks.push(jump);
+        }
+        Ok(())
+    }
+
+    fn parse_continue_statement(&mut self) -> Result<(), InterpretError> {
+        self.advance();
+        if self.loops.is_empty() {
+            Err(CompileError(ContinueOutsideLoop))?
+        }
+        self.expect_advance(TokenKind::Semicolon, "Expect ';' after continue")?;
+
+        let context = self.loops.last().ok_or(CompileError(ContinueOutsideLoop))?;
+        let next = context.next;
+        // The next iteration starts with the locals of the body gone
+        for _ in context.locals..self.compiler.local_count() {
+            self.emit_op_code(OpCode::Pop, self.line)?;
+        }
+        self.emit_loop(next)
+    }
+
+    fn begin_loop(&mut self, next: usize) {
+        self.loops.push(LoopContext {
+            locals: self.compiler.local_count(),
+            breaks: Vec::new(),
+            next,
+        });
+    }
+
+    // Patches the breaks to jump to here, the exit of the loop
+    fn end_loop(&mut self) -> Result<(), InterpretError> {
+        if let Some(context) = self.loops.pop() {
+            for jump in context.breaks {
+                self.patch_jump(jump)?;
+            }
+        }
+        Ok(())
+    }
+
     fn parse_if_statement(&mut self) -> Result<(), InterpretError> {
         // if
         self.advance(); // consume if
@@ -555,16 +1214,78 @@ impl<'a> Parser<'a> {
 
         // do it
         self.emit_op_code(OpCode::Pop, self.line)?; // pop condition of stack
+        self.begin_loop(loop_start);
         self.parse_statement()?;
         self.emit_loop(loop_start)?;
 
         // exit
         self.patch_jump(jump_to_exit)?;
         self.emit_op_code(OpCode::Pop, self.line)?; // pop condition of stack
-        Ok(())
+        self.end_loop()
     }
 
     // @TODO consider not popping from stack for conditional jumps
+    // The callee is already on the stack, followed by the arguments
+    fn parse_call(&mut self) -> Result<(), InterpretError> {
+        let line = self.line;
+        self.advance();
+
+        let mut arg_count = 0;
+        if !self.current()?.is_kind(TokenKind::RightParen) {
+            loop {
+                self.parse_expression(0)?;
+                arg_count += 1;
+                if !self.current()?.is_kind(TokenKind::Comma) {
+                    break;
+                }
+                self.advance();
+            }
+        }
+        self.expect_advance(TokenKind::RightParen, "Expect ')' after arguments")?;
+
+        self.emit_call(arg_count, line)
+    }
+
+    // `value[index]`, or `value[index] = element` where a variable could be assigned too
+    fn parse_index(&mut self, precedence: i32) -> Result<(), InterpretError> {
+        let line = self.line;
+        self.advance();
+        self.parse_expression(0)?;
+        self.expect_advance(TokenKind::RightBracket, "Expect ']' after index")?;
+
+        let can_assign = precedence <= self.precedence(TokenKind::Equal);
+        match self.current()?.kind {
+            TokenKind::Equal if can_assign => {
+                self.advance();
+                self.parse_expression(0)?;
+                self.emit_op_code(OpCode::SetIndex, line)
+            }
+            TokenKind::Equal => Err(RuntimeErrorWithReason("Invalid assignment target")),
+            _ => self.emit_op_code(OpCode::Index, line),
+        }
+    }
+
+    // `[a, b, c]`, the elements end up on the stack for the array to take them
+    fn parse_array(&mut self) -> Result<(), InterpretError> {
+        let line = self.line;
+        self.advance();
+
+        let mut len = 0;
+        if !self.current()?.is_kind(TokenKind::RightBracket) {
+            loop {
+                self.parse_expression(0)?;
+                len += 1;
+                if !self.current()?.is_kind(TokenKind::Comma) {
+                    break;
+                }
+                self.advance();
+            }
+        }
+        self.expect_advance(TokenKind::RightBracket, "Expect ']' after elements")?;
+
+        self.chunk.write_build_array(len, line)
+    }
+
     fn parse_and_expression(&mut self) -> Result<(), InterpretError> {
         // lhs and rhs; continue | if lhs = false -> jump to continue, false value is still on stack
         // lhs and rhs; continue | if lhs = true  -> fallthrough to rhs, pop lhs from stack, evaluate
@@ -605,7 +1326,7 @@ impl<'a> Parser<'a> {
     }
 
     fn parse_for_loop_statement(&mut self) -> Result<(), InterpretError> {
-        // for (initializer; condition; modifier) { block; } exit
+        // for (initializer; condition; modifier) statement; exit
 
         self.compiler.begin_scope()?;
 
@@ -632,8 +1353,15 @@ impl<'a> Parser<'a> {
             TokenKind::Semicolon => (), // no conditional, just skip to modifier
             _ => {
                 self.parse_expression(0)?;
-                to_exit = Some(self.emit_jump(OpCode::JumpIfFalse)?); // jump out of loop if false
-                self.emit_op_code(OpCode::Pop, self.line)?; // pop condition from stack
+                let is_always_true = self.mark_code() == to_condition + 1
+                    && self.chunk.read_byte(to_condition) == Some(True as Byte);
+                if self.options.optimize && is_always_true {
+                    // Loops like `for(;;)`, the literal `true` does not need a check
+                    self.chunk.remove_code(to_condition, self.mark_code());
+                } else {
+                    to_exit = Some(self.emit_jump(OpCode::JumpIfFalse)?); // jump out of loop if false
+                    self.emit_op_code(OpCode::Pop, self.line)?; // pop condition from stack
+                }
             }
         }
         self.expect_advance(
@@ -659,8 +1387,8 @@ impl<'a> Parser<'a> {
 
         // block
         self.patch_jump(to_block)?;
-        self.expect(TokenKind::LeftBrace, "Expect '{' in for loop")?;
-        self.parse_statement()?;
+        self.begin_loop(to_modify);
+        self.parse_statement()?; // a block or a single statement
         self.emit_loop(to_modify)?;
 
         // exit
@@ -668,16 +1396,107 @@ impl<'a> Parser<'a> {
             self.patch_jump(offset)?;
             self.emit_op_code(OpCode::Pop, self.line)?;
         }
+        self.end_loop()?;
 
-        self.compiler.end_scope()?;
+        // The loop variable goes out of scope, breaks left it on the stack as well
+        let local_vars_to_pop = self.compiler.end_scope()?;
+        self.chunk.close_locals(local_vars_to_pop);
+        for _ in 0..local_vars_to_pop {
+            self.emit_op_code(OpCode::Pop, self.line)?;
+        }
 
         Ok(())
     }
 }
 
+// Decodes the escape sequences in the content of a string literal
+fn unescape_string(source: &str) -> Result<String, InterpretError> {
+    let mut it = String::with_capacity(source.len());
+    let mut chars = source.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                let escaped = chars.next().unwrap_or('\\');
+                let c = match escaped {
+                    'x' => unescape_hex(&mut chars)?,
+                    'u' => unescape_unicode(&mut chars)?,
+                    _ => unescape(escaped).ok_or(CompileError(InvalidEscapeSequence(escaped)))?,
+                };
+                it.push(c);
+            }
+            c => it.push(c),
+        }
+    }
+    Ok(it)
+}
+
+// \xHH, exactly two hex digits
+fn unescape_hex(chars: &mut Chars) -> Result<char, InterpretError> {
+    let digits: String = chars.take(2).collect();
+    if digits.len() != 2 || !digits.chars().all(|it| it.is_ascii_hexdigit()) {
+        return Err(CompileError(MalformedEscapeSequence('x')));
+    }
+    let code = u32::from_str_radix(&digits, 16).unwrap();
+    char::from_u32(code).ok_or(CompileError(InvalidCodePoint(code)))
+}
+
+// \u{H...}, one to six hex digits of a unicode scalar value
+fn unescape_unicode(chars: &mut Chars) -> Result<char, InterpretError> {
+    let malformed = CompileError(MalformedEscapeSequence('u'));
+    if chars.next() != Some('{') {
+        return Err(malformed);
+    }
+    let mut digits = String::new();
+    loop {
+        match chars.next() {
+            Some('}') => break,
+            Some(it) if it.is_ascii_hexdigit() && digits.len() < 6 => digits.push(it),
+            _ => return Err(malformed),
+        }
+    }
+    let code = u32::from_str_radix(&digits, 16).map_err(|_| malformed)?;
+    char::from_u32(code).ok_or(CompileError(InvalidCodePoint(code)))
+}
+
+// Maps the char following a \ to the char it stands for
+fn unescape(escaped: char) -> Option<char> {
+    match escaped {
+        'n' => Some('\n'),
+        't' => Some('\t'),
+        'r' => Some('\r'),
+        '0' => Some('\0'),
+        '\\' => Some('\\'),
+        '\'' => Some('\''),
+        '"' => Some('"'),
+        _ => None,
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::opcode::Returned;
+    use crate::vm::CompilationErrorReason::{
+        IdentifierTooLong, InvalidDigitSeparator, NumberTooLong, UnterminatedChar,
+        UnterminatedString,
+    };
+
+    // Disassembles without the offset column, jumps point at labels instead. Snapshots taken
+    // this way only change when the instructions do, not when an opcode changes size.
+    fn disassemble_without_offsets(chunk: &Chunk, name: &str) -> String {
+        chunk
+            .disassemble_labeled_into_string(name)
+            .lines()
+            .map(|line| match line.split_once(" | ") {
+                Some((columns, instruction)) => {
+                    let line = columns.split_whitespace().last().unwrap_or_default();
+                    format!("{:>8} | {}", line, instruction)
+                }
+                None => line.to_string(),
+            })
+            .map(|line| line + "\n")
+            .collect()
+    }
 
     #[test]
     fn handles_new() {
@@ -691,10 +1510,12 @@ mod tests {
         let output = it.unwrap().disassemble_into_string("parse 1");
         let expected = r#"
 == parse 1 ==
-       0        0 | Constant 10.0
-       2        0 | Constant 30.0
+       0        0 | Constant 10
+       2        0 | Constant 30
        4        0 | Add
        5        0 | Return
+       6        0 | Nil
+       7        0 | Return
 "#;
         assert_eq!(output, expected);
     }
@@ -706,12 +1527,14 @@ mod tests {
         let output = it.unwrap().disassemble_into_string("parse 2");
         let expected = r#"
 == parse 2 ==
-       0        0 | Constant 10.0
-       2        0 | Constant 30.0
-       4        0 | Constant 40.0
+       0        0 | Constant 10
+       2        0 | Constant 30
+       4        0 | Constant 40
        6        0 | Multiply
        7        0 | Add
        8        0 | Return
+       9        0 | Nil
+      10        0 | Return
 "#;
         assert_eq!(output, expected);
     }
@@ -725,12 +1548,14 @@ mod tests {
         let output = it.unwrap().disassemble_into_string("parse 3");
         let expected = r#"
 == parse 3 ==
-       0        0 | Constant 10.0
-       2        0 | Constant 30.0
+       0        0 | Constant 10
+       2        0 | Constant 30
        4        0 | Add
-       5        0 | Constant 40.0
+       5        0 | Constant 40
        7        0 | Multiply
        8        0 | Return
+       9        0 | Nil
+      10        0 | Return
 "#;
         assert_eq!(output, expected);
     }
@@ -744,29 +1569,58 @@ mod tests {
         let output = it.unwrap().disassemble_into_string("parse 4");
         let expected = r#"
 == parse 4 ==
-       0        0 | Constant 10.0
-       2        0 | Constant 30.0
+       0        0 | Constant 10
+       2        0 | Constant 30
        4        0 | Negate
        5        0 | Add
-       6        0 | Constant 40.0
+       6        0 | Constant 40
        8        0 | Multiply
        9        0 | Return
+      10        0 | Nil
+      11        0 | Return
 "#;
         assert_eq!(output, expected);
     }
 
     #[test]
-    fn parse_5() {
-        let it = Parser::parse(Tokenizer::new("return \"hello world\";"));
+    fn parse_array() {
+        let it = Parser::parse(Tokenizer::new("a[0] = [1, [], \"x\"];"));
 
-        let output = it.unwrap().disassemble_into_string("parse 5");
+        let output = it.unwrap().disassemble_into_string("parse array");
         let expected = r#"
-== parse 5 ==
-       0        0 | String "hello world"
-       2        0 | Return
+== parse array ==
+       0        0 | Global get "a"
+       2        0 | Constant 0
+       4        0 | Constant 1
+       6        0 | Build array (len 0)
+       8        0 | String "x"
+      10        0 | Build array (len 3)
+      12        0 | Set index
+      13        0 | Pop
+      14        0 | Nil
+      15        0 | Return
 "#;
         assert_eq!(output, expected);
-    }
+
+        for source in ["1 + a[0] = 2;", "[1, 2;", "[1 2];"] {
+            assert!(Parser::parse(Tokenizer::new(source)).is_err(), "{}", source);
+        }
+    }
+
+    #[test]
+    fn parse_5() {
+        let it = Parser::parse(Tokenizer::new("return \"hello world\";"));
+
+        let output = it.unwrap().disassemble_into_string("parse 5");
+        let expected = r#"
+== parse 5 ==
+       0        0 | String "hello world"
+       2        0 | Return
+       3        0 | Nil
+       4        0 | Return
+"#;
+        assert_eq!(output, expected);
+    }
 
     #[test]
     fn parse_print_statement() {
@@ -777,6 +1631,8 @@ mod tests {
 == parse print statement ==
        0        0 | String "hello world"
        2        0 | Print
+       3        0 | Nil
+       4        0 | Return
 "#;
         assert_eq!(output, expected);
     }
@@ -790,10 +1646,12 @@ mod tests {
             .disassemble_into_string("parse var declaration 1");
         let expected = r#"
 == parse var declaration 1 ==
-       0        0 | Constant 5.0
-       2        0 | Constant 3.0
+       0        0 | Constant 5
+       2        0 | Constant 3
        4        0 | Add
        5        0 | Global define "it"
+       7        0 | Nil
+       8        0 | Return
 "#;
         assert_eq!(output, expected);
     }
@@ -809,6 +1667,8 @@ mod tests {
 == parse var declaration 2 ==
        0        0 | Global get "hello"
        2        0 | Global define "it"
+       4        0 | Nil
+       5        0 | Return
 "#;
         assert_eq!(output, expected);
     }
@@ -824,13 +1684,15 @@ mod tests {
 == parse var declaration 3 ==
        0        0 | Nil
        1        0 | Global define "it"
-       3        0 | Constant 3.0
-       5        0 | Constant 5.0
+       3        0 | Constant 3
+       5        0 | Constant 5
        7        0 | Add
        8        0 | Global set "it"
       10        0 | Pop
       11        0 | Global get "it"
       13        0 | Print
+      14        0 | Nil
+      15        0 | Return
 "#;
         assert_eq!(output, expected);
     }
@@ -846,14 +1708,16 @@ mod tests {
             .disassemble_into_string("parse var declaration 4");
         let expected = r#"
 == parse var declaration 4 ==
-       0        0 | Constant 3.0
-       2        0 | Constant 5.0
+       0        0 | Constant 3
+       2        0 | Constant 5
        4        0 | Local var get index(1)
        6        0 | Return
        7        0 | Pop
        8        0 | Pop
-       9        0 | Constant 5.0
+       9        0 | Constant 5
       11        0 | Return
+      12        0 | Nil
+      13        0 | Return
 "#;
         assert_eq!(output, expected);
     }
@@ -870,16 +1734,18 @@ mod tests {
        0        0 | True
        1        0 | If (false) jump to 17
        4        0 | Pop
-       5        0 | Constant 3.0
-       7        0 | Constant 5.0
+       5        0 | Constant 3
+       7        0 | Constant 5
        9        0 | Local var get index(1)
       11        0 | Return
       12        0 | Pop
       13        0 | Pop
       14        0 | Jump to 18
       17        0 | Pop
-      18        0 | Constant 5.0
+      18        0 | Constant 5
       20        0 | Return
+      21        0 | Nil
+      22        0 | Return
 "#;
         assert_eq!(output, expected);
     }
@@ -898,18 +1764,20 @@ mod tests {
        0        0 | True
        1        0 | If (false) jump to 17
        4        0 | Pop
-       5        0 | Constant 3.0
-       7        0 | Constant 5.0
+       5        0 | Constant 3
+       7        0 | Constant 5
        9        0 | Local var get index(1)
       11        0 | Return
       12        0 | Pop
       13        0 | Pop
       14        0 | Jump to 21
       17        0 | Pop
-      18        0 | Constant 5.0
+      18        0 | Constant 5
       20        0 | Return
-      21        0 | Constant 10.0
+      21        0 | Constant 10
       23        0 | Return
+      24        0 | Nil
+      25        0 | Return
 "#;
         assert_eq!(output, expected);
     }
@@ -926,6 +1794,8 @@ mod tests {
        4        0 | Pop
        5        0 | True
        6        0 | Return
+       7        0 | Nil
+       8        0 | Return
 "#;
         assert_eq!(output, expected);
     }
@@ -942,6 +1812,8 @@ mod tests {
        4        0 | Pop
        5        0 | True
        6        0 | Return
+       7        0 | Nil
+       8        0 | Return
 "#;
         assert_eq!(output, expected);
     }
@@ -955,20 +1827,68 @@ mod tests {
         let output = it.unwrap().disassemble_into_string("parse while statement");
         let expected = r#"
 == parse while statement ==
-       0        0 | Constant 10.0
+       0        0 | Constant 10
        2        0 | Global define "z"
        4        0 | True
        5        0 | If (false) jump to 15
        8        0 | Pop
-       9        0 | Constant 3.0
+       9        0 | Constant 3
       11        0 | Pop
       12        0 | Loop back to 4
       15        0 | Pop
-      16        0 | Constant 5.0
+      16        0 | Constant 5
       18        0 | Return
+      19        0 | Nil
+      20        0 | Return
+"#;
+        assert_eq!(output, expected);
+    }
+    #[test]
+    fn parse_while_statement_labeled() {
+        let it = Parser::parse(Tokenizer::new(
+            "var y = 3; while (y > 0) { if (y == 2) break; y = y - 1; } return y;",
+        ));
+
+        let output = it
+            .unwrap()
+            .disassemble_labeled_into_string("parse while statement labeled");
+        let expected = r#"
+== parse while statement labeled ==
+       0        0 | Constant 3
+       2        0 | Global define "y"
+L0:
+       4        0 | Global get "y"
+       6        0 | Constant 0
+       8        0 | Greater
+       9        0 | If (false) jump L3
+      12        0 | Pop
+      13        0 | Global get "y"
+      15        0 | Constant 2
+      17        0 | Equal
+      18        0 | If (false) jump L1
+      21        0 | Pop
+      22        0 | Jump L4
+      25        0 | Jump L2
+L1:
+      28        0 | Pop
+L2:
+      29        0 | Global get "y"
+      31        0 | Constant 1
+      33        0 | Subtract
+      34        0 | Global set "y"
+      36        0 | Pop
+      37        0 | Loop L0
+L3:
+      40        0 | Pop
+L4:
+      41        0 | Global get "y"
+      43        0 | Return
+      44        0 | Nil
+      45        0 | Return
 "#;
         assert_eq!(output, expected);
     }
+
     #[test]
     fn parse_while_statement_2() {
         let it = Parser::parse(Tokenizer::new(
@@ -980,22 +1900,22 @@ mod tests {
             .disassemble_into_string("parse while statement 2");
         let expected = r#"
 == parse while statement 2 ==
-       0        0 | Constant 0.0
+       0        0 | Constant 0
        2        0 | Global define "x"
-       4        0 | Constant 3.0
+       4        0 | Constant 3
        6        0 | Global define "y"
        8        0 | Global get "y"
-      10        0 | Constant 0.0
+      10        0 | Constant 0
       12        0 | Greater
       13        0 | If (false) jump to 36
       16        0 | Pop
       17        0 | Global get "y"
-      19        0 | Constant 1.0
+      19        0 | Constant 1
       21        0 | Subtract
       22        0 | Global set "y"
       24        0 | Pop
       25        0 | Global get "x"
-      27        0 | Constant 1.0
+      27        0 | Constant 1
       29        0 | Add
       30        0 | Global set "x"
       32        0 | Pop
@@ -1003,6 +1923,8 @@ mod tests {
       36        0 | Pop
       37        0 | Global get "x"
       39        0 | Return
+      40        0 | Nil
+      41        0 | Return
 "#;
         assert_eq!(output, expected);
     }
@@ -1016,30 +1938,33 @@ mod tests {
         let output = it.unwrap().disassemble_into_string("parse for loop 1");
         let expected = r#"
 == parse for loop 1 ==
-       0        0 | Constant 0.0
+       0        0 | Constant 0
        2        0 | Global define "x"
-       4        0 | Constant 0.0
+       4        0 | Constant 0
        6        0 | Local var get index(0)
-       8        0 | Constant 10.0
+       8        0 | Constant 10
       10        0 | Less
       11        0 | If (false) jump to 40
       14        0 | Pop
       15        0 | Jump to 29
       18        0 | Local var get index(0)
-      20        0 | Constant 1.0
+      20        0 | Constant 1
       22        0 | Add
       23        0 | Local var set index(0)
       25        0 | Pop
       26        0 | Loop back to 6
       29        0 | Global get "x"
-      31        0 | Constant 1.0
+      31        0 | Constant 1
       33        0 | Add
       34        0 | Global set "x"
       36        0 | Pop
       37        0 | Loop back to 18
       40        0 | Pop
-      41        0 | Global get "x"
-      43        0 | Print
+      41        0 | Pop
+      42        0 | Global get "x"
+      44        0 | Print
+      45        0 | Nil
+      46        0 | Return
 "#;
         assert_eq!(output, expected);
     }
@@ -1053,7 +1978,7 @@ mod tests {
         let output = it.unwrap().disassemble_into_string("parse for loop 2");
         let expected = r#"
 == parse for loop 2 ==
-       0        0 | Constant 10.0
+       0        0 | Constant 10
        2        0 | Global define "x"
        4        0 | Jump to 10
        7        0 | Loop back to 4
@@ -1062,6 +1987,894 @@ mod tests {
       13        0 | Loop back to 7
       16        0 | Global get "x"
       18        0 | Return
+      19        0 | Nil
+      20        0 | Return
+"#;
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn parse_for_loop_always_true() {
+        let options = ParserOptions {
+            optimize: true,
+            ..Default::default()
+        };
+        let source = "for (var i=0; true; i=i+1){ if (i>=3) break; }";
+        let it = Parser::parse_with_options(Tokenizer::new(source), options);
+
+        // Only the `if` checks a condition, the loop goes straight to the modifier
+        let output = it.unwrap().disassemble_into_string("always true");
+        let expected = r#"
+== always true ==
+       0        0 | Constant 0
+       2        0 | Jump to 16
+       5        0 | Local var get index(0)
+       7        0 | Constant 1
+       9        0 | Add
+      10        0 | Local var set index(0)
+      12        0 | Pop
+      13        0 | Loop back to 2
+      16        0 | Local var get index(0)
+      18        0 | Constant 3
+      20        0 | Less
+      21        0 | Not
+      22        0 | If (false) jump to 32
+      25        0 | Pop
+      26        0 | Jump to 36
+      29        0 | Jump to 33
+      32        0 | Pop
+      33        0 | Loop back to 5
+      36        0 | Pop
+      37        0 | Nil
+      38        0 | Return
+"#;
+        assert_eq!(output, expected);
+
+        let source = "var n = 0; for (var i = 0; true; i = i + 1) { if (i >= 3) break; n = n + 1; } return n;";
+        let chunk = Parser::parse_with_options(Tokenizer::new(source), options).unwrap();
+        assert_eq!(crate::vm::interpret(&chunk).unwrap(), Returned::Int(3));
+    }
+
+    #[test]
+    fn parse_reports_unused_local() {
+        let (_, diagnostics) = Parser::parse_with_diagnostics(
+            Tokenizer::new("{ var x = 3; var y = 5; print y; }"),
+            ParserOptions::default(),
+        )
+        .unwrap();
+
+        assert_eq!(
+            diagnostics,
+            vec![Diagnostic::new(
+                0,
+                "Local variable 'x' is never used".to_string()
+            )]
+        );
+    }
+
+    #[test]
+    fn parse_char_literal() {
+        let it = Parser::parse(Tokenizer::new("return 'A' + '\\n';"));
+
+        let output = it.unwrap().disassemble_into_string("parse char literal");
+        let expected = r#"
+== parse char literal ==
+       0        0 | Constant 65
+       2        0 | Constant 10
+       4        0 | Add
+       5        0 | Return
+       6        0 | Nil
+       7        0 | Return
+"#;
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn parse_invalid_char_literal() {
+        for source in ["return 'ab';", "return '';", "return '\\q';"] {
+            let it = Parser::parse(Tokenizer::new(source));
+            assert!(
+                matches!(it, Err(CompileError(InvalidCharLiteral))),
+                "{} should not parse",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn parse_too_long_tokens() {
+        let source = format!("var {} = 1;", "a".repeat(1000));
+        let it = Parser::parse(Tokenizer::new(&source));
+        assert!(matches!(it, Err(CompileError(IdentifierTooLong))));
+
+        let source = format!("return {};", "1".repeat(1000));
+        let it = Parser::parse(Tokenizer::new(&source));
+        assert!(matches!(it, Err(CompileError(NumberTooLong))));
+
+        let it = Parser::parse(Tokenizer::new("var short = 1;"));
+        assert!(it.is_ok());
+    }
+
+    #[test]
+    fn parse_reports_unreachable_code() {
+        let (_, diagnostics) = Parser::parse_with_diagnostics(
+            Tokenizer::new("{ return 1;\n print 2;\n print 3; }"),
+            ParserOptions::default(),
+        )
+        .unwrap();
+
+        assert_eq!(
+            diagnostics,
+            vec![Diagnostic::new(
+                1,
+                "Unreachable code after return".to_string()
+            )]
+        );
+
+        let (_, diagnostics) = Parser::parse_with_diagnostics(
+            Tokenizer::new("{ print 1; if (true) return 1; print 2; return 3; }"),
+            ParserOptions::default(),
+        )
+        .unwrap();
+
+        assert_eq!(diagnostics, vec![]);
+    }
+
+    #[test]
+    fn parse_deny_warnings() {
+        let source = "{ var x = 3; } return 1;";
+
+        assert!(Parser::parse(Tokenizer::new(source)).is_ok());
+
+        let it = Parser::parse_with_options(
+            Tokenizer::new(source),
+            ParserOptions {
+                deny_warnings: true,
+                ..Default::default()
+            },
+        );
+        assert!(matches!(it, Err(CompileError(DeniedWarning(_)))));
+
+        let it = Parser::parse_with_options(
+            Tokenizer::new("{ var x = 3; print x; } return 1;"),
+            ParserOptions {
+                deny_warnings: true,
+                ..Default::default()
+            },
+        );
+        assert!(it.is_ok());
+    }
+
+    #[test]
+    fn parse_dead_store_elimination() {
+        let source = "{ var x = 1; x = 2; return x; }";
+        let options = ParserOptions {
+            optimize: true,
+            ..Default::default()
+        };
+
+        let it = Parser::parse_with_options(Tokenizer::new(source), options);
+        let output = it.unwrap().disassemble_into_string("dead store");
+        let expected = r#"
+== dead store ==
+       0        0 | Constant 2
+       2        0 | Local var get index(0)
+       4        0 | Return
+       5        0 | Pop
+       6        0 | Nil
+       7        0 | Return
+"#;
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn parse_dead_store_elimination_keeps_live_stores() {
+        let options = ParserOptions {
+            optimize: true,
+            ..Default::default()
+        };
+        for source in [
+            "{ var x = 1; x = x + 1; return x; }",
+            "{ var x = 1; print x; x = 2; return x; }",
+            "{ var x = 2 * 3; x = 1; return x; }",
+            "{ var x = 1; var y = 2; x = 3; return x + y; }",
+        ] {
+            let optimized = Parser::parse_with_options(Tokenizer::new(source), options).unwrap();
+            let plain = Parser::parse(Tokenizer::new(source)).unwrap();
+            assert_eq!(
+                optimized.disassemble_into_string(source),
+                plain.disassemble_into_string(source),
+                "{} should not be optimized",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn parse_dead_store_elimination_keeps_results() {
+        let options = ParserOptions {
+            optimize: true,
+            ..Default::default()
+        };
+        for (source, result) in [
+            ("{ var x = 1; x = 2; return x; }", 2),
+            ("{ var x = 1; x = 2; x = 3; return x; }", 3),
+            ("{ var y = 5; var x; x = 1; x = y * 2; return x; }", 10),
+            ("{ var x = 1; { x = 2; } x = 4; return x + 1; }", 5),
+        ] {
+            let chunk = Parser::parse_with_options(Tokenizer::new(source), options).unwrap();
+            let it = crate::vm::interpret(&chunk).unwrap();
+            assert_eq!(it, Returned::Int(result), "{}", source);
+        }
+    }
+
+    #[test]
+    fn parse_index() {
+        let it = Parser::parse(Tokenizer::new("return s[-1] + \"x\";"));
+
+        let output = it.unwrap().disassemble_into_string("parse index");
+        let expected = r#"
+== parse index ==
+       0        0 | Global get "s"
+       2        0 | Constant 1
+       4        0 | Negate
+       5        0 | Index
+       6        0 | String "x"
+       8        0 | Add
+       9        0 | Return
+      10        0 | Nil
+      11        0 | Return
+"#;
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn parse_while_statement_without_offsets() {
+        let it = Parser::parse(Tokenizer::new(
+            "var y = 3;\nwhile (y > 0) {\n  if (y == 2) break;\n  y = y - 1;\n}\nreturn y;",
+        ));
+
+        let output = disassemble_without_offsets(&it.unwrap(), "while without offsets");
+        let expected = r#"
+== while without offsets ==
+       0 | Constant 3
+       1 | Global define "y"
+L0:
+       1 | Global get "y"
+       1 | Constant 0
+       1 | Greater
+       1 | If (false) jump L3
+       1 | Pop
+       2 | Global get "y"
+       2 | Constant 2
+       2 | Equal
+       2 | If (false) jump L1
+       2 | Pop
+       3 | Jump L4
+       3 | Jump L2
+L1:
+       3 | Pop
+L2:
+       3 | Global get "y"
+       3 | Constant 1
+       3 | Subtract
+       3 | Global set "y"
+       4 | Pop
+       5 | Loop L0
+L3:
+       5 | Pop
+L4:
+       5 | Global get "y"
+       5 | Return
+       5 | Nil
+       5 | Return
+"#;
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn parse_fun_declaration_without_offsets() {
+        let it = Parser::parse(Tokenizer::new(
+            "fun add(a, b) {\n  return a + b;\n}\nprint add(1, 2);",
+        ));
+
+        let output = disassemble_without_offsets(&it.unwrap(), "fun without offsets");
+        let expected = r#"
+== fun without offsets ==
+       0 | Function <fn add>
+       0 | Global define "add"
+       3 | Global get "add"
+       3 | Constant 1
+       3 | Constant 2
+       3 | Call (argc 2)
+       3 | Print
+       3 | Nil
+       3 | Return
+
+== <fn add> ==
+       1 | Local var get index(1)
+       1 | Local var get index(2)
+       1 | Add
+       2 | Return
+       3 | Nil
+       3 | Return
+"#;
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn parse_call() {
+        let it = Parser::parse(Tokenizer::new("f(1, 2); g();"));
+
+        let output = it.unwrap().disassemble_into_string("parse call");
+        let expected = r#"
+== parse call ==
+       0        0 | Global get "f"
+       2        0 | Constant 1
+       4        0 | Constant 2
+       6        0 | Call (argc 2)
+       8        0 | Pop
+       9        0 | Global get "g"
+      11        0 | Call (argc 0)
+      13        0 | Pop
+      14        0 | Nil
+      15        0 | Return
+"#;
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn parse_or_binds_looser_than_and() {
+        // a or (b and c): both jumps skip to the end of the expression
+        let it = Parser::parse(Tokenizer::new("return false or true and false;"));
+
+        let output = it.unwrap().disassemble_into_string("parse or and");
+        let expected = r#"
+== parse or and ==
+       0        0 | False
+       1        0 | If (true) jump to 11
+       4        0 | Pop
+       5        0 | True
+       6        0 | If (false) jump to 11
+       9        0 | Pop
+      10        0 | False
+      11        0 | Return
+      12        0 | Nil
+      13        0 | Return
+"#;
+        assert_eq!(output, expected);
+
+        // (a and b) or c: the `and` jump lands on the `or` test
+        let it = Parser::parse(Tokenizer::new("return false and true or false;"));
+
+        let output = it.unwrap().disassemble_into_string("parse and or");
+        let expected = r#"
+== parse and or ==
+       0        0 | False
+       1        0 | If (false) jump to 6
+       4        0 | Pop
+       5        0 | True
+       6        0 | If (true) jump to 11
+       9        0 | Pop
+      10        0 | False
+      11        0 | Return
+      12        0 | Nil
+      13        0 | Return
+"#;
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn parse_keep_last_expression() {
+        let options = ParserOptions {
+            keep_last_expression: true,
+            ..Default::default()
+        };
+        for (source, result) in [
+            ("1; 2; 3", Returned::Int(3)),
+            ("1; 2; 3;", Returned::Int(3)),
+            ("var a = 2; a * 5", Returned::Int(10)),
+            ("1; { 2; }", Returned::Nil),
+            ("3; print 1;", Returned::Nil),
+        ] {
+            let chunk = Parser::parse_with_options(Tokenizer::new(source), options).unwrap();
+            let it = crate::vm::interpret(&chunk).unwrap();
+            assert_eq!(it, result, "{}", source);
+        }
+
+        let chunk = Parser::parse(Tokenizer::new("1; 2; 3;")).unwrap();
+        assert_eq!(crate::vm::interpret(&chunk).unwrap(), Returned::Nil);
+        assert!(Parser::parse(Tokenizer::new("1; 2; 3")).is_err());
+    }
+
+    #[test]
+    fn parse_require_init() {
+        let options = ParserOptions {
+            require_init: true,
+            ..Default::default()
+        };
+
+        assert!(Parser::parse(Tokenizer::new("var a;")).is_ok());
+        assert!(Parser::parse(Tokenizer::new("{ var a; }")).is_ok());
+
+        let it = Parser::parse_with_options(Tokenizer::new("var a;"), options);
+        assert!(matches!(it, Err(CompileError(MissingInitializer))));
+        let it = Parser::parse_with_options(Tokenizer::new("{ var a; }"), options);
+        assert!(matches!(it, Err(CompileError(MissingInitializer))));
+        let it = Parser::parse_with_options(Tokenizer::new("var a = nil;"), options);
+        assert!(it.is_ok());
+    }
+
+    #[test]
+    fn parse_unterminated_string() {
+        let it = Parser::parse(Tokenizer::new("print \"hello;"));
+        assert!(matches!(it, Err(CompileError(UnterminatedString))));
+    }
+
+    #[test]
+    fn parse_unterminated_char() {
+        let it = Parser::parse(Tokenizer::new("print 'a;"));
+        assert!(matches!(it, Err(CompileError(UnterminatedChar))));
+    }
+
+    #[test]
+    fn parse_comma_operator() {
+        let it = Parser::parse(Tokenizer::new("return (1, 2, 3);"));
+
+        let output = it.unwrap().disassemble_into_string("parse comma");
+        let expected = r#"
+== parse comma ==
+       0        0 | Constant 1
+       2        0 | Pop
+       3        0 | Constant 2
+       5        0 | Pop
+       6        0 | Constant 3
+       8        0 | Return
+       9        0 | Nil
+      10        0 | Return
+"#;
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn parse_invalid_escape_sequence() {
+        let it = Parser::parse(Tokenizer::new("return \"a\\qb\";"));
+        assert!(matches!(it, Err(CompileError(InvalidEscapeSequence('q')))));
+    }
+
+    #[test]
+    fn parse_hex_and_unicode_escapes() {
+        let interpret = |source: &str| {
+            let chunk = Parser::parse(Tokenizer::new(source)).unwrap();
+            crate::vm::interpret(&chunk).unwrap()
+        };
+        assert_eq!(
+            interpret("return \"\\u{1F600}\";"),
+            Returned::Object(Obj::String {
+                str: "\u{1F600}".to_string()
+            })
+        );
+        assert_eq!(
+            interpret("return \"\\x41\";"),
+            Returned::Object(Obj::String {
+                str: "A".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn parse_malformed_hex_and_unicode_escapes() {
+        let parse = |source: &str| Parser::parse(Tokenizer::new(source));
+        assert!(matches!(
+            parse("return \"\\x1\";"),
+            Err(CompileError(MalformedEscapeSequence('x')))
+        ));
+        assert!(matches!(
+            parse("return \"\\xZZ\";"),
+            Err(CompileError(MalformedEscapeSequence('x')))
+        ));
+        assert!(matches!(
+            parse("return \"\\u{}\";"),
+            Err(CompileError(MalformedEscapeSequence('u')))
+        ));
+        assert!(matches!(
+            parse("return \"\\u{41\";"),
+            Err(CompileError(MalformedEscapeSequence('u')))
+        ));
+        assert!(matches!(
+            parse("return \"\\u{D800}\";"),
+            Err(CompileError(InvalidCodePoint(0xD800)))
+        ));
+    }
+
+    #[test]
+    fn iterate_constants_and_strings() {
+        let chunk =
+            Parser::parse(Tokenizer::new("var a = 1.5; print \"hi\"; return a + 2;")).unwrap();
+
+        let constants: Vec<_> = chunk.constants_iter().cloned().collect();
+        assert!(constants == vec![Value::Number(1.5), Value::Int(2)]);
+
+        let strings: Vec<_> = chunk.strings_iter().collect();
+        assert_eq!(strings, vec!["a", "hi", "a"]);
+    }
+
+    #[test]
+    fn parse_fun_declaration() {
+        let it = Parser::parse(Tokenizer::new(
+            "fun add(a, b) { return a + b; }\n{ fun nothing() {} }",
+        ));
+
+        let output = it.unwrap().disassemble_into_string("parse fun");
+        let expected = r#"
+== parse fun ==
+       0        0 | Function <fn add>
+       2        0 | Global define "add"
+       4        1 | Function <fn nothing>
+       6        1 | Pop
+       7        1 | Nil
+       8        1 | Return
+
+== <fn add> ==
+       0        0 | Local var get index(1)
+       2        0 | Local var get index(2)
+       4        0 | Add
+       5        0 | Return
+       6        1 | Nil
+       7        1 | Return
+
+== <fn nothing> ==
+       0        1 | Nil
+       1        1 | Return
+"#;
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn parse_fun_declaration_errors() {
+        let parse = |source: &str| Parser::parse(Tokenizer::new(source));
+        assert!(matches!(
+            parse("fun add(a b) {}"),
+            Err(RuntimeErrorWithReason("Expect ')' after parameters"))
+        ));
+        assert!(matches!(
+            parse("fun add() return 1;"),
+            Err(RuntimeErrorWithReason("Expect '{' before function body"))
+        ));
+        assert!(matches!(
+            parse("while (true) fun f() {}"),
+            Err(CompileError(UnexpectedDeclaration))
+        ));
+    }
+
+    #[test]
+    fn parse_keeps_stack_balanced() {
+        let options = ParserOptions {
+            check_stack: true,
+            ..Default::default()
+        };
+        let source = "var a = 1; fun add(x, y) { var z = x + y; return z; }
+            { var b = add(a, 2); if (b > 2 and a < 2) print b; else print a; }
+            while (a < 10) a += 1;
+            for (var i = 0; i < 10; i = i + 1) {
+                var c = i * 2;
+                if (c == 4) continue;
+                if (c > 8) break;
+                print c;
+            }
+            print (1, 2) or false;
+            return a;";
+        let it = Parser::parse_with_options(Tokenizer::new(source), options);
+        assert!(it.is_ok());
+    }
+
+    #[test]
+    fn parse_break_statement() {
+        let it = Parser::parse(Tokenizer::new("while (true) { var a = 1; if (a) break; }"));
+
+        let output = it.unwrap().disassemble_into_string("parse break");
+        let expected = r#"
+== parse break ==
+       0        0 | True
+       1        0 | If (false) jump to 25
+       4        0 | Pop
+       5        0 | Constant 1
+       7        0 | Local var get index(0)
+       9        0 | If (false) jump to 20
+      12        0 | Pop
+      13        0 | Pop
+      14        0 | Jump to 26
+      17        0 | Jump to 21
+      20        0 | Pop
+      21        0 | Pop
+      22        0 | Loop back to 0
+      25        0 | Pop
+      26        0 | Nil
+      27        0 | Return
+"#;
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn parse_break_outside_loop() {
+        let parse = |source: &str| Parser::parse(Tokenizer::new(source));
+        assert!(matches!(
+            parse("break;"),
+            Err(CompileError(BreakOutsideLoop))
+        ));
+        assert!(matches!(
+            parse("while (true) { fun f() { break; } }"),
+            Err(CompileError(BreakOutsideLoop))
+        ));
+    }
+
+    #[test]
+    fn parse_reports_every_error() {
+        let parse = |source: &str| Parser::parse(Tokenizer::new(source));
+        let it = parse("var a = ;\nprint a;\n{ print 1 +; }\nprint a");
+
+        match it {
+            Err(InterpretError::CompileErrors(errors)) => {
+                assert!(matches!(
+                    errors.as_slice(),
+                    [
+                        CompileError(ExpectedPrefix),
+                        CompileError(ExpectedPrefix),
+                        RuntimeErrorWithReason("Expected ';' after value"),
+                    ]
+                ));
+            }
+            _ => panic!("Expected all errors, got {:?}", it),
+        }
+
+        // An error in a loop body doesn't leave the loop or its scope open
+        match parse("for (var i = 0; i < 2; i = i + 1) print ;\nbreak;") {
+            Err(InterpretError::CompileErrors(errors)) => {
+                assert!(matches!(
+                    errors.as_slice(),
+                    [CompileError(ExpectedPrefix), CompileError(BreakOutsideLoop),]
+                ));
+            }
+            it => panic!("Expected all errors, got {:?}", it),
+        }
+
+        // Recovering always moves on, even from a stray `}`
+        assert!(matches!(
+            parse("} print 1;"),
+            Err(CompileError(ExpectedPrefix))
+        ));
+    }
+
+    #[test]
+    fn parse_all_errors() {
+        let parse =
+            |source: &str| Parser::parse_all_errors(Tokenizer::new(source), Default::default());
+        let errors = parse("var = 1;\nprint (1;\nbreak;\nreturn 2;").unwrap_err();
+
+        assert!(matches!(
+            errors.as_slice(),
+            [
+                RuntimeErrorWithReason("Expected variable name"),
+                CompileError(ExpectedRightParen),
+                CompileError(BreakOutsideLoop),
+            ]
+        ));
+        assert!(matches!(
+            parse("print;").unwrap_err().as_slice(),
+            [CompileError(ExpectedPrefix)]
+        ));
+        assert!(parse("return 2;").is_ok());
+    }
+
+    #[test]
+    fn break_and_continue_need_a_loop() {
+        let parse = |source: &str| Parser::parse(Tokenizer::new(source));
+
+        let it = parse("break;").unwrap_err();
+        assert!(matches!(it, CompileError(BreakOutsideLoop)));
+        assert_eq!(
+            it.to_string(),
+            "compilation error: Can't break outside a loop"
+        );
+        let it = parse("{ var a = 1; continue; }").unwrap_err();
+        assert!(matches!(it, CompileError(ContinueOutsideLoop)));
+        assert_eq!(
+            it.to_string(),
+            "compilation error: Can't continue outside a loop"
+        );
+
+        assert!(parse("while (false) { break; }").is_ok());
+        assert!(parse("for (var i = 0; i < 1; i = i + 1) { var a = 1; continue; }").is_ok());
+    }
+
+    #[test]
+    fn parse_continue_outside_loop() {
+        let parse = |source: &str| Parser::parse(Tokenizer::new(source));
+        assert!(matches!(
+            parse("if (true) continue;"),
+            Err(CompileError(ContinueOutsideLoop))
+        ));
+    }
+
+    #[test]
+    fn parse_rejects_arithmetic_on_string_and_number_literals() {
+        let parse = |source: &str| Parser::parse(Tokenizer::new(source));
+
+        for source in [
+            "return \"a\" - 1;",
+            "return 1 * \"a\";",
+            "return \"a\" + 1;",
+            "return 2 + (\"a\" / 2);",
+        ] {
+            assert!(
+                matches!(parse(source), Err(CompileError(StringAndNumberOperands))),
+                "{}",
+                source
+            );
+        }
+        // Only literals are known while compiling, the rest is checked at runtime
+        assert!(parse("var a = \"a\"; return a - 1;").is_ok());
+        assert!(parse("return \"a\" + \"b\";").is_ok());
+        assert!(parse("return (\"a\" + \"b\") - 1;").is_ok());
+        assert_eq!(
+            CompileError(StringAndNumberOperands).to_string(),
+            "compilation error: Can't do arithmetic on a string and a number"
+        );
+    }
+
+    #[test]
+    fn fold_string_concatenation() {
+        let options = ParserOptions {
+            optimize: true,
+            ..Default::default()
+        };
+        let parse = |source: &str| {
+            let it = Parser::parse_with_options(Tokenizer::new(source), options);
+            it.unwrap().disassemble_into_string("fold")
+        };
+
+        let expected = r#"
+== fold ==
+       0        0 | String "ab"
+       2        0 | Return
+       3        0 | Nil
+       4        0 | Return
+"#;
+        assert_eq!(parse("return \"a\" + \"b\";"), expected);
+
+        let expected = r#"
+== fold ==
+       0        0 | String "abc"
+       2        0 | Nil
+       3        0 | Add
+       4        0 | Return
+       5        0 | Nil
+       6        0 | Return
+"#;
+        assert_eq!(parse("return \"a\" + \"b\" + \"c\" + nil;"), expected);
+
+        // Only adjacent literals fold, the variable keeps the rest apart
+        let expected = r#"
+== fold ==
+       0        0 | Nil
+       1        0 | Global define "x"
+       3        0 | String "a"
+       5        0 | Global get "x"
+       7        0 | Add
+       8        0 | String "b"
+      10        0 | Add
+      11        0 | Return
+      12        0 | Nil
+      13        0 | Return
+"#;
+        assert_eq!(parse("var x; return \"a\" + x + \"b\";"), expected);
+    }
+
+    #[test]
+    fn parse_stops_at_eof() {
+        let it = Parser::parse(Tokenizer::new("{ print 1;"));
+        assert!(matches!(
+            it,
+            Err(RuntimeErrorWithReason("Expect '}' after block"))
+        ));
+
+        let it = Parser::parse(Tokenizer::new("return 1 +"));
+        assert!(matches!(it, Err(CompileError(NotEnoughTokens))));
+    }
+
+    #[test]
+    fn parse_declaration_as_body() {
+        for source in [
+            "while (false) var a = 1;",
+            "if (true) var a = 1;",
+            "for (;;) var a = 1;",
+        ] {
+            let it = Parser::parse(Tokenizer::new(source));
+            assert!(
+                matches!(it, Err(CompileError(UnexpectedDeclaration))),
+                "{} should not parse",
+                source
+            );
+        }
+        assert!(Parser::parse(Tokenizer::new("while (false) { var a = 1; }")).is_ok());
+    }
+
+    #[test]
+    fn parse_warns_about_table_sizes() {
+        let source = |count: usize| format!("return 0{};", " + 1".repeat(count - 1));
+
+        // Plain constants have room for more
+        let (_, diagnostics) =
+            Parser::parse_with_diagnostics(Tokenizer::new(&source(210)), ParserOptions::default())
+                .unwrap();
+        assert_eq!(diagnostics, vec![]);
+
+        // Functions are still referred to by a byte
+        let source = format!("var a = {}; fun f() {{}}", "1 + ".repeat(199) + "1");
+        let (_, diagnostics) =
+            Parser::parse_with_diagnostics(Tokenizer::new(&source), ParserOptions::default())
+                .unwrap();
+        assert_eq!(
+            diagnostics,
+            vec![Diagnostic::new(
+                0,
+                "More than 200 constants, the limit is 256".to_string()
+            )]
+        );
+
+        // Neither are strings
+        let source = "var a = \"a\"; ".repeat(201);
+        let (_, diagnostics) =
+            Parser::parse_with_diagnostics(Tokenizer::new(&source), ParserOptions::default())
+                .unwrap();
+        assert_eq!(diagnostics, vec![]);
+
+        let options = ParserOptions {
+            global_slots: true,
+            ..Default::default()
+        };
+        let source = (0..201)
+            .map(|i| format!("var a{} = 1; ", i))
+            .collect::<String>();
+        let (_, diagnostics) =
+            Parser::parse_with_diagnostics(Tokenizer::new(&source), options).unwrap();
+        assert_eq!(
+            diagnostics,
+            vec![Diagnostic::new(
+                0,
+                "More than 200 global slots, the limit is 256".to_string()
+            )]
+        );
+    }
+
+    #[test]
+    fn parse_digit_separators() {
+        let it = Parser::parse(Tokenizer::new("return 1_000_000;"));
+        assert!(it.is_ok());
+
+        for source in ["return 5_;", "return 5__0;"] {
+            let it = Parser::parse(Tokenizer::new(source));
+            assert!(
+                matches!(it, Err(CompileError(InvalidDigitSeparator))),
+                "{} should not parse",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn parse_modulo() {
+        let it = Parser::parse(Tokenizer::new("return 10 % 3;"));
+
+        let output = it.unwrap().disassemble_into_string("parse modulo");
+        let expected = r#"
+== parse modulo ==
+       0        0 | Constant 10
+       2        0 | Constant 3
+       4        0 | Modulo
+       5        0 | Return
+       6        0 | Nil
+       7        0 | Return
 "#;
         assert_eq!(output, expected);
     }
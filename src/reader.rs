@@ -1,18 +1,335 @@
-use crate::vm::InterpretError;
+use crate::chunk::Chunk;
+use crate::opcode::Returned;
+use crate::parser::{Parser, ParserOptions};
+use crate::tokenizer::Tokenizer;
+use crate::vm::{interpret as interpret_chunk, Environment, InterpretError, Vm};
 use std::fs;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter};
+use std::path::Path;
 
-pub fn run_file(path: &str) -> Result<(), InterpretError> {
+// Runs a source file, or a `.loxc` file compiled before without parsing it again
+pub fn run_file(path: &str) -> Result<Returned, InterpretError> {
     println!("Reading file from path {}", path);
 
-    let buffer = fs::read_to_string(path)?;
+    let chunk = match Path::new(path).extension().is_some_and(|it| it == "loxc") {
+        true => Chunk::deserialize(&mut BufReader::new(File::open(path)?))?,
+        false => Parser::parse(Tokenizer::new(&fs::read_to_string(path)?))?,
+    };
+    interpret_chunk(&chunk)
+}
+
+// Compiles the file to a `.loxc` file next to it, returns the path of the compiled file
+pub fn compile_file(path: &str) -> Result<String, InterpretError> {
+    let source = fs::read_to_string(path)?;
+    let chunk = Parser::parse(Tokenizer::new(&source))?;
+
+    let compiled = Path::new(path).with_extension("loxc");
+    let mut writer = BufWriter::new(File::create(&compiled)?);
+    chunk.serialize(&mut writer)?;
+    writer.into_inner().map_err(|it| it.into_error())?;
+    Ok(compiled.to_string_lossy().to_string())
+}
 
-    interpret(&buffer)
+// Runs source given on the command line
+pub fn run_source(source: &str) -> Result<Returned, InterpretError> {
+    let chunk = Parser::parse(Tokenizer::new(source))?;
+    interpret_chunk(&chunk)
 }
 
-fn interpret(source: &str) -> Result<(), InterpretError> {
-    for line in source.lines() {
-        println!("{}", line);
+// Prints the tokens of the file with the line they are on, to debug the tokenizer
+pub fn print_tokens(path: &str) -> Result<(), InterpretError> {
+    let source = fs::read_to_string(path)?;
+    for token in Tokenizer::new(&source) {
+        println!("{:4} {:?} {:?}", token.line, token.kind, token.source);
     }
+    Ok(())
+}
+
+// Prints the bytecode the file compiles to without running it
+pub fn print_bytecode(path: &str) -> Result<(), InterpretError> {
+    let source = fs::read_to_string(path)?;
+    let chunk = Parser::parse(Tokenizer::new(&source))?;
+    chunk.disassemble(path);
+    Ok(())
+}
+
+// Compiles the file one top level statement at a time instead of reading it all in memory
+pub fn run_file_streaming(path: &str) -> Result<(), InterpretError> {
+    println!("Streaming file from path {}", path);
+
+    let reader = BufReader::new(File::open(path)?);
+    let chunk = Parser::parse_statements(Statements::new(reader), ParserOptions::default())?;
+    let result = interpret_chunk(&chunk)?;
+    println!("{}", result);
 
     Ok(())
 }
+
+// Runs the files one after the other sharing their globals, so a file can use what the
+// files before it defined. Returns what the last one returned.
+pub fn run_files(paths: &[String]) -> Result<Returned, InterpretError> {
+    let mut environment = Environment::default();
+    let mut result = Returned::Nil;
+    for path in paths {
+        let in_file = |error| InterpretError::InFile(path.clone(), Box::new(error));
+        let source = fs::read_to_string(path).map_err(|it| in_file(it.into()))?;
+        let chunk = Parser::parse_all_errors(Tokenizer::new(&source), ParserOptions::default())
+            .map_err(|it| in_file(it.into()))?;
+
+        let mut vm = Vm::new(&chunk).with_environment(environment);
+        let it = vm.run();
+        environment = vm.into_environment();
+        result = it.map(Returned::from).map_err(in_file)?;
+    }
+    Ok(result)
+}
+
+/// Splits the source from a reader in top level statements together with the line they
+/// start on. Reads line by line so only the statement being collected is held in memory.
+pub struct Statements<R: BufRead> {
+    reader: R,
+    line: usize,          // line the next line to read is on
+    next: Option<String>, // line read ahead to check for an `else`
+}
+
+impl<R: BufRead> Statements<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader,
+            line: 0,
+            next: None,
+        }
+    }
+
+    fn read_line(&mut self) -> Result<Option<String>, InterpretError> {
+        if let Some(it) = self.next.take() {
+            return Ok(Some(it));
+        }
+        let mut line = String::new();
+        match self.reader.read_line(&mut line)? {
+            0 => Ok(None),
+            _ => Ok(Some(line)),
+        }
+    }
+
+    fn read_statement(&mut self) -> Result<Option<(usize, String)>, InterpretError> {
+        let start = self.line;
+        let mut statement = String::new();
+        let mut scanner = Scanner::default();
+
+        while let Some(line) = self.read_line()? {
+            self.line += 1;
+            scanner.scan(&line);
+            statement.push_str(&line);
+
+            if !scanner.is_statement_end() {
+                continue;
+            }
+            // An `if` statement might continue on the next line
+            match self.read_line()? {
+                Some(next) if starts_with_else(&next) => self.next = Some(next),
+                next => {
+                    self.next = next;
+                    break;
+                }
+            }
+        }
+
+        match statement.trim().is_empty() {
+            true => Ok(None),
+            false => Ok(Some((start, statement))),
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for Statements<R> {
+    type Item = Result<(usize, String), InterpretError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_statement().transpose()
+    }
+}
+
+fn starts_with_else(line: &str) -> bool {
+    let line = line.trim_start();
+    line.starts_with("else") && !line[4..].starts_with(|it: char| it.is_alphanumeric() || it == '_')
+}
+
+// Tracks just enough of the syntax to know where a statement ends
+#[derive(Default)]
+struct Scanner {
+    depth: i32,
+    quote: Option<char>,
+    in_comment: bool,   // inside a /* */ comment, these can span lines
+    last: Option<char>, // last char that is not whitespace or comment
+}
+
+impl Scanner {
+    fn scan(&mut self, line: &str) {
+        let mut chars = line.chars().peekable();
+        while let Some(it) = chars.next() {
+            if self.in_comment {
+                if it == '*' && chars.peek() == Some(&'/') {
+                    chars.next();
+                    self.in_comment = false;
+                }
+                continue;
+            }
+            if let Some(quote) = self.quote {
+                match it {
+                    '\\' => {
+                        chars.next();
+                    }
+                    it if it == quote => self.quote = None,
+                    _ => (),
+                }
+                continue;
+            }
+            match it {
+                '/' if chars.peek() == Some(&'/') => break,
+                '/' if chars.peek() == Some(&'*') => {
+                    chars.next();
+                    self.in_comment = true;
+                    continue;
+                }
+                '"' | '\'' => self.quote = Some(it),
+                '{' | '(' => self.depth += 1,
+                '}' | ')' => self.depth -= 1,
+                _ => (),
+            }
+            if !it.is_whitespace() {
+                self.last = Some(it);
+            }
+        }
+        // Strings don't span lines
+        self.quote = None;
+    }
+
+    fn is_statement_end(&self) -> bool {
+        self.depth <= 0 && matches!(self.last, Some(';') | Some('}'))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn splits_statements() {
+        let source = "var a = 1; // one\n/* two;\n */ var b = \"}\";\n{\n  a = a + 1;\n}\nif (a > 1)\n  print a;\nelse\n  print b;\nreturn a;\n";
+        let statements = Statements::new(source.as_bytes())
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+
+        assert_eq!(
+            statements,
+            vec![
+                (0, "var a = 1; // one\n".to_string()),
+                (1, "/* two;\n */ var b = \"}\";\n".to_string()),
+                (3, "{\n  a = a + 1;\n}\n".to_string()),
+                (6, "if (a > 1)\n  print a;\nelse\n  print b;\n".to_string()),
+                (10, "return a;\n".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn runs_file() {
+        let path = std::env::temp_dir().join(format!("rlox-run-{}.lox", std::process::id()));
+        let path = path.to_string_lossy().to_string();
+        fs::write(
+            &path,
+            "var a = 40;\nfun inc(x) { return x + 1; }\nreturn inc(a) + 1;\n",
+        )
+        .unwrap();
+
+        let result = run_file(&path);
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(result.unwrap(), Returned::Int(42));
+        assert!(matches!(run_file(&path), Err(InterpretError::Io(_))));
+    }
+
+    #[test]
+    fn runs_compiled_file() {
+        let path = std::env::temp_dir().join(format!("rlox-compile-{}.lox", std::process::id()));
+        let path = path.to_string_lossy().to_string();
+        fs::write(&path, "fun inc(x) { return x + 1; }\nreturn inc(41);\n").unwrap();
+
+        let compiled = compile_file(&path);
+        fs::remove_file(&path).unwrap();
+        let compiled = compiled.unwrap();
+        assert!(compiled.ends_with(".loxc"));
+
+        let result = run_file(&compiled);
+        fs::remove_file(&compiled).unwrap();
+        assert_eq!(result.unwrap(), Returned::Int(42));
+    }
+
+    #[test]
+    fn runs_source() {
+        assert_eq!(run_source("return 40 + 2;").unwrap(), Returned::Int(42));
+        assert!(run_source("return 40 +;").is_err());
+    }
+
+    #[test]
+    fn runs_files_sharing_globals() {
+        let dir = std::env::temp_dir();
+        let path = |name: &str| {
+            let it = dir.join(format!("rlox-{}-{}.lox", name, std::process::id()));
+            it.to_string_lossy().to_string()
+        };
+        let (helpers, main, broken) = (path("helpers"), path("main"), path("broken"));
+        fs::write(
+            &helpers,
+            "fun double(x) { return x * 2; }\nvar base = 20;\n",
+        )
+        .unwrap();
+        fs::write(&main, "return double(base) + 2;\n").unwrap();
+        fs::write(&broken, "return missing;\n").unwrap();
+
+        let result = run_files(&[helpers.clone(), main.clone()]);
+        let failed = run_files(&[helpers.clone(), broken.clone()]);
+        for it in [&helpers, &main, &broken] {
+            fs::remove_file(it).unwrap();
+        }
+
+        assert_eq!(result.unwrap(), Returned::Int(42));
+        match failed {
+            Err(InterpretError::InFile(path, error)) => {
+                assert_eq!(path, broken);
+                assert!(matches!(*error, InterpretError::UndefinedVariable(_)));
+            }
+            _ => panic!("Expected the broken file to fail"),
+        }
+    }
+
+    #[test]
+    fn parses_large_files_in_pieces() {
+        let path = std::env::temp_dir().join(format!("rlox-streaming-{}.lox", std::process::id()));
+        let mut file = File::create(&path).unwrap();
+        writeln!(file, "var total = 0;").unwrap();
+        // Every statement adds constants which are capped per chunk
+        for _ in 0..80 {
+            writeln!(file, "total = total + 1;").unwrap();
+        }
+        writeln!(file, "return total;").unwrap();
+        drop(file);
+
+        let file_size = fs::metadata(&path).unwrap().len() as usize;
+        let largest = Statements::new(BufReader::new(File::open(&path).unwrap()))
+            .map(|it| it.unwrap().1.len())
+            .max()
+            .unwrap();
+        assert!(largest * 50 < file_size);
+
+        let reader = BufReader::new(File::open(&path).unwrap());
+        let chunk = Parser::parse_statements(Statements::new(reader), ParserOptions::default());
+        fs::remove_file(&path).unwrap();
+
+        let result = interpret_chunk(&chunk.unwrap()).unwrap();
+        assert_eq!(result, Returned::Int(80));
+    }
+}
@@ -1,27 +1,180 @@
+use crate::chunk::Chunk;
+use crate::opcode::Returned;
 use crate::parser::Parser;
 use crate::tokenizer::Tokenizer;
-use crate::vm::{interpret, InterpretError};
+use crate::vm::CompilationErrorReason::{NotEnoughTokens, UnterminatedComment};
+use crate::vm::InterpretError::{CompileError, RuntimeErrorWithReason};
+use crate::vm::{Environment, InterpretError, Vm};
+use std::fs;
 use std::io::{stdin, stdout, Write};
 
 pub fn repl() -> Result<(), InterpretError> {
     let mut line = String::new();
+    let mut session = Session::new();
 
     println!("> Rlox repl:");
     loop {
-        print!("> ");
+        match session.is_pending() {
+            true => print!("... "),
+            false => print!("> "),
+        }
         stdout().flush()?;
-        stdin().read_line(&mut line)?;
+        if stdin().read_line(&mut line)? == 0 {
+            return Ok(());
+        }
         let input = line.clone();
         line.clear();
-        interpret_line(input)?;
+        // A mistake in one input should not end the session
+        match session.feed(&input) {
+            Ok(Some(result)) => println!("> PRINTED {}", result),
+            Ok(None) => (), // waiting for the rest of the statement
+            Err(error) => println!("> {}", error),
+        }
     }
 }
 
-// Dummy implementation that evaluates just the current line, not taking into account
-// what came before it.
-fn interpret_line(line: String) -> Result<(), InterpretError> {
-    let chunk = Parser::parse(Tokenizer::new(&line))?;
-    let result = interpret(&chunk)?;
-    print!("> PRINTED {:?}", result);
-    Ok(())
+/// Inputs of a REPL session, every input sees the globals defined by the ones before it
+pub struct Session {
+    environment: Environment,
+    pending: String, // the lines so far of input spanning several lines
+}
+
+impl Default for Session {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Session {
+    pub fn new() -> Self {
+        Self {
+            environment: Environment::default(),
+            pending: String::new(),
+        }
+    }
+
+    /// Input is waiting for more lines to complete it
+    pub fn is_pending(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    /// Like `dispatch` but keeps input that ends half way a statement, like `if (x) {`,
+    /// until the next lines complete it. Returns None while waiting for more.
+    /// An empty line gives up on the input and reports why it doesn't parse.
+    pub fn feed(&mut self, line: &str) -> Result<Option<Returned>, InterpretError> {
+        if !self.is_pending() && line.trim_start().starts_with(':') {
+            return self.dispatch(line).map(Some);
+        }
+        self.pending.push_str(line);
+        let source = std::mem::take(&mut self.pending);
+        let chunk = match Parser::parse(Tokenizer::new(&source)) {
+            Err(error) if is_incomplete(&error) && !line.trim().is_empty() => {
+                self.pending = source;
+                return Ok(None);
+            }
+            it => it?,
+        };
+        self.run(&chunk).map(Some)
+    }
+
+    /// Runs a line of source or a meta command like `:load path.lox`
+    pub fn dispatch(&mut self, line: &str) -> Result<Returned, InterpretError> {
+        match line.trim().split_once(char::is_whitespace) {
+            Some((":load", path)) => self.eval(&fs::read_to_string(path.trim())?),
+            _ if line.trim_start().starts_with(':') => {
+                Err(InterpretError::RuntimeErrorWithReason("Unknown command"))
+            }
+            _ => self.eval(line),
+        }
+    }
+
+    pub fn eval(&mut self, source: &str) -> Result<Returned, InterpretError> {
+        let chunk = Parser::parse(Tokenizer::new(source))?;
+        self.run(&chunk)
+    }
+
+    fn run(&mut self, chunk: &Chunk) -> Result<Returned, InterpretError> {
+        let environment = std::mem::take(&mut self.environment);
+        let mut vm = Vm::new(chunk).with_environment(environment);
+        let result = vm.run();
+        // The chunk goes away after this input, anything its globals don't refer to is dead
+        vm.collect_preserving_globals();
+        // Keep what got defined, even when the input failed half way
+        self.environment = vm.into_environment();
+        result.map(Returned::from)
+    }
+}
+
+// The source ran out before the statement was done, more lines could complete it
+fn is_incomplete(error: &InterpretError) -> bool {
+    matches!(
+        error,
+        CompileError(NotEnoughTokens)
+            | CompileError(UnterminatedComment)
+            | RuntimeErrorWithReason("Expect '}' after block")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn inputs_share_globals() {
+        let mut session = Session::new();
+        session.dispatch("var x = \"a\" + \"b\";\n").unwrap();
+        session.dispatch("fun twice() { return x + x; }\n").unwrap();
+
+        assert_eq!(
+            session.dispatch("return twice() + x;\n").unwrap(),
+            Returned::from("ababab")
+        );
+    }
+
+    #[test]
+    fn input_spanning_lines() {
+        let mut session = Session::new();
+        session.feed("var x = 1;\n").unwrap();
+
+        assert_eq!(session.feed("if (x > 0) {\n").unwrap(), None);
+        assert!(session.is_pending());
+        assert_eq!(
+            session.feed("  return x + 1; }\n").unwrap(),
+            Some(Returned::Int(2))
+        );
+        assert!(!session.is_pending());
+
+        // An empty line gives up on it
+        assert_eq!(session.feed("{\n").unwrap(), None);
+        assert!(session.feed("\n").is_err());
+        assert!(!session.is_pending());
+        assert!(matches!(session.feed("print;\n"), Err(CompileError(_))));
+    }
+
+    #[test]
+    fn load_file_into_session() {
+        let path = std::env::temp_dir().join(format!("rlox-load-{}.lox", std::process::id()));
+        fs::write(&path, "var answer = 40;\nanswer = answer + 2;\n").unwrap();
+
+        let mut session = Session::new();
+        let loaded = session.dispatch(&format!(":load {}\n", path.display()));
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded.unwrap(), Returned::Nil);
+        assert_eq!(
+            session.dispatch("return answer;\n").unwrap(),
+            Returned::Int(42)
+        );
+    }
+
+    #[test]
+    fn load_missing_file() {
+        let mut session = Session::new();
+        let it = session.dispatch(":load does/not/exist.lox");
+        assert!(matches!(it, Err(InterpretError::Io(_))));
+        assert!(matches!(
+            session.dispatch(":nope"),
+            Err(InterpretError::RuntimeErrorWithReason("Unknown command"))
+        ));
+    }
 }
@@ -1,6 +1,21 @@
-use crate::tokenizer::TokenKind::{Identifier, Number, String};
+use crate::tokenizer::TokenKind::{Char, Error, Identifier, Number, String};
 use std::cmp::PartialEq;
 
+// Guards against pathological input, a token longer than this becomes an error token
+const MAX_IDENTIFIER_LENGTH: usize = 255;
+const MAX_NUMBER_LENGTH: usize = 255;
+
+// Why the source could not be turned into a valid token
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub enum LexError {
+    IdentifierTooLong,
+    NumberTooLong,
+    UnterminatedComment,
+    UnterminatedString,
+    UnterminatedChar,
+    InvalidDigitSeparator,
+}
+
 #[derive(PartialEq, Debug, Copy, Clone)]
 pub enum TokenKind {
     // Single-character tokens.
@@ -8,6 +23,8 @@ pub enum TokenKind {
     RightParen,
     LeftBrace,
     RightBrace,
+    LeftBracket,
+    RightBracket,
     Comma,
     Dot,
     Minus,
@@ -15,6 +32,7 @@ pub enum TokenKind {
     Semicolon,
     Slash,
     Star,
+    Percent,
     // One or two character tokens.
     Bang,
     BangEqual,
@@ -24,14 +42,23 @@ pub enum TokenKind {
     GreaterEqual,
     Less,
     LessEqual,
+    PlusEqual,
+    MinusEqual,
+    StarEqual,
+    SlashEqual,
     // Literals.
     Identifier,
     String,
     Number,
+    Char,
     // Keywords.
     And,
+    Break,
     Class,
+    Continue,
+    Div, // integer division, `//` already starts a comment
     Else,
+    EPrint,
     False,
     For,
     Fun,
@@ -46,7 +73,7 @@ pub enum TokenKind {
     Var,
     While,
 
-    Error,
+    Error(LexError),
     Eof,
 }
 
@@ -92,6 +119,14 @@ impl<'a> Token<'a> {
     }
 }
 
+// The text of a comment without its `//` or `/* */` markers, together with the line it
+// starts on
+#[derive(PartialEq, Debug)]
+pub struct Comment<'a> {
+    pub text: &'a str,
+    pub line: usize,
+}
+
 #[derive(Debug)]
 pub struct Tokenizer<'a> {
     source: &'a str,
@@ -99,13 +134,31 @@ pub struct Tokenizer<'a> {
     checkpoint: usize, // checkpoint to indicate a start of a token
     current: usize,    // points to the next item to read
     line: usize,
+    max_identifier_length: usize,
+    max_number_length: usize,
+    is_done: bool,                      // the Eof token has been handed out
+    comments: Option<Vec<Comment<'a>>>, // only collected when asked for
 }
 
 impl<'a> Iterator for Tokenizer<'a> {
     type Item = Token<'a>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        self.token()
+        match self.token() {
+            Some(it) => Some(it),
+            // Signal the end of input once with an Eof token, then stop
+            None if !self.is_done => {
+                self.is_done = true;
+                let end = self.source.len();
+                Some(Token::new(
+                    TokenKind::Eof,
+                    &self.source[end..],
+                    end,
+                    self.line,
+                ))
+            }
+            None => None,
+        }
     }
 }
 
@@ -117,6 +170,44 @@ impl<'a> Tokenizer<'a> {
             checkpoint: 0,
             current: 0,
             line: 0,
+            max_identifier_length: MAX_IDENTIFIER_LENGTH,
+            max_number_length: MAX_NUMBER_LENGTH,
+            is_done: false,
+            comments: None,
+        }
+    }
+
+    pub fn with_max_identifier_length(mut self, length: usize) -> Self {
+        self.max_identifier_length = length;
+        self
+    }
+
+    pub fn with_max_number_length(mut self, length: usize) -> Self {
+        self.max_number_length = length;
+        self
+    }
+
+    // For source that is fed in pieces, continue counting lines where the previous piece stopped
+    pub fn with_line(mut self, line: usize) -> Self {
+        self.line = line;
+        self
+    }
+
+    /// Keeps the comments instead of throwing them away, for tools extracting docs
+    pub fn with_comments(mut self) -> Self {
+        self.comments = Some(Vec::new());
+        self
+    }
+
+    /// The comments seen so far, empty unless collected `with_comments`
+    pub fn comments(&self) -> &[Comment<'a>] {
+        self.comments.as_deref().unwrap_or_default()
+    }
+
+    fn record_comment(&mut self, from: usize, to: usize, line: usize) {
+        let text = &self.source[from..to];
+        if let Some(comments) = self.comments.as_mut() {
+            comments.push(Comment { text, line });
         }
     }
 
@@ -183,12 +274,37 @@ impl<'a> Tokenizer<'a> {
         }
     }
 
+    // Leaves the newline ending the comment for the whitespace to count the line
     fn take_comment(&mut self) {
-        while let Some(it) = self.take_byte() {
-            if it == b'\n' {
+        self.advance_bytes(2); // skip the opening //
+        let from = self.current;
+        while let Some(it) = self.peek_byte() {
+            if it.is_newline() {
                 break;
             }
+            self.advance_byte();
+        }
+        self.record_comment(from, self.current, self.line);
+    }
+
+    // Skips a `/* ... */` comment, returns false when the input ends before the comment does
+    fn take_block_comment(&mut self) -> bool {
+        self.advance_bytes(2); // skip the opening /*
+        let (from, line) = (self.current, self.line);
+        while let Some(it) = self.take_byte() {
+            match it {
+                b'*' if self.peek_byte() == Some(b'/') => {
+                    self.record_comment(from, self.current - 1, line);
+                    self.advance_byte();
+                    return true;
+                }
+                it if it.is_newline() => self.advance_line(),
+                _ => (),
+            }
         }
+        // take_byte steps past the end when there is nothing left
+        self.current = self.as_bytes.len();
+        false
     }
 
     fn peek_byte(&self) -> Option<u8> {
@@ -199,6 +315,27 @@ impl<'a> Tokenizer<'a> {
         }
     }
 
+    fn peek_byte_at(&self, offset: usize) -> Option<u8> {
+        self.as_bytes.get(self.current + offset).copied()
+    }
+
+    // Digits with `_` separators like 1_000, returns false when a `_` is not between two digits
+    fn take_digits(&mut self) -> bool {
+        let mut is_valid = true;
+        let mut previous = None;
+        while let Some(it) = self
+            .peek_byte()
+            .filter(|it| it.is_ascii_digit() || *it == b'_')
+        {
+            if it == b'_' && !previous.is_some_and(|it: u8| it.is_ascii_digit()) {
+                is_valid = false;
+            }
+            previous = Some(it);
+            self.advance_byte();
+        }
+        is_valid && previous != Some(b'_')
+    }
+
     fn peek_bytes(&self, amount: usize) -> Option<&str> {
         if self.current + amount > self.as_bytes.len() {
             None
@@ -254,25 +391,65 @@ impl<'a> Tokenizer<'a> {
         // We are not handling newlines in strings as we assume strings are just one line with
         // escaped newline chars in it.
         while let Some(it) = self.take_byte() {
-            if it == b'"' {
-                return Some(self.create_token(String));
+            match it {
+                b'\\' => self.advance_byte(), // an escaped " does not end the string
+                b'"' => return Some(self.create_token(String)),
+                _ => (),
             }
         }
-        // @TODO error unterminated string
-        None
+        // take_byte steps past the end when there is nothing left
+        self.current = self.as_bytes.len();
+        Some(self.create_token(Error(LexError::UnterminatedString)))
     }
 
-    fn make_number(&mut self) -> Option<Token<'a>> {
+    fn make_char(&mut self) -> Option<Token<'a>> {
         self.checkpoint();
-        // We are not handling newlines in strings as we assume strings are just one line with
-        // escaped newline chars in it.
-        while let Some(it) = self.peek_byte() {
-            if !it.is_ascii_digit() {
-                break;
+        // Skip the opening '
+        self.advance_byte();
+        // Whether the content is exactly one (escaped) char is up to the parser to decide
+        while let Some(it) = self.take_byte() {
+            match it {
+                b'\\' => self.advance_byte(), // an escaped ' does not end the literal
+                b'\'' => return Some(self.create_token(Char)),
+                _ => (),
             }
+        }
+        // take_byte steps past the end when there is nothing left
+        self.current = self.as_bytes.len();
+        Some(self.create_token(Error(LexError::UnterminatedChar)))
+    }
+
+    fn make_number(&mut self) -> Option<Token<'a>> {
+        self.checkpoint();
+        let mut is_valid = self.take_digits();
+        // A `.` only belongs to the number when a digit follows
+        if self.peek_byte() == Some(b'.')
+            && self.peek_byte_at(1).is_some_and(|it| it.is_ascii_digit())
+        {
             self.advance_byte();
+            is_valid &= self.take_digits();
         }
-        return Some(self.create_token(Number));
+        // Exponent like `e3` or `E-4`, needs at least one digit
+        if matches!(self.peek_byte(), Some(b'e' | b'E')) {
+            let digits_at = match self.peek_byte_at(1) {
+                Some(b'+' | b'-') => 2,
+                _ => 1,
+            };
+            if self
+                .peek_byte_at(digits_at)
+                .is_some_and(|it| it.is_ascii_digit())
+            {
+                self.advance_bytes(digits_at);
+                is_valid &= self.take_digits();
+            }
+        }
+        if self.current - self.checkpoint > self.max_number_length {
+            return Some(self.create_token(Error(LexError::NumberTooLong)));
+        }
+        if !is_valid {
+            return Some(self.create_token(Error(LexError::InvalidDigitSeparator)));
+        }
+        Some(self.create_token(Number))
     }
 
     fn make_identifier(&mut self) -> Option<Token<'a>> {
@@ -284,6 +461,9 @@ impl<'a> Tokenizer<'a> {
                 break;
             }
         }
+        if self.current - self.checkpoint > self.max_identifier_length {
+            return Some(self.create_token(Error(LexError::IdentifierTooLong)));
+        }
         Some(self.create_token(Identifier))
     }
 
@@ -299,17 +479,37 @@ impl<'a> Tokenizer<'a> {
             b')' => self.make_token_with_length(RightParen, 1),
             b'{' => self.make_token_with_length(LeftBrace, 1),
             b'}' => self.make_token_with_length(RightBrace, 1),
+            b'[' => self.make_token_with_length(LeftBracket, 1),
+            b']' => self.make_token_with_length(RightBracket, 1),
             b';' => self.make_token_with_length(Semicolon, 1),
             b',' => self.make_token_with_length(Comma, 1),
             b'.' => self.make_token_with_length(Dot, 1),
-            b'-' => self.make_token_with_length(Minus, 1),
-            b'+' => self.make_token_with_length(Plus, 1),
-            b'*' => self.make_token_with_length(Star, 1),
+            b'-' => match self.peek_bytes(2) {
+                Some("-=") => self.make_token_with_length(MinusEqual, 2),
+                _ => self.make_token_with_length(Minus, 1),
+            },
+            b'+' => match self.peek_bytes(2) {
+                Some("+=") => self.make_token_with_length(PlusEqual, 2),
+                _ => self.make_token_with_length(Plus, 1),
+            },
+            b'*' => match self.peek_bytes(2) {
+                Some("*=") => self.make_token_with_length(StarEqual, 2),
+                _ => self.make_token_with_length(Star, 1),
+            },
+            b'%' => self.make_token_with_length(Percent, 1),
             b'/' => match self.peek_bytes(2) {
                 Some("//") => {
                     self.take_comment();
                     self.token()
                 }
+                Some("/*") => {
+                    self.checkpoint();
+                    match self.take_block_comment() {
+                        true => self.token(),
+                        false => Some(self.create_token(Error(LexError::UnterminatedComment))),
+                    }
+                }
+                Some("/=") => self.make_token_with_length(SlashEqual, 2),
                 _ => self.make_token_with_length(Slash, 1),
             },
             b'!' => match self.peek_bytes(2) {
@@ -329,9 +529,14 @@ impl<'a> Tokenizer<'a> {
                 _ => self.make_token_with_length(Greater, 1),
             },
             b'"' => self.make_string(),
+            b'\'' => self.make_char(),
             it if it.is_ascii_digit() => self.make_number(),
             _ if self.match_bytes("and") => self.make_token_with_length(And, 3),
+            _ if self.match_bytes("break") => self.make_token_with_length(Break, 5),
             _ if self.match_bytes("class") => self.make_token_with_length(Class, 5),
+            _ if self.match_bytes("continue") => self.make_token_with_length(Continue, 8),
+            _ if self.match_bytes("div") => self.make_token_with_length(Div, 3),
+            _ if self.match_bytes("eprint") => self.make_token_with_length(EPrint, 6),
             _ if self.match_bytes("else") => self.make_token_with_length(Else, 4),
             _ if self.match_bytes("if") => self.make_token_with_length(If, 2),
             _ if self.match_bytes("nil") => self.make_token_with_length(Nil, 3),
@@ -458,7 +663,72 @@ mod tests {
     fn tokenize(source: &str) -> Vec<TokenKind> {
         let tokenizer = Tokenizer::new(source);
 
-        tokenizer.map(|it| it.kind).collect::<Vec<_>>()
+        let mut kinds = tokenizer.map(|it| it.kind).collect::<Vec<_>>();
+        assert_eq!(
+            kinds.pop(),
+            Some(TokenKind::Eof),
+            "tokens should end with Eof"
+        );
+        kinds
+    }
+
+    // Kind, offset and line of every token, to pin where each one is found
+    fn positions(source: &str) -> Vec<(TokenKind, usize, usize)> {
+        Tokenizer::new(source)
+            .map(|it| (it.kind, it.offset, it.line))
+            .collect()
+    }
+
+    #[test]
+    fn token_positions() {
+        let source = "// greets\nvar name = \"lox\"; /* spans\ntwo lines */ fun greet(who) {\n  if (who != nil) print \"hi \" + who;\n  return 1.5;\n}\n";
+        let expected = vec![
+            // line 0 is only a comment
+            (Var, 10, 1),
+            (Identifier, 14, 1),
+            (Equal, 19, 1),
+            (String, 21, 1),
+            (Semicolon, 26, 1),
+            // the block comment ends on line 2
+            (Fun, 50, 2),
+            (Identifier, 54, 2),
+            (LeftParen, 59, 2),
+            (Identifier, 60, 2),
+            (RightParen, 63, 2),
+            (LeftBrace, 65, 2),
+            (If, 69, 3),
+            (LeftParen, 72, 3),
+            (Identifier, 73, 3),
+            (BangEqual, 77, 3),
+            (Nil, 80, 3),
+            (RightParen, 83, 3),
+            (Print, 85, 3),
+            (String, 91, 3),
+            (Plus, 97, 3),
+            (Identifier, 99, 3),
+            (Semicolon, 102, 3),
+            (Return, 106, 4),
+            (Number, 113, 4),
+            (Semicolon, 116, 4),
+            (RightBrace, 118, 5),
+            // after the trailing newline
+            (Eof, 120, 6),
+        ];
+        assert_eq!(positions(source), expected);
+    }
+
+    #[test]
+    fn ends_with_eof() {
+        let mut t = Tokenizer::new("1;\n");
+        assert_eq!(t.next().map(|it| it.kind), Some(Number));
+        assert_eq!(t.next().map(|it| it.kind), Some(Semicolon));
+        assert_eq!(t.next(), Some(Token::new(Eof, "", 3, 1)));
+        assert_eq!(t.next(), None);
+        assert_eq!(t.next(), None);
+
+        let mut t = Tokenizer::new("");
+        assert_eq!(t.next(), Some(Token::new(Eof, "", 0, 0)));
+        assert_eq!(t.next(), None);
     }
 
     #[test]
@@ -469,13 +739,30 @@ mod tests {
         assert_eq!(t.next().map(|it| it.kind), Some(RightParen));
     }
 
+    #[test]
+    fn single_tokens_percent() {
+        assert_eq!(tokenize("10 % 3"), vec!(Number, Percent, Number));
+    }
+
     #[test]
     fn single_tokens_2() {
         assert_eq!(
-            tokenize("(){};,.-+/*"),
+            // `/*` would start a block comment
+            tokenize("(){}[];,.-+*/"),
             vec!(
-                LeftParen, RightParen, LeftBrace, RightBrace, Semicolon, Comma, Dot, Minus, Plus,
-                Slash, Star
+                LeftParen,
+                RightParen,
+                LeftBrace,
+                RightBrace,
+                LeftBracket,
+                RightBracket,
+                Semicolon,
+                Comma,
+                Dot,
+                Minus,
+                Plus,
+                Star,
+                Slash
             )
         );
     }
@@ -500,6 +787,14 @@ mod tests {
         );
     }
 
+    #[test]
+    fn compound_assignment_tokens() {
+        assert_eq!(
+            tokenize("+=-=*=/=+ ="),
+            vec!(PlusEqual, MinusEqual, StarEqual, SlashEqual, Plus, Equal)
+        );
+    }
+
     #[test]
     fn handles_whitespace_1() {
         assert_eq!(tokenize("  ()"), vec!(LeftParen, RightParen));
@@ -518,6 +813,37 @@ mod tests {
         assert_eq!(tokenize("====      "), vec!(EqualEqual, EqualEqual));
     }
 
+    #[test]
+    fn collects_comments() {
+        let source = "// adds numbers\nfun add(a, b) { /* sum\n of both */ return a + b; }\nvar x; // the end";
+        let mut t = Tokenizer::new(source).with_comments();
+        let tokens = t.by_ref().map(|it| it.kind).collect::<Vec<_>>();
+
+        assert_eq!(tokens.first(), Some(&Fun));
+        assert_eq!(
+            t.comments(),
+            &[
+                Comment {
+                    text: " adds numbers",
+                    line: 0
+                },
+                Comment {
+                    text: " sum\n of both ",
+                    line: 1
+                },
+                Comment {
+                    text: " the end",
+                    line: 3
+                },
+            ]
+        );
+
+        // Not collected by default
+        let mut t = Tokenizer::new(source);
+        t.by_ref().for_each(|_| ());
+        assert_eq!(t.comments(), &[]);
+    }
+
     #[test]
     fn handles_comments_1() {
         assert_eq!(tokenize("// ok this is a comment"), vec!());
@@ -536,6 +862,41 @@ mod tests {
         assert_eq!(tokenize("// ok this is a comment \n!"), vec!(Bang));
     }
 
+    #[test]
+    fn handles_block_comments() {
+        assert_eq!(tokenize("1 /* hi */ + 2"), vec!(Number, Plus, Number));
+        assert_eq!(tokenize("/**/!/* * / */!"), vec!(Bang, Bang));
+        assert_eq!(
+            tokenize("1 /* a\nb\nc */ + 2 /* // */"),
+            vec!(Number, Plus, Number)
+        );
+    }
+
+    #[test]
+    fn handles_newlines_in_block_comments() {
+        let mut t = Tokenizer::new("/* one\ntwo\n */ x");
+        assert_eq!(t.next(), Some(Token::new(Identifier, "x", 15, 2)));
+    }
+
+    #[test]
+    fn handles_unterminated_block_comments() {
+        assert_eq!(
+            tokenize("1 + /* oops\n"),
+            vec!(Number, Plus, Error(LexError::UnterminatedComment))
+        );
+        let mut t = Tokenizer::new("/* oops");
+        assert_eq!(
+            t.next(),
+            Some(Token::new(
+                Error(LexError::UnterminatedComment),
+                "/* oops",
+                0,
+                0
+            ))
+        );
+        assert_eq!(t.next(), Some(Token::new(Eof, "", 7, 0)));
+    }
+
     #[test]
     fn handles_newlines() {
         let mut t = Tokenizer::new("*\n!\n.");
@@ -560,9 +921,42 @@ mod tests {
 
     #[test]
     fn handles_unterminated_strings() {
-        // @TODO this should terminate with error
         let mut t = Tokenizer::new("\"Hello world!");
-        assert_eq!(t.next(), None);
+        assert_eq!(
+            t.next(),
+            Some(Token::new(
+                Error(LexError::UnterminatedString),
+                "\"Hello world!",
+                0,
+                0
+            ))
+        );
+        assert_eq!(t.next(), Some(Token::new(Eof, "", 13, 0)));
+    }
+
+    #[test]
+    fn handles_unterminated_chars() {
+        let mut t = Tokenizer::new("'a; print 1;");
+        assert_eq!(
+            t.next(),
+            Some(Token::new(
+                Error(LexError::UnterminatedChar),
+                "'a; print 1;",
+                0,
+                0
+            ))
+        );
+        assert_eq!(t.next(), Some(Token::new(Eof, "", 12, 0)));
+    }
+
+    #[test]
+    fn handles_chars() {
+        let mut t = Tokenizer::new("'A' '\\n' '\\'' 'ab' ''");
+        assert_eq!(t.next(), Some(Token::new(Char, "'A'", 0, 0)));
+        assert_eq!(t.next(), Some(Token::new(Char, "'\\n'", 4, 0)));
+        assert_eq!(t.next(), Some(Token::new(Char, "'\\''", 9, 0)));
+        assert_eq!(t.next(), Some(Token::new(Char, "'ab'", 14, 0)));
+        assert_eq!(t.next(), Some(Token::new(Char, "''", 19, 0)));
     }
 
     #[test]
@@ -584,6 +978,35 @@ mod tests {
         assert_eq!(t.next(), Some(Token::new(Number, "1", 1, 0)));
     }
 
+    #[test]
+    fn handles_scientific_numbers() {
+        let mut t = Tokenizer::new("1e3 1.5e10 2e-3 4E+2 2.5");
+        assert_eq!(t.next(), Some(Token::new(Number, "1e3", 0, 0)));
+        assert_eq!(t.next(), Some(Token::new(Number, "1.5e10", 4, 0)));
+        assert_eq!(t.next(), Some(Token::new(Number, "2e-3", 11, 0)));
+        assert_eq!(t.next(), Some(Token::new(Number, "4E+2", 16, 0)));
+        assert_eq!(t.next(), Some(Token::new(Number, "2.5", 21, 0)));
+
+        assert_eq!(tokenize("e3"), vec!(Identifier));
+        assert_eq!(tokenize("1e"), vec!(Number, Identifier));
+        assert_eq!(tokenize("1e-"), vec!(Number, Identifier, Minus));
+        assert_eq!(tokenize("1."), vec!(Number, Dot));
+    }
+
+    #[test]
+    fn handles_digit_separators() {
+        let mut t = Tokenizer::new("1_000_000 1_0.2_5e1_0");
+        assert_eq!(t.next(), Some(Token::new(Number, "1_000_000", 0, 0)));
+        assert_eq!(t.next(), Some(Token::new(Number, "1_0.2_5e1_0", 10, 0)));
+
+        let invalid = Error(LexError::InvalidDigitSeparator);
+        assert_eq!(tokenize("5_"), vec!(invalid));
+        assert_eq!(tokenize("5__0"), vec!(invalid));
+        assert_eq!(tokenize("5_.5"), vec!(invalid));
+        // Not a number but a valid identifier
+        assert_eq!(tokenize("_5"), vec!(Identifier));
+    }
+
     #[test]
     fn handles_identifiers() {
         let mut t = Tokenizer::new("it _it it5");
@@ -592,6 +1015,32 @@ mod tests {
         assert_eq!(t.next(), Some(Token::new(Identifier, "it5", 7, 0)));
     }
 
+    #[test]
+    fn handles_too_long_identifiers() {
+        let long = "a".repeat(MAX_IDENTIFIER_LENGTH + 1);
+        let max = "a".repeat(MAX_IDENTIFIER_LENGTH);
+        assert_eq!(tokenize(&long), vec!(Error(LexError::IdentifierTooLong)));
+        assert_eq!(tokenize(&max), vec!(Identifier));
+
+        let mut t = Tokenizer::new("abcd abc ab").with_max_identifier_length(3);
+        assert_eq!(
+            t.next(),
+            Some(Token::new(Error(LexError::IdentifierTooLong), "abcd", 0, 0))
+        );
+        assert_eq!(t.next(), Some(Token::new(Identifier, "abc", 5, 0)));
+        assert_eq!(t.next(), Some(Token::new(Identifier, "ab", 9, 0)));
+    }
+
+    #[test]
+    fn handles_too_long_numbers() {
+        let mut t = Tokenizer::new("1234 123").with_max_number_length(3);
+        assert_eq!(
+            t.next(),
+            Some(Token::new(Error(LexError::NumberTooLong), "1234", 0, 0))
+        );
+        assert_eq!(t.next(), Some(Token::new(Number, "123", 5, 0)));
+    }
+
     #[test]
     fn handles_keyword_and() {
         let mut t = Tokenizer::new("and ! and! !and andand");
@@ -639,6 +1088,34 @@ mod tests {
         assert_eq!(t.next(), Some(Token::new(Identifier, "ors", 3, 0)));
     }
 
+    #[test]
+    fn handles_keyword_break() {
+        let mut t = Tokenizer::new("break breaks");
+        assert_eq!(t.next(), Some(Token::new(Break, "break", 0, 0)));
+        assert_eq!(t.next(), Some(Token::new(Identifier, "breaks", 6, 0)));
+    }
+
+    #[test]
+    fn handles_keyword_continue() {
+        let mut t = Tokenizer::new("continue continues");
+        assert_eq!(t.next(), Some(Token::new(Continue, "continue", 0, 0)));
+        assert_eq!(t.next(), Some(Token::new(Identifier, "continues", 9, 0)));
+    }
+
+    #[test]
+    fn handles_keyword_div() {
+        let mut t = Tokenizer::new("div divide");
+        assert_eq!(t.next(), Some(Token::new(Div, "div", 0, 0)));
+        assert_eq!(t.next(), Some(Token::new(Identifier, "divide", 4, 0)));
+    }
+
+    #[test]
+    fn handles_keyword_eprint() {
+        let mut t = Tokenizer::new("eprint eprints");
+        assert_eq!(t.next(), Some(Token::new(EPrint, "eprint", 0, 0)));
+        assert_eq!(t.next(), Some(Token::new(Identifier, "eprints", 7, 0)));
+    }
+
     #[test]
     fn handles_keyword_print() {
         let mut t = Tokenizer::new("print prints");
@@ -1,23 +1,115 @@
 use crate::chunk::{Chunk, Jump};
-use crate::heap::rc::RcHeap as Heap;
-use crate::opcode::Value::{Bool, Number, Object};
-use crate::opcode::{Byte, Obj, OpCode, Returned, Value};
-use crate::tokenizer::TokenKind;
+use crate::compiler::Diagnostic;
+use crate::heap::rc::RcHeap;
+use crate::heap::Heap;
+use crate::opcode::Value::{Bool, Int, Number, Object};
+use crate::opcode::{Byte, NativeFn, Obj, OpCode, Returned, Runtime, Value};
+use crate::parser::{Parser, ParserOptions};
+use crate::tokenizer::{LexError, TokenKind, Tokenizer};
 use crate::vm::InterpretError::{RuntimeError, RuntimeErrorWithReason, StackUnderflowError};
+use globals::{GlobalCache, Globals};
 use stack::Stack;
-use std::collections::HashMap;
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::collections::HashSet;
 use std::fmt::{Display, Formatter};
+use std::io::Write;
+use std::rc::Rc;
 
+mod globals;
+mod json;
+mod natives;
 mod stack;
 
-/// Virtual machine that executes our program
+// How many values from the top of the stack the trace shows
+const TRACE_STACK_DEPTH: usize = 4;
 
+// How deep calls can nest before we give up
+const FRAMES_MAX: usize = 64;
+
+// Heap size of the first garbage collection, after that it grows with the live objects
+const GC_THRESHOLD: usize = 1024;
+
+// Gets the value of every `print` statement
+pub type PrintHandler<'a> = Box<dyn FnMut(&Value) + 'a>;
+
+// A function being executed, the script itself runs outside of any frame
+struct CallFrame {
+    chunk: Rc<Chunk>,
+    ip: usize,
+    base: usize, // stack slot of the callee, its arguments and locals follow it
+}
+
+// Knobs to tweak how the vm runs
+#[derive(Debug, Default, Clone, Copy)]
+pub struct VmConfig {
+    // Logs the top of the stack after every instruction to stdout
+    pub trace: bool,
+    // Stops the program with an error once it executed this many instructions
+    pub fuel: Option<usize>,
+}
+
+/// What a run leaves behind for the next one, like the globals a REPL input defined
+pub struct Environment {
+    globals: Globals,
+    heap: RcHeap,
+}
+
+impl Default for Environment {
+    fn default() -> Self {
+        let mut globals = Globals::new();
+        natives::define_all(&mut globals);
+        Self {
+            globals,
+            heap: RcHeap::new(),
+        }
+    }
+}
+
+// What a `Call` can invoke
+enum Callee {
+    Function { chunk: Rc<Chunk>, arity: usize },
+    Native(NativeFn),
+}
+
+/// Virtual machine that executes our program. Values hold an `Rc<Obj>`, so it allocates on
+/// the `RcHeap`; the handles of the other heaps can't be kept in a value.
 pub struct Vm<'a> {
     chunk: &'a Chunk,
     stack: Stack,
-    heap: Heap,
-    globals: HashMap<String, Value>,
-    ip: usize,
+    heap: RcHeap,
+    next_gc: usize, // collects garbage when the heap reaches this size
+    globals: Globals,
+    global_cache: GlobalCache,
+    ip: usize, // of the script, a called function keeps its own in its frame
+    frames: Vec<CallFrame>,
+    // Source lines to pause on, same numbering as the chunk's lines
+    breakpoints: HashSet<usize>,
+    // Line of the last executed instruction, we only pause when entering a line
+    previous_line: Option<usize>,
+    // Set when paused so the next step executes the instruction instead of pausing again
+    resuming: bool,
+    // Where to log the stack after each instruction, off when None
+    trace: Option<Box<dyn Write + 'a>>,
+    // Instructions left to execute, unlimited when None
+    fuel: Option<usize>,
+    // Gets every printed value, printed to stdout when None
+    print_handler: Option<PrintHandler<'a>>,
+    // Where `print` writes to, unless the print handler takes the values
+    output: Box<dyn Write + 'a>,
+    // Where `eprint` writes to
+    error_output: Box<dyn Write + 'a>,
+}
+
+/// Result of executing a single instruction
+#[derive(Debug, PartialEq)]
+pub enum StepOutcome {
+    // The program is not done yet, ip and line of the next instruction to execute
+    Continue { ip: usize, line: usize },
+    // Paused before executing the first instruction of a line with a breakpoint
+    Breakpoint { ip: usize, line: usize },
+    // The program returned
+    Done(Value),
 }
 
 #[derive(Debug)]
@@ -29,10 +121,61 @@ pub enum CompilationErrorReason {
     ExpectedPrefix,
     ExpectedBinaryOperator,
     ScopeUnderflow,
+    InvalidCharLiteral,
+    InvalidEscapeSequence(char),
+    // A \x or \u escape without the digits it needs
+    MalformedEscapeSequence(char),
+    InvalidCodePoint(u32),
+    IdentifierTooLong,
+    NumberTooLong,
+    MissingInitializer,
+    UnexpectedDeclaration,
+    // Slot operands are a single byte
+    TooManyGlobalSlots,
+    BreakOutsideLoop,
+    ContinueOutsideLoop,
+    UnterminatedComment,
+    UnterminatedString,
+    UnterminatedChar,
+    InvalidDigitSeparator,
+    // Arithmetic on a string and a number literal, it would always fail at runtime
+    StringAndNumberOperands,
+    // The stack depth is off at this code address, a bug in the code generation
+    UnbalancedStack(usize),
     ExpectedDifferentToken {
         expected: TokenKind,
         received: TokenKind,
     },
+    // A warning promoted to an error because warnings are denied
+    DeniedWarning(Diagnostic),
+}
+
+impl Display for CompilationErrorReason {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CompilationErrorReason::BreakOutsideLoop => write!(f, "Can't break outside a loop"),
+            CompilationErrorReason::ContinueOutsideLoop => {
+                write!(f, "Can't continue outside a loop")
+            }
+            CompilationErrorReason::StringAndNumberOperands => {
+                write!(f, "Can't do arithmetic on a string and a number")
+            }
+            it => write!(f, "{:?}", it),
+        }
+    }
+}
+
+impl From<LexError> for CompilationErrorReason {
+    fn from(value: LexError) -> Self {
+        match value {
+            LexError::IdentifierTooLong => CompilationErrorReason::IdentifierTooLong,
+            LexError::NumberTooLong => CompilationErrorReason::NumberTooLong,
+            LexError::UnterminatedComment => CompilationErrorReason::UnterminatedComment,
+            LexError::UnterminatedString => CompilationErrorReason::UnterminatedString,
+            LexError::UnterminatedChar => CompilationErrorReason::UnterminatedChar,
+            LexError::InvalidDigitSeparator => CompilationErrorReason::InvalidDigitSeparator,
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -42,7 +185,15 @@ pub enum InterpretError {
     RuntimeError,
     StackUnderflowError,
     RuntimeErrorWithReason(&'static str),
+    // Reading a global that has not been defined, holds its name
+    UndefinedVariable(String),
+    // Running one of several files failed, holds its path
+    InFile(String, Box<InterpretError>),
+    // Compiling went on after an error and found more, in source order
+    CompileErrors(Vec<InterpretError>),
     JumpTooFar,
+    // A jump that lands outside the code or in the middle of an instruction
+    JumpOutOfBounds,
     Io(std::io::Error),
 }
 
@@ -52,16 +203,35 @@ impl From<std::io::Error> for InterpretError {
     }
 }
 
+// A single error stays as is, several become `CompileErrors`
+impl From<Vec<InterpretError>> for InterpretError {
+    fn from(mut value: Vec<InterpretError>) -> Self {
+        match value.len() {
+            1 => value.remove(0),
+            _ => InterpretError::CompileErrors(value),
+        }
+    }
+}
+
 impl Display for InterpretError {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
-            InterpretError::CompileError(_) => write!(f, "compilation error"),
+            InterpretError::CompileError(reason) => write!(f, "compilation error: {}", reason),
             InterpretError::RuntimeError => write!(f, "runtime error"),
             InterpretError::StackUnderflowError => write!(f, "stack underflow error"),
             InterpretError::RuntimeErrorWithReason(reason) => {
                 write!(f, "runtime error: {}", reason)
             }
+            InterpretError::UndefinedVariable(name) => {
+                write!(f, "runtime error: Undefined variable '{}'", name)
+            }
+            InterpretError::InFile(path, error) => write!(f, "{}: {}", path, error),
+            InterpretError::CompileErrors(errors) => {
+                write!(f, "{} compilation errors", errors.len())?;
+                errors.iter().try_for_each(|it| write!(f, "\n  {}", it))
+            }
             InterpretError::JumpTooFar => write!(f, "jump too far"),
+            InterpretError::JumpOutOfBounds => write!(f, "jump out of bounds"),
             InterpretError::LoadError => write!(f, "load error"),
             InterpretError::Io(io) => write!(f, "Io error {}", io),
         }
@@ -74,58 +244,239 @@ pub fn interpret(chunk: &Chunk) -> Result<Returned, InterpretError> {
     // Not strictly necessary to call free_all as it would be dropped by just going out of scope too
     vm.heap.free_all();
 
-    println!("Globals: {:?}", vm.globals);
-
     result.map(Returned::from)
 }
 
+// Like `interpret` but on error it also hands back what was left on the stack
+pub fn interpret_debug(chunk: &Chunk) -> Result<Returned, (InterpretError, Vec<Returned>)> {
+    let mut vm = Vm::new(chunk);
+    match vm.run() {
+        Ok(it) => Ok(Returned::from(it)),
+        Err(error) => {
+            let stack = vm.stack.values().iter().cloned().map(Returned::from);
+            Err((error, stack.collect()))
+        }
+    }
+}
+
+// Integer division rounding down like `floor` does for numbers, -7 // 2 is -4
+fn checked_div_floor(lhs: i64, rhs: i64) -> Option<i64> {
+    let it = lhs.checked_div(rhs)?;
+    match lhs % rhs != 0 && (lhs < 0) != (rhs < 0) {
+        true => Some(it - 1),
+        false => Some(it),
+    }
+}
+
+// The remainder that goes with `checked_div_floor`, it has the sign of the divisor: -7 % 2 is 1
+fn checked_rem_floor(lhs: i64, rhs: i64) -> Option<i64> {
+    // Only the quotient of i64::MIN / -1 overflows, the remainder is 0 like for any -1
+    if rhs == -1 {
+        return Some(0);
+    }
+    let it = lhs.checked_rem(rhs)?;
+    match it != 0 && (it < 0) != (rhs < 0) {
+        true => Some(it + rhs),
+        false => Some(it),
+    }
+}
+
 impl<'a> Vm<'a> {
     pub fn new(chunk: &'a Chunk) -> Self {
+        Self::with_config(chunk, VmConfig::default())
+    }
+
+    pub fn with_config(chunk: &'a Chunk, config: VmConfig) -> Self {
+        let mut globals = Globals::new();
+        natives::define_all(&mut globals);
         Vm {
             chunk,
             stack: Stack::new(),
-            heap: Heap::new(),
-            globals: HashMap::new(),
+            heap: RcHeap::new(),
+            next_gc: GC_THRESHOLD,
+            globals,
+            global_cache: GlobalCache::new(),
             ip: 0,
+            frames: Vec::new(),
+            breakpoints: HashSet::new(),
+            previous_line: None,
+            resuming: false,
+            trace: None,
+            fuel: None,
+            print_handler: None,
+            output: Box::new(std::io::stdout()),
+            error_output: Box::new(std::io::stderr()),
+        }
+        .configure(config)
+    }
+
+    /// Continues with the globals and heap of an earlier run
+    pub fn with_environment(mut self, environment: Environment) -> Self {
+        self.globals = environment.globals;
+        self.heap = environment.heap;
+        self
+    }
+
+    pub fn into_environment(self) -> Environment {
+        Environment {
+            globals: self.globals,
+            heap: self.heap,
+        }
+    }
+
+    /// Applies the config to a vm set up otherwise, like with an environment
+    pub fn configure(self, config: VmConfig) -> Self {
+        let VmConfig { trace, fuel } = config;
+        Self {
+            fuel,
+            ..self.with_trace(trace)
+        }
+    }
+
+    /// Makes a Rust function callable from the program as a global
+    pub fn define_native(&mut self, name: &str, function: NativeFn) {
+        natives::define(&mut self.globals, name, function)
+    }
+
+    /// Hands every printed value to the handler instead of writing it to stdout
+    pub fn on_print(&mut self, handler: PrintHandler<'a>) {
+        self.print_handler = Some(handler);
+    }
+
+    /// Sends what `print` writes to the sink instead of stdout
+    pub fn with_output<W: Write + 'a>(mut self, sink: W) -> Self {
+        self.output = Box::new(sink);
+        self
+    }
+
+    /// Sends what `eprint` writes to the sink instead of stderr
+    pub fn with_error_output<W: Write + 'a>(mut self, sink: W) -> Self {
+        self.error_output = Box::new(sink);
+        self
+    }
+
+    /// Logs the top of the stack after every instruction to stdout
+    pub fn with_trace(self, trace: bool) -> Self {
+        match trace {
+            true => self.with_trace_to(std::io::stdout()),
+            false => Self {
+                trace: None,
+                ..self
+            },
+        }
+    }
+
+    pub fn with_trace_to<W: Write + 'a>(mut self, sink: W) -> Self {
+        self.trace = Some(Box::new(sink));
+        self
+    }
+
+    // The code being executed, of the called function or else the script
+    fn chunk(&self) -> &Chunk {
+        match self.frames.last() {
+            Some(frame) => &frame.chunk,
+            None => self.chunk,
+        }
+    }
+
+    fn ip(&self) -> usize {
+        match self.frames.last() {
+            Some(frame) => frame.ip,
+            None => self.ip,
+        }
+    }
+
+    fn ip_mut(&mut self) -> &mut usize {
+        match self.frames.last_mut() {
+            Some(frame) => &mut frame.ip,
+            None => &mut self.ip,
         }
     }
 
+    // Where the locals of the code being executed start on the stack
+    fn base(&self) -> usize {
+        self.frames.last().map_or(0, |it| it.base)
+    }
+
     /// Returns the next to fetch instruction location and advances the ip
     fn advance(&mut self) -> usize {
-        let ip = self.ip;
-        self.ip = ip + 1;
-        ip
+        let ip = self.ip_mut();
+        let at = *ip;
+        *ip = at + 1;
+        at
     }
 
     fn read_byte(&mut self) -> Option<Byte> {
-        self.chunk.read_byte(self.advance())
+        let at = self.advance();
+        self.chunk().read_byte(at)
     }
 
     fn read_jump(&mut self) -> Option<Jump> {
         let at = self.advance(); // start of jump code
         self.advance(); // advance once more because a jump is 2 bytes long
-        self.chunk.read_jump(at)
+        self.chunk().read_jump(at)
     }
 
     fn read_constant(&mut self) -> Result<Value, InterpretError> {
-        self.chunk.read_constant(self.advance()).ok_or(RuntimeError)
+        let at = self.advance();
+        self.chunk().read_constant(at).ok_or(RuntimeError)
     }
 
-    fn read_string(&mut self) -> Result<Value, InterpretError> {
-        let it = self.chunk.read_string(self.advance());
-        let str = it.ok_or(RuntimeError)?;
-        let obj = self.heap.alloc(Obj::String {
-            str: str.to_string(),
-        });
-        Ok(Value::Object(obj))
+    fn read_constant_long(&mut self) -> Result<Value, InterpretError> {
+        let at = self.advance(); // start of the index
+        self.advance(); // advance once more because the index is 2 bytes long
+        self.chunk().read_constant_long(at).ok_or(RuntimeError)
+    }
+
+    fn read_string(&mut self, long: bool) -> Result<Value, InterpretError> {
+        let str = self.read_global_name(long)?;
+        Ok(self.intern(str))
     }
 
-    fn read_global_name(&mut self) -> Result<String, InterpretError> {
-        let it = self.chunk.read_string(self.advance());
+    // Strings with the same content share one object
+    fn intern(&mut self, str: String) -> Value {
+        self.collect_when_full();
+        Object(self.heap.intern(str))
+    }
+
+    // The caller collects first, while what goes in the object is still on the stack
+    fn alloc(&mut self, object: Obj) -> Value {
+        Object(self.heap.alloc(object))
+    }
+
+    fn collect_when_full(&mut self) {
+        if self.heap.size() >= self.next_gc {
+            self.collect_preserving_globals();
+            self.next_gc = GC_THRESHOLD.max(self.heap.size() * 2);
+        }
+    }
+
+    // The long instructions refer to the string with a two byte index
+    fn read_global_name(&mut self, long: bool) -> Result<String, InterpretError> {
+        let at = self.advance();
+        let it = match long {
+            true => {
+                self.advance();
+                self.chunk().read_string_long(at)
+            }
+            false => self.chunk().read_string(at),
+        };
         let str = it.ok_or(RuntimeError)?;
         Ok(str.to_string())
     }
 
+    // Where the global of the slot operand is stored, links the chunk to the globals first
+    fn read_global_slot(&mut self) -> Result<usize, InterpretError> {
+        let at = self.advance();
+        let chunk = match self.frames.last() {
+            Some(frame) => &frame.chunk,
+            None => self.chunk,
+        };
+        let globals = &mut self.globals;
+        chunk.link_global_slots(globals.id(), |name| globals.slot(name));
+        chunk.read_linked_global_slot(at).ok_or(RuntimeError)
+    }
+
     fn push_stack(&mut self, value: Value) {
         self.stack.push(value)
     }
@@ -139,6 +490,96 @@ impl<'a> Vm<'a> {
     }
 
     pub fn run(&mut self) -> Result<Value, InterpretError> {
+        loop {
+            if let StepOutcome::Done(it) = self.step()? {
+                break Ok(it);
+            }
+        }
+    }
+
+    pub fn set_breakpoint(&mut self, line: usize) {
+        self.breakpoints.insert(line);
+    }
+
+    pub fn clear_breakpoint(&mut self, line: usize) {
+        self.breakpoints.remove(&line);
+    }
+
+    pub fn clear_breakpoints(&mut self) {
+        self.breakpoints.clear();
+    }
+
+    /// Runs another chunk on this vm, keeping the globals and heap of the previous runs
+    pub fn load(&mut self, chunk: &'a Chunk) {
+        self.chunk = chunk;
+        self.ip = 0;
+        self.frames.clear();
+        self.stack = Stack::new();
+        // Cached reads are keyed by the address in the old chunk
+        self.global_cache = GlobalCache::new();
+        self.previous_line = None;
+        self.resuming = false;
+    }
+
+    /// Frees the heap objects no global or stack value refers to
+    pub fn collect_preserving_globals(&mut self) {
+        let roots = self.globals.values().chain(self.stack.values());
+        self.heap.collect(roots);
+        self.trace_heap();
+    }
+
+    /// Looks up the current value of a local or global variable by name, to inspect
+    /// a paused program
+    pub fn watch(&self, name: &str) -> Option<Returned> {
+        let value = match self.chunk().resolve_local(name, self.ip()) {
+            Some(slot) => self.stack.get(self.base() + slot),
+            None => self.globals.get(name),
+        };
+        value.cloned().map(Returned::from)
+    }
+
+    /// Evaluates the source against the current state, like the eval prompt of a debugger.
+    /// It sees the globals and the locals in scope of a paused program but works on a copy
+    /// of them, nothing it assigns or defines changes the program.
+    pub fn eval(&self, source: &str) -> Result<Returned, InterpretError> {
+        let options = ParserOptions {
+            keep_last_expression: true,
+            ..Default::default()
+        };
+        let chunk = Parser::parse_with_options(Tokenizer::new(source), options)?;
+
+        let mut globals = self.globals.snapshot();
+        for (name, slot) in self.chunk().visible_locals(self.ip()) {
+            if let Some(it) = self.stack.get(self.base() + slot) {
+                globals.insert(name.to_string(), it.clone());
+            }
+        }
+        let mut vm = Vm::new(&chunk);
+        vm.globals = globals;
+        vm.run().map(Returned::from)
+    }
+
+    /// Executes exactly one instruction, unless it hits a breakpoint
+    pub fn step(&mut self) -> Result<StepOutcome, InterpretError> {
+        let line = self.chunk().read_line(self.ip()).ok_or(RuntimeError)?;
+        let is_entering_line = self.previous_line != Some(line);
+        if !self.resuming && is_entering_line && self.breakpoints.contains(&line) {
+            self.resuming = true;
+            return Ok(StepOutcome::Breakpoint {
+                ip: self.ip(),
+                line,
+            });
+        }
+        self.resuming = false;
+        self.previous_line = Some(line);
+        if let Some(fuel) = self.fuel.as_mut() {
+            if *fuel == 0 {
+                Err(RuntimeErrorWithReason("Out of fuel"))?
+            }
+            *fuel -= 1;
+        }
+        let at = self.ip();
+
         macro_rules! binary_op_number {
             ($op:tt) => {
                 {
@@ -152,235 +593,1234 @@ impl<'a> Vm<'a> {
                     self.push_stack(Number(lhs $op rhs))
                 }
             };
-        }
-
-        macro_rules! binary_op_bool {
-            ($op:tt) => {
+            // Two ints stay an int, `$checked` is the int operation returning None on overflow
+            ($op:tt, $checked:expr) => {
                 {
-
-                    let is_number = self.peek_stack(0).is_some_and(|it| it.is_number()) &&  self.peek_stack(1).is_some_and(|it| it.is_number());
-                    if !is_number {
-                        Err(RuntimeErrorWithReason("Operands must be numbers"))?;
+                    let is_int = self.peek_stack(0).is_some_and(|it| it.is_int()) &&  self.peek_stack(1).is_some_and(|it| it.is_int());
+                    if is_int {
+                        let rhs = self.pop_stack()?.as_int();
+                        let lhs = self.pop_stack()?.as_int();
+                        let it = $checked(lhs, rhs).ok_or(RuntimeErrorWithReason("Integer overflow"))?;
+                        self.push_stack(Int(it))
+                    } else {
+                        binary_op_number!($op)
                     }
-                    let rhs = self.pop_stack()?.as_number();
-                    let lhs = self.pop_stack()?.as_number();
-                    self.push_stack(Bool(lhs $op rhs))
                 }
             };
         }
 
+        macro_rules! binary_op_bool {
+            ($is:path) => {{
+                let is_number = self.peek_stack(0).is_some_and(|it| it.is_number())
+                    && self.peek_stack(1).is_some_and(|it| it.is_number());
+                let is_string = self.peek_stack(0).is_some_and(|it| it.is_string())
+                    && self.peek_stack(1).is_some_and(|it| it.is_string());
+                if is_string {
+                    // Lexicographic, by comparing the bytes
+                    let rhs = self.pop_stack()?;
+                    let lhs = self.pop_stack()?;
+                    self.push_stack(Bool($is(lhs.as_string().cmp(rhs.as_string()))))
+                } else if is_number {
+                    // Ints and numbers compare by their value, NaN is never ordered
+                    let rhs = self.pop_stack()?;
+                    let lhs = self.pop_stack()?;
+                    self.push_stack(Bool(lhs.ordering(&rhs).is_some_and($is)))
+                } else {
+                    Err(RuntimeErrorWithReason(
+                        "Operands must be two numbers or two strings",
+                    ))?;
+                }
+            }};
+        }
+
         use OpCode::*;
-        loop {
-            match self.read_decode()? {
-                // We are done
-                Return => {
-                    // there should be just one value on the stack which will be popped before we exit
-
-                    let it = self.pop_stack()?;
-
-                    if !self.stack.is_empty() {
-                        // Currently, we can do an early return and still have some items on the stack
-                        println!("stack not empty: {:?}", self.stack);
-                        // Err(RuntimeErrorWithReason(
-                        //     "Program terminating but stack is not empty",
-                        // ))?;
+        match self.read_decode()? {
+            // We are done
+            Return => {
+                // there should be just one value on the stack which will be popped before we exit
+
+                let it = self.pop_stack()?;
+
+                // Back to the caller, the callee and its locals make room for the result
+                if let Some(frame) = self.frames.pop() {
+                    self.stack.truncate(frame.base);
+                    self.push_stack(it);
+                } else {
+                    if let Some(trace) = self.trace.as_mut() {
+                        if !self.stack.is_empty() {
+                            // Currently, we can do an early return and still have some items on the stack
+                            writeln!(trace, "stack not empty: {:?}", self.stack)?;
+                            // Err(RuntimeErrorWithReason(
+                            //     "Program terminating but stack is not empty",
+                            // ))?;
+                        }
+                        writeln!(trace, "Return: {:?}", it)?;
+                    }
+                    self.trace_globals()?;
+                    return Ok(StepOutcome::Done(it));
+                }
+            }
+
+            // unary
+            Not => {
+                let it = self.pop_stack()?.is_truthy();
+                self.push_stack(Bool(!it));
+            }
+
+            // Literals
+            False => self.push_stack(Bool(false)),
+            True => self.push_stack(Bool(true)),
+            Nil => self.push_stack(Value::Nil),
+            code @ (String | StringLong) => {
+                let x = self.read_string(code == StringLong)?;
+                // @todo turn into string Value
+                self.push_stack(x)
+            }
+
+            // Comparison
+            Equal => {
+                let rhs = self.pop_stack()?;
+                let lhs = self.pop_stack()?;
+                self.push_stack(Value::Bool(lhs.is_equal(&rhs)));
+            }
+            Greater => binary_op_bool!(Ordering::is_gt),
+            Less => binary_op_bool!(Ordering::is_lt),
+
+            // Arithmetic
+            Add => {
+                let is_string = self.peek_stack(0).is_some_and(|it| it.is_string())
+                    && self.peek_stack(1).is_some_and(|it| it.is_string());
+                if is_string {
+                    self.string_concatenate()?;
+                } else {
+                    binary_op_number!(+, i64::checked_add)
+                }
+            }
+            Subtract => binary_op_number!(-, i64::checked_sub),
+            Multiply => binary_op_number!(*, i64::checked_mul),
+            // Always a number, 10 / 4 is 2.5
+            Divide => {
+                self.check_divisor()?;
+                binary_op_number!(/)
+            }
+            // Rounds down like `div`, so `a == (a div b) * b + a % b` holds
+            Modulo => {
+                self.check_divisor()?;
+                let divisor = self
+                    .peek_stack(0)
+                    .filter(|it| it.is_number())
+                    .map(Value::as_number);
+                binary_op_number!(%, checked_rem_floor);
+                let it = match (self.pop_stack()?, divisor) {
+                    (Number(it), Some(divisor)) if it != 0.0 && (it < 0.0) != (divisor < 0.0) => {
+                        Number(it + divisor)
                     }
-                    println!("Return: {:?}", it);
-                    break Ok(it);
+                    (it, _) => it,
+                };
+                self.push_stack(it)
+            }
+            IntDivide => {
+                self.check_divisor()?;
+                binary_op_number!(/, checked_div_floor);
+                let it = match self.pop_stack()? {
+                    Number(it) => Number(it.floor()),
+                    it => it,
+                };
+                self.push_stack(it)
+            }
+            Negate => {
+                let is_number = self.peek_stack(0).is_some_and(|it| it.is_number());
+                if !is_number {
+                    Err(RuntimeErrorWithReason("Negation works on numbers only"))?;
                 }
+                let it = match self.pop_stack()? {
+                    Int(it) => Int(it
+                        .checked_neg()
+                        .ok_or(RuntimeErrorWithReason("Integer overflow"))?),
+                    it => Number(-it.as_number()),
+                };
+                self.push_stack(it)
+            }
+
+            Constant => {
+                let x = self.read_constant()?;
+                self.push_stack(x)
+            }
+            ConstantLong => {
+                let x = self.read_constant_long()?;
+                self.push_stack(x)
+            }
 
-                // unary
-                Not => {
-                    let it = self.pop_stack()?.is_truthy();
-                    self.push_stack(Bool(!it));
+            // bindings
+            code @ (DefineGlobal | DefineGlobalLong) => {
+                let name = self.read_global_name(code == DefineGlobalLong)?;
+                let value = self.pop_stack()?;
+                self.globals.insert(name, value);
+            }
+
+            code @ (GetGlobal | GetGlobalLong) => {
+                // Cache the value per call site, any write to the globals invalidates it.
+                // Only for the script, addresses in functions would clash with its addresses.
+                let at = self.ip();
+                let generation = self.globals.generation();
+                let cached = match self.frames.is_empty() {
+                    true => self.global_cache.get(at, generation).cloned(),
+                    false => None,
+                };
+                let value = match cached {
+                    Some(it) => {
+                        // skip the name operand, no need to look it up
+                        for _ in 0..code.operand_len() {
+                            self.advance();
+                        }
+                        it
+                    }
+                    None => {
+                        let name = self.read_global_name(code == GetGlobalLong)?;
+                        let it = match self.globals.get(&name) {
+                            Some(it) => it.clone(),
+                            None => Err(InterpretError::UndefinedVariable(name))?,
+                        };
+                        if self.frames.is_empty() {
+                            self.global_cache.insert(at, generation, it.clone());
+                        }
+                        it
+                    }
+                };
+                self.push_stack(value)
+            }
+
+            code @ (SetGlobal | SetGlobalLong) => {
+                let name = self.read_global_name(code == SetGlobalLong)?;
+                // we dont pop from the stack according to the book
+                // that seems odd so we dont
+                // => We dont because this is an expression statement which will auto pop the stack
+                let value = self.peek_stack(0).ok_or(StackUnderflowError)?.clone();
+                if !self.globals.set(name, value) {
+                    Err(RuntimeErrorWithReason("Global is not defined"))?
                 }
+            }
 
-                // Literals
-                False => self.push_stack(Bool(false)),
-                True => self.push_stack(Bool(true)),
-                Nil => self.push_stack(Value::Nil),
-                String => {
-                    let x = self.read_string()?;
-                    // @todo turn into string Value
-                    self.push_stack(x)
+            DefineGlobalSlot => {
+                let slot = self.read_global_slot()?;
+                let value = self.pop_stack()?;
+                self.globals.define_slot(slot, value);
+            }
+
+            GetGlobalSlot => {
+                let at = self.ip();
+                let slot = self.read_global_slot()?;
+                let value = match self.globals.get_slot(slot) {
+                    Some(it) => it.clone(),
+                    None => {
+                        let name = self.chunk().read_global_slot(at).ok_or(RuntimeError)?;
+                        Err(InterpretError::UndefinedVariable(name.to_string()))?
+                    }
+                };
+                self.push_stack(value)
+            }
+
+            SetGlobalSlot => {
+                let slot = self.read_global_slot()?;
+                let value = self.peek_stack(0).ok_or(StackUnderflowError)?.clone();
+                if !self.globals.set_slot(slot, value) {
+                    Err(RuntimeErrorWithReason("Global is not defined"))?
                 }
+            }
 
-                // Comparison
-                Equal => {
-                    let rhs = self.pop_stack()?;
-                    let lhs = self.pop_stack()?;
-                    self.push_stack(Value::Bool(lhs == rhs));
-                } // @TODO more then just numbers can be compared
-                Greater => binary_op_bool!(>),
-                Less => binary_op_bool!(<),
-
-                // Arithmetic
-                Add => {
-                    let is_string = self.peek_stack(0).is_some_and(|it| it.is_string())
-                        && self.peek_stack(1).is_some_and(|it| it.is_string());
-                    if is_string {
-                        self.string_concatenate()?;
-                    } else {
-                        binary_op_number!(+)
+            GetLocal => {
+                // next byte contains the local_var_offset
+                let at = self.read_byte().ok_or(RuntimeError)?;
+                let value =
+                    self.stack
+                        .get(self.base() + at as usize)
+                        .ok_or(RuntimeErrorWithReason(
+                            "Local variable value could not be found",
+                        ))?;
+                self.push_stack(value.clone());
+            }
+
+            SetLocal => {
+                // next byte contains the local_var_offset
+                let at = self.read_byte().ok_or(RuntimeError)?;
+                // According to the book, we should just peek the stack to not modify if but
+                // then our stack just keeps growing so better pop it.
+                // => We dont because this is an expression statement which will auto pop the stack
+                let value = self.peek_stack(0).ok_or(StackUnderflowError)?;
+                self.stack
+                    .set(self.base() + at as usize, value.clone())
+                    .ok_or(RuntimeErrorWithReason(
+                        "Local variable value could not be found",
+                    ))?;
+            }
+
+            // functions
+            Function => {
+                let function = self.read_constant()?;
+                self.push_stack(function)
+            }
+            FunctionLong => {
+                let function = self.read_constant_long()?;
+                self.push_stack(function)
+            }
+            code @ (Call | TailCall) => {
+                let arg_count = self.read_byte().ok_or(RuntimeError)? as usize;
+                // The callee sits below its arguments
+                let callee = Self::callee(self.peek_stack(arg_count).ok_or(StackUnderflowError)?);
+                match callee {
+                    Some(Callee::Function { chunk, arity }) => {
+                        if code == TailCall {
+                            self.leave_frame(arg_count)?;
+                        }
+                        self.call(chunk, arity, arg_count)?
+                    }
+                    Some(Callee::Native(function)) => {
+                        // The arguments stay on the stack while it runs, to survive a collection
+                        let arguments = self.stack.top(arg_count).to_vec();
+                        let it = function(self, &arguments)?;
+                        self.stack.pop_n(arg_count + 1).ok_or(StackUnderflowError)?;
+                        self.push_stack(it)
+                    }
+                    None => {
+                        self.stack.pop_n(arg_count + 1).ok_or(StackUnderflowError)?;
+                        Err(RuntimeErrorWithReason(
+                            "Can only call functions and classes",
+                        ))?
                     }
                 }
-                Subtract => binary_op_number!(-),
-                Multiply => binary_op_number!(*),
-                Divide => binary_op_number!(/),
-                Negate => {
-                    let is_number = self.peek_stack(0).is_some_and(|it| it.is_number());
-                    if !is_number {
-                        Err(RuntimeErrorWithReason("Negation works on numbers only"))?;
+            }
+
+            // collections
+            BuildArray => {
+                let len = self.read_byte().ok_or(RuntimeError)? as usize;
+                self.collect_when_full();
+                let elements = self.stack.pop_n(len).ok_or(StackUnderflowError)?;
+                let it = self.alloc(Obj::Array {
+                    elements: RefCell::new(elements),
+                });
+                self.push_stack(it)
+            }
+            Index => {
+                let index = self.pop_stack()?;
+                let it = self.pop_stack()?;
+                let element = match it.as_object() {
+                    Some(Obj::Array { elements }) => {
+                        let elements = elements.borrow();
+                        elements[index.as_index(elements.len())?].clone()
+                    }
+                    Some(Obj::String { str }) => {
+                        let at = index.as_index(str.chars().count())?;
+                        let str = str.chars().nth(at).ok_or(RuntimeError)?.to_string();
+                        self.intern(str)
                     }
-                    let x = self.pop_stack()?;
-                    self.push_stack(Number(-x.as_number()))
+                    _ => Err(RuntimeErrorWithReason("Can only index strings and arrays"))?,
+                };
+                self.push_stack(element)
+            }
+            SetIndex => {
+                let value = self.pop_stack()?;
+                let index = self.pop_stack()?;
+                let it = self.pop_stack()?;
+                let Some(Obj::Array { elements }) = it.as_object() else {
+                    Err(RuntimeErrorWithReason("Can only set elements of arrays"))?
+                };
+                let at = index.as_index(elements.borrow().len())?;
+                elements.borrow_mut()[at] = value.clone();
+                self.push_stack(value)
+            }
+
+            // statements
+            Print => {
+                self.print()?;
+            }
+            EPrint => {
+                let it = self.pop_stack()?;
+                writeln!(self.error_output, "{}", it)?;
+            }
+            Pop => {
+                self.pop_stack()?;
+            }
+            // control flow
+            JumpIfFalse => {
+                // Always read the jump as it will update the ip past the Jump bytes
+                // which we need if we dont jump so the next instruction to fetch
+                // on true if the on true block
+                let distance = self.read_jump().ok_or(RuntimeError)?;
+                if !self.peek_stack(0).ok_or(StackUnderflowError)?.is_truthy() {
+                    self.jump_forward(distance)
                 }
+            }
+            JumpIfTrue => {
+                // Always read the jump as it will update the ip past the Jump bytes
+                // which we need if we dont jump so the next instruction to fetch
+                // on false if the on false block
+                let distance = self.read_jump().ok_or(RuntimeError)?;
+                if self.peek_stack(0).ok_or(StackUnderflowError)?.is_truthy() {
+                    self.jump_forward(distance)
+                }
+            }
+
+            Jump => {
+                let distance = self.read_jump().ok_or(RuntimeError)?;
+                self.jump_forward(distance)
+            }
+
+            Loop => {
+                let distance = self.read_jump().ok_or(RuntimeError)?;
+                self.jump_backward(distance)?
+            }
+        }
+
+        self.trace_stack(at)?;
+
+        Ok(StepOutcome::Continue {
+            ip: self.ip(),
+            line: self.chunk().read_line(self.ip()).ok_or(RuntimeError)?,
+        })
+    }
+
+    // Runs the function next, its arguments become its first locals
+    fn callee(value: &Value) -> Option<Callee> {
+        match value.as_object()? {
+            Obj::Function { arity, chunk, .. } => Some(Callee::Function {
+                chunk: chunk.clone(),
+                arity: *arity,
+            }),
+            Obj::Native { function, .. } => Some(Callee::Native(*function)),
+            _ => None,
+        }
+    }
+
+    fn call(
+        &mut self,
+        chunk: Rc<Chunk>,
+        arity: usize,
+        arg_count: usize,
+    ) -> Result<(), InterpretError> {
+        if arity != arg_count {
+            Err(RuntimeErrorWithReason("Wrong number of arguments"))?
+        }
+        if self.frames.len() == FRAMES_MAX {
+            Err(RuntimeErrorWithReason("Stack overflow"))?
+        }
+        let base = self.stack.len() - arg_count - 1;
+        self.frames.push(CallFrame { chunk, ip: 0, base });
+        Ok(())
+    }
+
+    // Makes room for a tail call, the callee and its arguments take the place of the current
+    // frame. The script has no frame to leave, there it is a plain call.
+    fn leave_frame(&mut self, arg_count: usize) -> Result<(), InterpretError> {
+        if let Some(frame) = self.frames.pop() {
+            let call = self.stack.pop_n(arg_count + 1).ok_or(StackUnderflowError)?;
+            self.stack.truncate(frame.base);
+            for it in call {
+                self.push_stack(it);
+            }
+        }
+        Ok(())
+    }
+
+    // Both 0.0 and -0.0 are zero, dividing by them is an error instead of inf or NaN
+    fn check_divisor(&self) -> Result<(), InterpretError> {
+        match self.peek_stack(0) {
+            Some(Number(it)) if *it == 0.0 => Err(RuntimeErrorWithReason("Division by zero")),
+            Some(Int(0)) => Err(RuntimeErrorWithReason("Division by zero")),
+            _ => Ok(()),
+        }
+    }
+
+    fn string_concatenate(&mut self) -> Result<(), InterpretError> {
+        let rhs = self.pop_stack()?;
+        let lhs = self.pop_stack()?;
+        let (lhs, rhs) = (lhs.as_string(), rhs.as_string());
+        // Allocate once for the result instead of copying lhs and growing it
+        let mut str = std::string::String::with_capacity(lhs.len() + rhs.len());
+        str.push_str(lhs);
+        str.push_str(rhs);
+        let it = self.intern(str);
+        self.push_stack(it);
+        Ok(())
+    }
+
+    fn trace_stack(&mut self, at: usize) -> Result<(), InterpretError> {
+        if let Some(trace) = self.trace.as_mut() {
+            write!(trace, "{:8} | stack", at)?;
+            for value in self.stack.top(TRACE_STACK_DEPTH) {
+                write!(trace, " [ {} ]", value)?;
+            }
+            writeln!(trace)?;
+        }
+        Ok(())
+    }
+
+    // The globals the script leaves behind once it is done
+    fn trace_globals(&mut self) -> Result<(), InterpretError> {
+        if let Some(trace) = self.trace.as_mut() {
+            write!(trace, "Globals:")?;
+            for (name, value) in self.globals.iter() {
+                write!(trace, " [ {} = {} ]", name, value)?;
+            }
+            writeln!(trace)?;
+        }
+        Ok(())
+    }
+
+    // What is left on the heap after a collection, to tune the gc and spot leaks
+    fn trace_heap(&mut self) {
+        if let Some(trace) = self.trace.as_mut() {
+            // A trace that can't be written shouldn't fail the collection
+            let _ = writeln!(
+                trace,
+                "      gc | {} objects, {} bytes, {} allocations",
+                self.heap.size(),
+                self.heap.bytes_allocated(),
+                self.heap.allocation_count()
+            );
+        }
+    }
+
+    fn read_decode(&mut self) -> Result<OpCode, InterpretError> {
+        // No more codes to fetch... runtime error
+        let byte = self.read_byte().ok_or(RuntimeError)?;
+        // Byte is not an opcode... runtime error
+        let code = OpCode::try_from(byte).map_err(|_| RuntimeError)?;
+
+        // This is ugly, because read_byte advances the ip, we need to put it back
+        // for the disassemble instruction
+        let at = self.ip() - 1;
+        if let Some(trace) = self.trace.as_mut() {
+            // Not through `chunk()`, the trace is borrowed already
+            let chunk = match self.frames.last() {
+                Some(frame) => &frame.chunk,
+                None => self.chunk,
+            };
+            chunk.disassemble_instruction_to(trace, byte, at);
+        }
+
+        Ok(code)
+    }
+
+    fn print(&mut self) -> Result<(), InterpretError> {
+        // According to the book: `Print is a statement so does not modify the stack`
+        // But that means our stack just keeps growing?
+        // Lets just pop the argument to print so that after print our
+        // stack is back where it was so
+        // our program exists correctly with an empty stack
+        let it = self.pop_stack()?;
+        match self.print_handler.as_mut() {
+            Some(handler) => handler(&it),
+            // The way Lox prints: `true`, `false` and `nil` lowercase, strings without quotes
+            None => writeln!(self.output, "{}", it)?,
+        }
+        Ok(())
+    }
+
+    fn jump_forward(&mut self, jump: Jump) {
+        *self.ip_mut() += jump.distance as usize;
+    }
+
+    // Loops only go back, to the start of an instruction at or before the loop itself
+    fn jump_backward(&mut self, jump: Jump) -> Result<(), InterpretError> {
+        let loop_at = self.ip() - 3; // the ip is past the opcode and its 2 operand bytes
+        let to = self
+            .ip()
+            .checked_sub(jump.distance as usize)
+            .filter(|it| *it <= loop_at && self.chunk().is_instruction_start(*it))
+            .ok_or(InterpretError::JumpOutOfBounds)?;
+        *self.ip_mut() = to;
+        Ok(())
+    }
+}
+
+// Natives call back into the vm, a function runs on top of whatever the native was called from
+impl Runtime for Vm<'_> {
+    fn call_value(&mut self, callee: &Value, arguments: &[Value]) -> Result<Value, InterpretError> {
+        match Self::callee(callee) {
+            Some(Callee::Function { chunk, arity }) => {
+                let depth = self.frames.len();
+                self.push_stack(callee.clone());
+                for it in arguments {
+                    self.push_stack(it.clone());
+                }
+                self.call(chunk, arity, arguments.len())?;
+                while self.frames.len() > depth {
+                    self.step()?;
+                }
+                self.pop_stack()
+            }
+            Some(Callee::Native(function)) => function(self, arguments),
+            None => Err(RuntimeErrorWithReason(
+                "Can only call functions and classes",
+            )),
+        }
+    }
+
+    fn alloc_value(&mut self, object: Obj) -> Value {
+        self.collect_when_full();
+        self.alloc(object)
+    }
+
+    fn intern_value(&mut self, str: String) -> Value {
+        self.intern(str)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::heap::estimated_size;
+    use crate::opcode::Value::Nil;
+
+    #[test]
+    fn interpret_math_expression_with_precedence() {
+        interpret_result(vec![("return 10 + 30 * 2;", 70)]);
+    }
+
+    #[test]
+    fn interpret_scientific_numbers() {
+        interpret_result(vec![
+            ("return 1e3;", Returned::Number(1000.0)),
+            ("return 1.5e10;", Returned::Number(1.5e10)),
+            ("return 2e-3;", Returned::Number(0.002)),
+            ("return 2.5 * 2;", Returned::Number(5.0)),
+            ("return 1_000_000;", Returned::Int(1_000_000)),
+            ("return 1_0.5e1;", Returned::Number(105.0)),
+        ]);
+    }
+
+    #[test]
+    fn interpret_function_calls() {
+        interpret_result(vec![
+            ("fun add(a, b) { return a + b; } return add(1, 2);", 3),
+            ("fun add(a, b) { var c = a + b; return c * 2; } return add(1, 2) + 1;", 7),
+            ("fun fib(n) { if (n < 2) return n; return fib(n - 1) + fib(n - 2); } return fib(10);", 55),
+            ("{ var x = 10; fun sub(a) { return a - 1; } return x + sub(x); }", 19),
+        ]);
+        let chunk = Parser::parse(Tokenizer::new("fun nothing() {} return nothing();")).unwrap();
+        assert_eq!(interpret(&chunk).unwrap(), Returned::Nil);
+    }
+
+    #[test]
+    fn interpret_native_functions() {
+        let chunk = Parser::parse(Tokenizer::new("return answer() + add(1, 2) * 10;")).unwrap();
+        let mut vm = Vm::new(&chunk);
+        vm.define_native("answer", |_, _| Ok(Number(42.0)));
+        vm.define_native("add", |_, arguments| {
+            Ok(Number(arguments.iter().map(|it| it.as_number()).sum()))
+        });
+        assert_eq!(Returned::from(vm.run().unwrap()), Returned::Number(72.0));
+
+        let chunk = Parser::parse(Tokenizer::new("return clock() > 0;")).unwrap();
+        assert_eq!(interpret(&chunk).unwrap(), Returned::Bool(true));
+    }
+
+    #[test]
+    fn interpret_higher_order_natives() {
+        for (source, expected) in [
+            (
+                "fun twice(x) { return x * 2; } return map([1, 2, 3], twice);",
+                "[2, 4, 6]",
+            ),
+            (
+                "fun odd(x) { return x % 2 == 1; } return filter([1, 2, 3, 4, 5], odd);",
+                "[1, 3, 5]",
+            ),
+            (
+                "fun add(a, b) { return a + b; } return reduce([1, 2, 3], add, 10);",
+                "16",
+            ),
+            ("fun add(a, b) { return a + b; } return reduce([], add, 10);", "10"),
+            // Functions calling natives calling functions
+            (
+                "fun inc(x) { return x + 1; } fun inc_all(a) { return map(a, inc); }
+                 return map([[1, 2], [3]], inc_all);",
+                "[[2, 3], [4]]",
+            ),
+            (
+                "fun has_a(s) { return contains(s, \"a\"); } return filter([\"ab\", \"cd\"], has_a);",
+                "[ab]",
+            ),
+        ] {
+            let chunk = Parser::parse(Tokenizer::new(source)).unwrap();
+            assert_eq!(interpret(&chunk).unwrap().to_string(), expected, "{}", source);
+        }
+
+        for (source, error) in [
+            ("return map([1], 1);", "Can only call functions and classes"),
+            (
+                "return filter([], nil);",
+                "Can only call functions and classes",
+            ),
+            (
+                "fun f(x) { return x; } return map(1, f);",
+                "Expected an array",
+            ),
+            (
+                "fun f(x) { return x; } return reduce([1], f);",
+                "Wrong number of arguments",
+            ),
+            (
+                "fun f(x) { return x; } return reduce([1], f, 0);",
+                "Wrong number of arguments",
+            ),
+            (
+                "fun f(x) { return -nil; } return map([1], f);",
+                "Negation works on numbers only",
+            ),
+        ] {
+            let chunk = Parser::parse(Tokenizer::new(source)).unwrap();
+            assert!(
+                matches!(interpret(&chunk), Err(RuntimeErrorWithReason(it)) if it == error),
+                "{}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn interpret_to_json() {
+        let source =
+            "var a = [1, 2.5, \"say \\\"hi\\\"\"]; return to_json([nil, true, a, [a, []]]);";
+        let chunk = Parser::parse(Tokenizer::new(source)).unwrap();
+        assert_eq!(
+            interpret(&chunk).unwrap().to_string(),
+            r#"[null,true,[1,2.5,"say \"hi\""],[[1,2.5,"say \"hi\""],[]]]"#
+        );
+
+        for (source, error) in [
+            (
+                "var a = [1]; a[0] = [a]; return to_json(a);",
+                "Can't convert an array that contains itself to json",
+            ),
+            ("return to_json(clock);", "Can't convert functions to json"),
+            ("return to_json();", "Wrong number of arguments"),
+        ] {
+            let chunk = Parser::parse(Tokenizer::new(source)).unwrap();
+            assert!(
+                matches!(interpret(&chunk), Err(RuntimeErrorWithReason(it)) if it == error),
+                "{}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn interpret_from_json() {
+        // The json as a lox string literal
+        fn read(json: &str) -> Result<Returned, InterpretError> {
+            let json = json.replace('\\', "\\\\").replace('"', "\\\"");
+            let source = format!("return from_json(\"{}\");", json);
+            interpret(&Parser::parse(Tokenizer::new(&source)).unwrap())
+        }
+
+        for (json, expected) in [
+            ("null", Returned::Nil),
+            (" true ", Returned::Bool(true)),
+            ("false", Returned::Bool(false)),
+            ("-42", Returned::Int(-42)),
+            ("2.0", Returned::Number(2.0)),
+            ("1e3", Returned::Number(1000.0)),
+            ("-0.25E-2", Returned::Number(-0.0025)),
+            (
+                "9223372036854775808",
+                Returned::Number(9223372036854775808.0),
+            ),
+        ] {
+            assert_eq!(read(json).unwrap(), expected, "{}", json);
+        }
+        for (json, expected) in [
+            (r#""a\"b\\c\/\n\u00e9\ud83d\ude00""#, "a\"b\\c/\né😀"),
+            ("[]", "[]"),
+            (r#"[1, [2.5, "x"], [[]], null]"#, "[1, [2.5, x], [[]], nil]"),
+        ] {
+            assert_eq!(read(json).unwrap().to_string(), expected, "{}", json);
+        }
+
+        for (json, error) in [
+            ("", "Malformed json"),
+            ("[1,", "Malformed json"),
+            ("[1 2]", "Malformed json"),
+            ("[1,]", "Malformed json"),
+            ("[1] x", "Malformed json"),
+            ("tru", "Malformed json"),
+            ("01", "Malformed json"),
+            ("1.", "Malformed json"),
+            ("-", "Malformed json"),
+            ("+1", "Malformed json"),
+            (r#""open"#, "Malformed json"),
+            (r#""\x""#, "Malformed json"),
+            (r#""\ud800""#, "Malformed json"),
+            (r#""\u12g4""#, "Malformed json"),
+            ("\"tab\tin string\"", "Malformed json"),
+            (r#"{"a": 1}"#, "Lox has no maps to read json objects into"),
+            (&"[".repeat(1000), "Json nested too deep"),
+        ] {
+            assert!(
+                matches!(read(json), Err(RuntimeErrorWithReason(it)) if it == error),
+                "{}",
+                json
+            );
+        }
+        let chunk = Parser::parse(Tokenizer::new("return from_json(1);")).unwrap();
+        assert!(matches!(
+            interpret(&chunk),
+            Err(RuntimeErrorWithReason("Can only read json from a string"))
+        ));
+    }
+
+    #[test]
+    fn interpret_json_round_trip() {
+        let source = "
+            var a = [nil, true, false, 1, -2.5, 2.0, \"q\\\"\\n\\\\é\", [[], [3]]];
+            var json = to_json(a);
+            var b = from_json(json);
+            print json;
+            print b;
+            return to_json(b) == json;";
+        let chunk = Parser::parse(Tokenizer::new(source)).unwrap();
+        let mut output: Vec<u8> = Vec::new();
+        let mut vm = Vm::new(&chunk).with_output(&mut output);
+        assert_eq!(Returned::from(vm.run().unwrap()), Returned::Bool(true));
+        drop(vm);
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "[null,true,false,1,-2.5,2.0,\"q\\\"\\n\\\\é\",[[],[3]]]\n[nil, true, false, 1, -2.5, 2, q\"\n\\é, [[], [3]]]\n"
+        );
+    }
+
+    #[test]
+    fn interpret_interns_strings() {
+        let chunk = Parser::parse(Tokenizer::new(
+            "var a = \"ok\"; var b = \"o\" + \"k\"; return a == b and a == \"ok\";",
+        ))
+        .unwrap();
+        let mut vm = Vm::new(&chunk);
+
+        assert_eq!(vm.run().map(Returned::from).unwrap(), Returned::Bool(true));
+        // "ok", "o" and "k"
+        assert_eq!(vm.heap.size(), 3);
+        match (vm.globals.get("a"), vm.globals.get("b")) {
+            (Some(Object(a)), Some(Object(b))) => assert!(Rc::ptr_eq(a, b)),
+            it => panic!("Expected two strings, got {:?}", it),
+        }
+    }
+
+    #[test]
+    fn interpret_index_strings() {
+        interpret_result(vec![
+            ("return \"abc\"[0];", "a"),
+            ("return \"abc\"[-1];", "c"),
+            ("var s = \"héllo\"; return s[1] + s[-5];", "éh"),
+        ]);
+
+        for (source, error) in [
+            ("return \"abc\"[-4];", "Index out of range"),
+            ("return \"abc\"[3];", "Index out of range"),
+            ("return \"abc\"[0.5];", "Index must be a whole number"),
+            ("return 12[0];", "Can only index strings and arrays"),
+        ] {
+            let chunk = Parser::parse(Tokenizer::new(source)).unwrap();
+            assert!(
+                matches!(interpret(&chunk), Err(RuntimeErrorWithReason(it)) if it == error),
+                "{}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn interpret_arrays() {
+        interpret_result(vec![
+            ("return [1, 2, 3][1];", Returned::Int(2)),
+            (
+                "var a = [1, \"b\", [true]]; return a[-1][0];",
+                Returned::Bool(true),
+            ),
+            ("var a = [1, 2]; return a[0] = 5;", Returned::Int(5)),
+            (
+                "{ var a = [1, 2]; a[1] = a[0] + 2; return a[1]; }",
+                Returned::Int(3),
+            ),
+            (
+                "var a = [1]; var b = a; b[0] = 2; return a[0];",
+                Returned::Int(2),
+            ),
+        ]);
+
+        let chunk =
+            Parser::parse(Tokenizer::new("var a = [1, 2, 3]; a[0] = 5; return a;")).unwrap();
+        let it = interpret(&chunk).unwrap();
+        assert_eq!(it.to_string(), "[5, 2, 3]");
+        assert!(matches!(it, Returned::Object(Obj::Array { .. })));
+
+        // Arrays containing themselves, compared by identity and printed with a placeholder
+        interpret_result(vec![
+            ("var a = [1]; a[0] = a; return a[0] == a;", true),
+            ("var a = [1]; var b = [1]; return a == b;", false),
+        ]);
+        let source = "var a = [1, 2]; var b = [a]; a[1] = b; a[0] = a; print a; return b;";
+        let chunk = Parser::parse(Tokenizer::new(source)).unwrap();
+        let mut output: Vec<u8> = Vec::new();
+        let mut vm = Vm::new(&chunk).with_output(&mut output);
+        let it = vm.run().unwrap();
+        assert_eq!(format!("{:?}", it), "Object(Array([[[...], [...]]]))");
+        drop(vm);
+        assert_eq!(output, b"[[...], [[...]]]\n");
+
+        for (source, error) in [
+            ("return [1, 2][2];", "Index out of range"),
+            ("var a = []; a[0] = 1;", "Index out of range"),
+            (
+                "var s = \"ab\"; s[0] = \"c\";",
+                "Can only set elements of arrays",
+            ),
+        ] {
+            let chunk = Parser::parse(Tokenizer::new(source)).unwrap();
+            assert!(
+                matches!(interpret(&chunk), Err(RuntimeErrorWithReason(it)) if it == error),
+                "{}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn interpret_string_search_natives() {
+        interpret_result(vec![
+            ("return contains(\"hello\", \"ell\");", Returned::Bool(true)),
+            (
+                "return contains(\"hello\", \"elk\");",
+                Returned::Bool(false),
+            ),
+            ("return index_of(\"héllo\", \"l\");", Returned::Int(2)),
+            ("return index_of(\"hello\", \"z\");", Returned::Int(-1)),
+        ]);
 
-                Constant => {
-                    let x = self.read_constant()?;
-                    self.push_stack(x)
-                }
+        for source in ["return contains(1, \"a\");", "return index_of(\"a\", nil);"] {
+            let chunk = Parser::parse(Tokenizer::new(source)).unwrap();
+            assert!(matches!(
+                interpret(&chunk),
+                Err(RuntimeErrorWithReason("Can only search in strings"))
+            ));
+        }
+        let chunk = Parser::parse(Tokenizer::new("return contains(\"a\");")).unwrap();
+        assert!(matches!(
+            interpret(&chunk),
+            Err(RuntimeErrorWithReason("Wrong number of arguments"))
+        ));
+    }
 
-                // bindings
-                DefineGlobal => {
-                    let name = self.read_global_name()?;
-                    let value = self.pop_stack()?;
-                    self.globals.insert(name, value);
-                }
+    #[test]
+    fn print_to_handler() {
+        let chunk = Parser::parse(Tokenizer::new(
+            "print 1 + 2; print \"a\" + \"b\"; print nil;",
+        ))
+        .unwrap();
+        let mut printed = Vec::new();
+        let mut vm = Vm::new(&chunk);
+        vm.on_print(Box::new(|it| printed.push(Returned::from(it.clone()))));
+        vm.run().unwrap();
+        drop(vm);
+
+        assert_eq!(
+            printed,
+            vec![Returned::Int(3), Returned::from("ab"), Returned::Nil]
+        );
+    }
 
-                GetGlobal => {
-                    let name = self.read_global_name()?;
-                    let value = self.globals.get(&name).unwrap_or(&Value::Nil);
-                    self.push_stack(value.clone())
-                }
+    #[test]
+    fn print_literals_like_lox() {
+        for (source, expected) in [
+            ("print true;", "true\n"),
+            ("print false;", "false\n"),
+            ("print nil;", "nil\n"),
+            ("print 1 == 1;", "true\n"),
+        ] {
+            let chunk = Parser::parse(Tokenizer::new(source)).unwrap();
+            let mut output: Vec<u8> = Vec::new();
+            let mut vm = Vm::new(&chunk).with_output(&mut output);
+            vm.run().unwrap();
+            drop(vm);
 
-                SetGlobal => {
-                    let name = self.read_global_name()?;
-                    // we dont pop from the stack according to the book
-                    // that seems odd so we dont
-                    // => We dont because this is an expression statement which will auto pop the stack
-                    let value = self.peek_stack(0).ok_or(StackUnderflowError)?.clone();
-                    if let std::collections::hash_map::Entry::Occupied(mut e) =
-                        self.globals.entry(name)
-                    {
-                        e.insert(value);
-                    } else {
-                        Err(RuntimeErrorWithReason("Global is not defined"))?
-                    }
-                }
+            assert_eq!(output, expected.as_bytes(), "{}", source);
+        }
+    }
 
-                GetLocal => {
-                    // next byte contains the local_var_offset
-                    let at = self.read_byte().ok_or(RuntimeError)?;
-                    let value = self.stack.get(at as usize).ok_or(RuntimeErrorWithReason(
-                        "Local variable value could not be found",
-                    ))?;
-                    self.push_stack(value.clone());
-                }
+    #[test]
+    fn print_to_output() {
+        let chunk = Parser::parse(Tokenizer::new("print 1 + 2; print \"a\" + \"b\";")).unwrap();
+        let mut output: Vec<u8> = Vec::new();
+        let mut vm = Vm::new(&chunk).with_output(&mut output);
+        vm.run().unwrap();
+        drop(vm);
+
+        assert_eq!(std::string::String::from_utf8(output).unwrap(), "3\nab\n");
+    }
 
-                SetLocal => {
-                    // next byte contains the local_var_offset
-                    let at = self.read_byte().ok_or(RuntimeError)?;
-                    // According to the book, we should just peek the stack to not modify if but
-                    // then our stack just keeps growing so better pop it.
-                    // => We dont because this is an expression statement which will auto pop the stack
-                    let value = self.peek_stack(0).ok_or(StackUnderflowError)?;
-                    self.stack.set(at as usize, value.clone());
-                }
+    #[test]
+    fn eprint_to_error_output() {
+        let chunk = Parser::parse(Tokenizer::new("eprint \"oops\"; eprint 1 + 2;")).unwrap();
+        let mut errors: Vec<u8> = Vec::new();
+        let mut printed = Vec::new();
+        let mut vm = Vm::new(&chunk).with_error_output(&mut errors);
+        vm.on_print(Box::new(|it| printed.push(Returned::from(it.clone()))));
+        vm.run().unwrap();
+        drop(vm);
+
+        assert_eq!(std::string::String::from_utf8(errors).unwrap(), "oops\n3\n");
+        assert!(printed.is_empty());
+    }
 
-                // statements
-                Print => {
-                    self.print()?;
-                }
-                Pop => {
-                    self.pop_stack()?;
-                }
-                // control flow
-                JumpIfFalse => {
-                    // Always read the jump as it will update the ip past the Jump bytes
-                    // which we need if we dont jump so the next instruction to fetch
-                    // on true if the on true block
-                    let distance = self.read_jump().ok_or(RuntimeError)?;
-                    if !self.peek_stack(0).ok_or(StackUnderflowError)?.is_truthy() {
-                        self.jump_forward(distance)
-                    }
-                }
-                JumpIfTrue => {
-                    // Always read the jump as it will update the ip past the Jump bytes
-                    // which we need if we dont jump so the next instruction to fetch
-                    // on false if the on false block
-                    let distance = self.read_jump().ok_or(RuntimeError)?;
-                    if self.peek_stack(0).ok_or(StackUnderflowError)?.is_truthy() {
-                        self.jump_forward(distance)
-                    }
-                }
+    #[test]
+    fn interpret_function_call_errors() {
+        for (source, reason) in [
+            (
+                "fun add(a, b) { return a + b; } return add(1);",
+                "Wrong number of arguments",
+            ),
+            (
+                "fun loop() { return loop(); } return loop();",
+                "Stack overflow",
+            ),
+            (
+                "var x = 1; return x();",
+                "Can only call functions and classes",
+            ),
+        ] {
+            let chunk = Parser::parse(Tokenizer::new(source)).unwrap();
+            assert!(
+                matches!(interpret(&chunk), Err(RuntimeErrorWithReason(it)) if it == reason),
+                "{}",
+                source
+            );
+        }
+    }
 
-                Jump => {
-                    let distance = self.read_jump().ok_or(RuntimeError)?;
-                    self.jump_forward(distance)
-                }
+    #[test]
+    fn interpret_tail_calls() {
+        let options = ParserOptions {
+            tail_calls: true,
+            ..Default::default()
+        };
+        let source = "fun countdown(n, acc) {
+                 if (n == 0) return acc;
+                 var next = n - 1;
+                 return countdown(next, acc + 1);
+             }
+             return countdown(1000, 0);";
+
+        let chunk = Parser::parse(Tokenizer::new(source)).unwrap();
+        assert!(matches!(
+            interpret(&chunk),
+            Err(RuntimeErrorWithReason("Stack overflow"))
+        ));
+        let chunk = Parser::parse_with_options(Tokenizer::new(source), options).unwrap();
+        assert_eq!(interpret(&chunk).unwrap(), Returned::Int(1000));
+
+        // Not in tail position, the frame is still needed for the addition
+        let source = "fun sum(n) { if (n == 0) return 0; return n + sum(n - 1); } print sum(100);";
+        let chunk = Parser::parse_with_options(Tokenizer::new(source), options).unwrap();
+        assert!(!chunk.disassemble_into_string("sum").contains("Tail call"));
+        assert!(matches!(
+            interpret(&chunk),
+            Err(RuntimeErrorWithReason("Stack overflow"))
+        ));
+
+        // Natives and the script itself have no frame to reuse
+        for (source, expected) in [
+            (
+                "fun f() { return contains(\"ab\", \"b\"); } return f();",
+                Returned::Bool(true),
+            ),
+            (
+                "fun id(x) { return x; } fun g() { return id(2); } return id(g());",
+                Returned::Int(2),
+            ),
+            (
+                "fun f(a) { return a or f(true); } return f(false);",
+                Returned::Bool(true),
+            ),
+        ] {
+            let chunk = Parser::parse_with_options(Tokenizer::new(source), options).unwrap();
+            assert_eq!(interpret(&chunk).unwrap(), expected, "{}", source);
+        }
+    }
 
-                Loop => {
-                    let distance = self.read_jump().ok_or(RuntimeError)?;
-                    self.jump_backward(distance)
-                }
+    #[test]
+    fn interpret_fun_declaration() {
+        let chunk = Parser::parse(Tokenizer::new(
+            "fun add(a, b) { return a + b; } return add;",
+        ))
+        .unwrap();
+        let result = interpret(&chunk).unwrap();
+
+        assert_eq!(
+            result,
+            Returned::Function {
+                name: "add".to_string(),
+                arity: 2
             }
-        }
+        );
+        assert_eq!(result.to_string(), "<fn add>");
+
+        interpret_result(vec![(
+            "fun twice(x) { return x * 2; } fun pick() { return twice; } return pick();",
+            Returned::Function {
+                name: "twice".to_string(),
+                arity: 1,
+            },
+        )]);
     }
 
-    fn string_concatenate(&mut self) -> Result<(), InterpretError> {
-        let rhs = self.pop_stack()?;
-        let lhs = self.pop_stack()?;
-        let it = self.heap.alloc(Obj::String {
-            str: lhs.as_string().to_string() + rhs.as_string(),
-        });
-        self.push_stack(Object(it));
-        Ok(())
+    #[test]
+    fn interpret_compound_assignment() {
+        interpret_result(vec![
+            ("var x = 5; x += 3; return x;", Returned::Int(8)),
+            ("var x = 5; x -= 3; return x;", Returned::Int(2)),
+            ("var x = 5; x *= 3; return x;", Returned::Int(15)),
+            ("var x = 6; x /= 3; return x;", Returned::Number(2.0)),
+            ("var x = 5; x += 1 + 2 * 3; return x;", Returned::Int(12)),
+            ("{ var x = 5; x += 3; return x; }", Returned::Int(8)),
+        ]);
     }
 
-    fn read_decode(&mut self) -> Result<OpCode, InterpretError> {
-        // No more codes to fetch... runtime error
-        let byte = self.read_byte().ok_or(RuntimeError)?;
-        // Byte is not an opcode... runtime error
-        let code = OpCode::try_from(byte).map_err(|_| RuntimeError)?;
+    #[test]
+    fn interpret_int_divide() {
+        interpret_result(vec![
+            ("return 7 div 2;", 3),
+            ("return -7 div 2;", -4),
+            ("return -7 div 2 * 2;", -8),
+            ("return 1 - -7 div 2;", 5),
+            ("return 1 + 9 div 2 * 2;", 9),
+        ]);
+        let chunk = Parser::parse(Tokenizer::new("return 7 div 0;")).unwrap();
+        assert!(matches!(
+            interpret(&chunk),
+            Err(RuntimeErrorWithReason("Division by zero"))
+        ));
+    }
 
-        // This is ugly, because read_byte advances the ip, we need to put it back
-        // for the disassemble instruction
-        self.chunk.disassemble_instruction(byte, self.ip - 1);
+    #[test]
+    fn interpret_ints() {
+        let chunk = Parser::parse(Tokenizer::new("return 10;")).unwrap();
+        let it = interpret(&chunk).unwrap();
+        assert_eq!(it, Returned::Int(10));
+        assert_eq!(it.to_string(), "10");
 
-        Ok(code)
+        interpret_result(vec![
+            ("return 10 / 4;", Returned::Number(2.5)),
+            ("return 10 / 5;", Returned::Number(2.0)),
+            ("return 1 + 0.5;", Returned::Number(1.5)),
+            ("return 7 div 2.0;", Returned::Number(3.0)),
+            ("return 7 div -2;", Returned::Int(-4)),
+            ("return 2 * -3;", Returned::Int(-6)),
+            ("return 1 == 1.0;", Returned::Bool(true)),
+            ("return 2 < 2.5;", Returned::Bool(true)),
+            ("return !0;", Returned::Bool(true)),
+            ("return 99999999999999999999;", Returned::Number(1e20)),
+        ]);
+
+        let chunk = Parser::parse(Tokenizer::new("return 9223372036854775807 + 1;")).unwrap();
+        assert!(matches!(
+            interpret(&chunk),
+            Err(RuntimeErrorWithReason("Integer overflow"))
+        ));
     }
 
-    fn print(&mut self) -> Result<(), InterpretError> {
-        // According to the book: `Print is a statement so does not modify the stack`
-        // But that means our stack just keeps growing?
-        // Lets just pop the argument to print so that after print our
-        // stack is back where it was so
-        // our program exists correctly with an empty stack
-        let it = self.pop_stack()?;
-        println!("PRINTED: {:?}", &it);
-        Ok(())
+    #[test]
+    fn interpret_string_comparison() {
+        interpret_result(vec![
+            ("return \"a\" < \"b\";", true),
+            ("return \"b\" < \"a\";", false),
+            ("return \"b\" > \"a\";", true),
+            ("return \"ab\" > \"a\";", true),
+            ("return \"a\" <= \"a\";", true),
+            ("return \"B\" >= \"a\";", false),
+        ]);
+        let chunk = Parser::parse(Tokenizer::new("return \"a\" < 1;")).unwrap();
+        assert!(matches!(
+            interpret(&chunk),
+            Err(RuntimeErrorWithReason(
+                "Operands must be two numbers or two strings"
+            ))
+        ));
     }
 
-    fn jump_forward(&mut self, jump: Jump) {
-        self.ip += jump.distance as usize;
+    #[test]
+    fn interpret_division_by_zero() {
+        for source in [
+            "return 1 / 0;",
+            "return 1 / -0;",
+            "return 0 / 0.0;",
+            "return 5 % 0;",
+            "return 5 div 0;",
+        ] {
+            let chunk = Parser::parse(Tokenizer::new(source)).unwrap();
+            assert!(
+                matches!(
+                    interpret(&chunk),
+                    Err(RuntimeErrorWithReason("Division by zero"))
+                ),
+                "{}",
+                source
+            );
+        }
+        interpret_result(vec![("return 0 / 1;", 0.0)]);
+    }
+
+    #[test]
+    fn interpret_break() {
+        interpret_result(vec![
+            ("var i=0; while(true){ i=i+1; if(i>=3) break; } return i;", 3),
+            ("var i = 0; for (;;) { var step = 2; i = i + step; if (i > 5) break; } return i;", 6),
+            ("var n = 0; for (var i = 0; i < 3; i = i + 1) { while (true) break; n = n + 1; } return n;", 3),
+        ]);
     }
 
-    fn jump_backward(&mut self, jump: Jump) {
-        self.ip -= jump.distance as usize;
+    #[test]
+    fn interpret_equality() {
+        interpret_result(vec![
+            ("return \"ab\" == \"a\" + \"b\";", true),
+            ("return \"ab\" != \"ab\";", false),
+            ("return 1 == 1;", true),
+            ("return nil == false;", false),
+            ("fun f() {} var g = f; return f == g;", true),
+            // Functions are objects, the same body does not make them equal
+            ("fun f() {} fun g() {} return f == g;", false),
+            ("return clock == clock;", true),
+        ]);
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::opcode::Value::Nil;
-    use crate::parser::Parser;
-    use crate::tokenizer::Tokenizer;
+    #[test]
+    fn interpret_continue() {
+        interpret_result(vec![
+            ("var sum = 0; for (var i = 0; i < 10; i = i + 1) { if (i % 2 == 1) continue; sum = sum + i; } return sum;", 20),
+            ("var sum = 0; var i = 0; while (i < 10) { var odd = i % 2 == 1; i = i + 1; if (odd) continue; sum = sum + i - 1; } return sum;", 20),
+        ]);
+    }
 
     #[test]
-    fn interpret_math_expression_with_precedence() {
-        interpret_result(vec![("return 10 + 30 * 2;", 70.0)]);
+    fn expression_statements_leave_the_stack_empty() {
+        let chunk = Parser::parse(Tokenizer::new("1 + 2; \"a\"; var x = 3; x * 2;")).unwrap();
+        let mut vm = Vm::new(&chunk);
+
+        assert_eq!(vm.run().unwrap(), Nil);
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn interpret_modulo() {
+        interpret_result(vec![
+            ("return 10 % 3;", Returned::Int(1)),
+            ("return 2 + 10 % 3 * 2;", Returned::Int(4)),
+            ("return -7 % 3;", Returned::Int(2)),
+            ("return 7 % -3;", Returned::Int(-2)),
+            ("return -7 div 3 * 3 + -7 % 3;", Returned::Int(-7)),
+            ("return (-9223372036854775807 - 1) % -1;", Returned::Int(0)),
+            ("return 7.5 % 2;", Returned::Number(1.5)),
+            ("return -7.5 % 2;", Returned::Number(0.5)),
+        ]);
     }
 
     #[test]
@@ -496,6 +1936,39 @@ mod tests {
         ])
     }
 
+    #[test]
+    fn interpret_and_or_precedence() {
+        for a in [true, false] {
+            for b in [true, false] {
+                for c in [true, false] {
+                    interpret_result(vec![
+                        (
+                            format!("return {} or {} and {};", a, b, c).as_str(),
+                            a || (b && c),
+                        ),
+                        (
+                            format!("return {} and {} or {};", a, b, c).as_str(),
+                            (a && b) || c,
+                        ),
+                    ]);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn interpret_comma_operator() {
+        interpret_result(vec![
+            ("return (1, 2, 3);", 3),
+            ("var x = 1; return (x = 5, x + 1);", 6),
+        ]);
+
+        let chunk = Parser::parse(Tokenizer::new("return (1, 2, 3);")).unwrap();
+        let mut vm = Vm::new(&chunk);
+        assert_eq!(vm.run().unwrap(), Int(3));
+        assert!(vm.stack.is_empty());
+    }
+
     #[test]
     fn interpret_expression() {
         interpret_result(vec![("return !(5 - 4 > 3 * 2 == !nil);", true)])
@@ -506,6 +1979,26 @@ mod tests {
         interpret_result(vec![("return \"hello world\";", "hello world")]);
     }
 
+    #[test]
+    fn interpret_chars() {
+        interpret_result(vec![
+            ("return 'A';", 65),
+            ("return '\\n';", 10),
+            ("return '\\'';", 39),
+            ("return 'é';", 233),
+        ]);
+        interpret_result(vec![("return 'a' < 'b';", true)]);
+    }
+
+    #[test]
+    fn interpret_string_escapes() {
+        interpret_result(vec![
+            ("return \"a\\nb\";", "a\nb"),
+            ("return \"quote\\\"here\";", "quote\"here"),
+            ("return \"\\t\\r\\\\\";", "\t\r\\"),
+        ]);
+    }
+
     #[test]
     fn interpret_string_equality() {
         interpret_result(vec![
@@ -526,7 +2019,7 @@ mod tests {
 
     #[test]
     fn interpret_print_statement() {
-        interpret_result(vec![("return 5 + 2;", 7.0)]);
+        interpret_result(vec![("return 5 + 2;", 7)]);
 
         interpret_result(vec![
             ("return 5 > 2;", true),
@@ -546,24 +2039,98 @@ mod tests {
         interpret_result(vec![
             (
                 "var summed = 5 + 2; print summed *2; return summed * 2;",
-                14.0,
+                Returned::Int(14),
             ),
             (
                 "var a; var b; var c; a = 5; b = 2; c = a + b; print c *2; return c * 2;",
-                14.0,
+                Returned::Int(14),
+            ),
+        ]);
+    }
+
+    #[test]
+    fn interpret_without_return() {
+        interpret_result(vec![
+            ("var a = 5; var b = 2; print a + b;", Returned::Nil),
+            (
+                "var a = 5; { var b = a * 2; a = b; } print a; a = a + 1;",
+                Returned::Nil,
             ),
+            ("", Returned::Nil),
         ]);
     }
 
     #[test]
-    fn interpret_unknown_globals_are_nil() {
-        // @TODO treat as runtime error instead
-        interpret_result(vec![("return unknown;", Value::Nil)]);
+    fn interpret_unknown_globals_are_errors() {
+        let chunk = Parser::parse(Tokenizer::new("return unknown;")).unwrap();
+        let it = interpret(&chunk);
+        assert!(matches!(&it, Err(InterpretError::UndefinedVariable(name)) if name == "unknown"));
+        assert_eq!(
+            it.unwrap_err().to_string(),
+            "runtime error: Undefined variable 'unknown'"
+        );
+    }
+
+    #[test]
+    fn interpret_cached_global_sees_reassignment() {
+        interpret_result(vec![(
+            "var step = 1; var total = 0; var i = 0;
+             while (i < 6) { total = total + step; if (i == 2) step = 10; i = i + 1; }
+             return total;",
+            33,
+        )]);
+    }
+
+    #[test]
+    fn interpret_global_slots() {
+        let options = ParserOptions {
+            global_slots: true,
+            ..Default::default()
+        };
+        let parse = |source| Parser::parse_with_options(Tokenizer::new(source), options).unwrap();
+
+        // A tight loop on globals, none of them looked up by name
+        let chunk = parse(
+            "var total = 0; var step = 2; var i = 0;
+             while (i < 10000) { total = total + step; i = i + 1; }
+             fun twice() { return total * 2; }
+             return twice() + clock() * 0;",
+        );
+        let code = chunk.disassemble_into_string("slots");
+        assert!(code.contains("Global slot get \"total\""));
+        assert!(!code.contains("Global get") && !code.contains("Global set"));
+        assert_eq!(interpret(&chunk).unwrap(), Returned::Number(40000.0));
+
+        let it = interpret(&parse("return unknown;"));
+        assert!(matches!(it, Err(InterpretError::UndefinedVariable(name)) if name == "unknown"));
+
+        // The slots are linked again for globals of another run
+        let define = Parser::parse(Tokenizer::new("var x = 20;")).unwrap();
+        let read = parse("x = x + 1; return x;");
+        for _ in 0..2 {
+            let mut vm = Vm::new(&define);
+            vm.run().unwrap();
+            let environment = vm.into_environment();
+            let mut vm = Vm::new(&read).with_environment(environment);
+            assert_eq!(vm.run().map(Returned::from).unwrap(), Returned::Int(21));
+        }
+
+        // Slot operands are a single byte
+        let source = (0..257)
+            .map(|i| format!("var g{} = {};", i, i))
+            .collect::<String>();
+        let it = Parser::parse_with_options(Tokenizer::new(&source), options);
+        assert!(matches!(
+            it,
+            Err(InterpretError::CompileError(
+                CompilationErrorReason::TooManyGlobalSlots
+            ))
+        ));
     }
 
     #[test]
     fn interpret_set_global() {
-        interpret_result(vec![("var it; it = 3 + 5; return it;", 8.0)]);
+        interpret_result(vec![("var it; it = 3 + 5; return it;", 8)]);
     }
 
     #[test]
@@ -609,14 +2176,14 @@ mod tests {
     }
     #[test]
     fn interpret_block_statements_5() {
-        interpret_result(vec![("var x; { x = 10; var y = 20; } return x;", 10.0)]);
+        interpret_result(vec![("var x; { x = 10; var y = 20; } return x;", 10)]);
     }
 
     #[test]
     fn interpret_block_statements_6() {
         interpret_result(vec![(
             "var z; { var x; var y; x = 10; y = 20; z = x + y; } return z;",
-            30.0,
+            30,
         )]);
     }
 
@@ -632,7 +2199,7 @@ mod tests {
     fn interpret_if_statement_true() {
         interpret_result(vec![(
             "var z = 2; if (true) { var x = 3; var y = 5; z = x + y; } return z;",
-            8.0,
+            8,
         )]);
     }
 
@@ -640,7 +2207,7 @@ mod tests {
     fn interpret_if_statement_false() {
         interpret_result(vec![(
             "var z = 2; if (false) { var x = 3; var y = 5; z = x + y; } return z;",
-            2.0,
+            2,
         )]);
     }
 
@@ -648,7 +2215,7 @@ mod tests {
     fn interpret_if_else_statement_true() {
         interpret_result(vec![(
             "var z = 2; if (true) { var x = 3; var y = 5; z = x + y; } else { z = 200; }return z;",
-            8.0,
+            8,
         )]);
     }
 
@@ -656,7 +2223,7 @@ mod tests {
     fn interpret_if_else_statement_false() {
         interpret_result(vec![(
             "var z = 2; if (false) { var x = 3; var y = 5; z = x + y; } else { z = 200; }return z;",
-            200.0,
+            200,
         )]);
     }
 
@@ -664,7 +2231,7 @@ mod tests {
     fn interpret_if_else_statement_false_2() {
         interpret_result(vec![(
             "if (false){ var x = 3; var y = 5; } else { var y = 100; } var x = 5; return x +2;",
-            7.0,
+            7,
         )]);
     }
 
@@ -672,7 +2239,7 @@ mod tests {
     fn interpret_while_loop() {
         interpret_result(vec![(
             "var x = 0; var y = 3; while (y > 0) { y = y - 1; x = x + 1; } return x;",
-            3.0,
+            3,
         )]);
 
         interpret_result(vec![
@@ -682,25 +2249,464 @@ mod tests {
 
         interpret_result(vec![(
             "var x = \"hi\"; var y = 3; while (y > 0) { y = y - 1; x = x + \"hi\"; } return x;",
-            "hihihihi",
+            Returned::from("hihihihi"),
+        )])
+    }
+
+    #[test]
+    fn interpret_concatenate_in_long_loop() {
+        let expected = "hi".repeat(1001);
+        interpret_result(vec![(
+            "var x = \"hi\"; var y = 1000; while (y > 0) { y = y - 1; x = x + \"hi\"; } return x;",
+            expected.as_str(),
         )])
     }
 
+    #[test]
+    fn interpret_for_loop_without_block() {
+        interpret_result(vec![
+            (
+                "for (var i=0;i<3;i=i+1) print i; return 1;",
+                Returned::Int(1),
+            ),
+            (
+                "var x = 0; for (var i = 0; i < 3; i = i + 1) x = x + i; return x;",
+                Returned::Int(3),
+            ),
+            // The loop variable is gone, the locals after it get their own slots
+            (
+                "for (var i = 0; i < 3; i = i + 1) print i; { var y = 7; return y; }",
+                Returned::Int(7),
+            ),
+            (
+                "{ for (var i = 0; i < 3; i = i + 1) if (i == 1) break; var y = 7; return y; }",
+                Returned::Int(7),
+            ),
+        ])
+    }
+
+    #[test]
+    fn interpret_while_loop_without_block() {
+        interpret_result(vec![
+            (
+                "var x = 0; while (x < 3) x = x + 1; return x;",
+                Returned::Int(3),
+            ),
+            (
+                "var x = 3; while (x > 0) print x = x - 1; return x;",
+                Returned::Int(0),
+            ),
+            (
+                "var y = 0; { var x = 0; while (x < 3) x = x + 1; y = x; } return y;",
+                Returned::Int(3),
+            ),
+        ]);
+
+        // Both bodies leave the stack as they found it
+        for source in [
+            "{ var x = 0; while (x < 3) x = x + 1; return x; }",
+            "{ var x = 0; while (x < 3) { x = x + 1; } return x; }",
+        ] {
+            let chunk = Parser::parse(Tokenizer::new(source)).unwrap();
+            let mut vm = Vm::new(&chunk);
+            assert_eq!(vm.run().unwrap(), Int(3));
+            assert_eq!(vm.stack.values(), &[Int(3)], "{}", source);
+        }
+    }
+
     #[test]
     fn interpret_for_loop() {
         interpret_result(vec![
             (
                 "var x = 0; for (var i = 0; i < 10; i = i + 1) { x = x + 1; } return x;",
-                10.0,
+                10,
             ),
-            ("var x = 0; for (; x < 10;) { x = x + 1; } return x;", 10.0),
+            ("var x = 0; for (; x < 10;) { x = x + 1; } return x;", 10),
             (
                 "var x = 0; for (;;) { x = x + 1; if (x >= 10) return x; } return x;",
-                10.0,
+                10,
             ),
         ])
     }
 
+    #[test]
+    fn step_single_instructions() {
+        let chunk = Parser::parse(Tokenizer::new("return 1 + 2;")).unwrap();
+        let mut vm = Vm::new(&chunk);
+
+        assert_eq!(vm.step().unwrap(), StepOutcome::Continue { ip: 2, line: 0 });
+        assert_eq!(vm.step().unwrap(), StepOutcome::Continue { ip: 4, line: 0 });
+        assert_eq!(vm.step().unwrap(), StepOutcome::Continue { ip: 5, line: 0 });
+        assert_eq!(vm.step().unwrap(), StepOutcome::Done(Int(3)));
+    }
+
+    #[test]
+    fn step_pauses_on_breakpoints() {
+        let source = "var i = 0;\nwhile (i < 3) {\n  i = i + 1;\n}\nreturn i;";
+        let chunk = Parser::parse(Tokenizer::new(source)).unwrap();
+        let mut vm = Vm::new(&chunk);
+        vm.set_breakpoint(2);
+
+        let mut hits = 0;
+        let result = loop {
+            match vm.step().unwrap() {
+                StepOutcome::Breakpoint { line, .. } => {
+                    assert_eq!(line, 2);
+                    hits += 1;
+                }
+                StepOutcome::Continue { .. } => (),
+                StepOutcome::Done(it) => break it,
+            }
+        };
+        assert_eq!(hits, 3);
+        assert_eq!(result, Int(3));
+    }
+
+    #[test]
+    fn step_clears_breakpoints() {
+        let source = "var i = 0;\ni = 1;\ni = 2;\nreturn i;";
+        let chunk = Parser::parse(Tokenizer::new(source)).unwrap();
+        let mut vm = Vm::new(&chunk);
+        vm.set_breakpoint(1);
+        vm.set_breakpoint(2);
+        vm.clear_breakpoint(1);
+
+        let mut lines = vec![];
+        while let Ok(outcome) = vm.step() {
+            match outcome {
+                StepOutcome::Breakpoint { line, .. } => {
+                    lines.push(line);
+                    vm.clear_breakpoints();
+                }
+                StepOutcome::Continue { .. } => (),
+                StepOutcome::Done(_) => break,
+            }
+        }
+        assert_eq!(lines, vec![2]);
+    }
+
+    #[test]
+    fn eval_against_current_state() {
+        let source = "var x = 7;\n{\n  var y = 2;\n  print y;\n}\nreturn x;";
+        let chunk = Parser::parse(Tokenizer::new(source)).unwrap();
+        let mut vm = Vm::new(&chunk);
+        vm.set_breakpoint(3);
+
+        while !matches!(vm.step().unwrap(), StepOutcome::Breakpoint { .. }) {}
+        assert_eq!(vm.eval("x * y").unwrap(), Returned::Int(14));
+        assert_eq!(vm.eval("x = y").unwrap(), Returned::Int(2));
+        assert!(matches!(
+            vm.eval("z"),
+            Err(InterpretError::UndefinedVariable(_))
+        ));
+
+        // The program did not notice
+        assert_eq!(vm.run().map(Returned::from).unwrap(), Returned::Int(7));
+        assert_eq!(vm.eval("x + 1").unwrap(), Returned::Int(8));
+        assert!(matches!(
+            vm.eval("y"),
+            Err(InterpretError::UndefinedVariable(_))
+        ));
+    }
+
+    #[test]
+    fn watch_variables_at_breakpoints() {
+        let source = "var total = 10;\n{\n  var i = 0;\n  while (i < 3) {\n    i = i + 1;\n  }\n}\nreturn total;";
+        let chunk = Parser::parse(Tokenizer::new(source)).unwrap();
+        let mut vm = Vm::new(&chunk);
+        vm.set_breakpoint(4);
+
+        let mut watched = vec![];
+        while let Ok(outcome) = vm.step() {
+            match outcome {
+                StepOutcome::Breakpoint { .. } => {
+                    watched.push(vm.watch("i"));
+                    assert_eq!(vm.watch("total"), Some(Returned::Int(10)));
+                    assert_eq!(vm.watch("unknown"), None);
+                }
+                StepOutcome::Continue { .. } => (),
+                StepOutcome::Done(_) => break,
+            }
+        }
+        assert_eq!(
+            watched,
+            vec![
+                Some(Returned::Int(0)),
+                Some(Returned::Int(1)),
+                Some(Returned::Int(2))
+            ]
+        );
+        // Out of scope once the block is done
+        assert_eq!(vm.watch("i"), None);
+    }
+
+    #[test]
+    fn interpret_rejects_malformed_chunks() {
+        let mut chunk = Chunk::new();
+        chunk.push_raw_byte(OpCode::Return as Byte + 1, 0);
+        assert!(matches!(interpret(&chunk), Err(RuntimeError)));
+
+        // Jumps past the end of the code
+        let mut chunk = Chunk::new();
+        chunk.push_raw_byte(OpCode::Jump as Byte, 0);
+        chunk.push_raw_byte(0xff, 0);
+        chunk.push_raw_byte(0xff, 0);
+        assert!(matches!(interpret(&chunk), Err(RuntimeError)));
+    }
+
+    #[test]
+    fn interpret_long_constants() {
+        let mut chunk = Chunk::new();
+        for i in 0..299 {
+            chunk.write_constant(Number(i as f64), 0);
+            chunk.write_code(OpCode::Pop, 0);
+        }
+        chunk.write_constant(Int(299), 1);
+        chunk.write_code(OpCode::Return, 1);
+
+        assert!(chunk
+            .disassemble_into_string("long")
+            .ends_with("| ConstantLong 299\n     943        1 | Return\n"));
+        assert_eq!(interpret(&chunk).unwrap(), Returned::Int(299));
+
+        let source = format!(
+            "return 0{};",
+            (1..300).map(|i| format!(" + {}", i)).collect::<String>()
+        );
+        let chunk = Parser::parse(Tokenizer::new(&source)).unwrap();
+        assert_eq!(interpret(&chunk).unwrap(), Returned::Int(44850));
+
+        // Functions declared once the constants no longer fit a byte
+        let source = format!(
+            "{}\nfun f() {{ return 1; }}\nreturn f();",
+            source.replace("return", "print")
+        );
+        let chunk = Parser::parse(Tokenizer::new(&source)).unwrap();
+        assert!(chunk
+            .disassemble_into_string("long")
+            .contains("| FunctionLong <fn f>"));
+        assert_eq!(interpret(&chunk).unwrap(), Returned::Int(1));
+    }
+
+    #[test]
+    fn interpret_long_globals() {
+        let mut source = (0..300)
+            .map(|i| format!("var g{} = \"v{}\";\n", i, i))
+            .collect::<std::string::String>();
+        source.push_str("g299 = g299 + \"!\";\nreturn g299;");
+        let chunk = Parser::parse(Tokenizer::new(&source)).unwrap();
+
+        let output = chunk.disassemble_into_string("long");
+        assert!(output.contains("| StringLong \"v299\"\n"), "{}", output);
+        assert!(output.contains("| Global define long \"g299\"\n"));
+        assert!(output.contains("| Global set long \"g299\"\n"));
+        assert!(output.contains("| Global get long \"g299\"\n"));
+        assert_eq!(interpret(&chunk).unwrap(), Returned::from("v299!"));
+    }
+
+    #[test]
+    fn interpret_rejects_loops_out_of_bounds() {
+        let run = |distance: u16| {
+            let mut chunk = Chunk::new();
+            chunk.write_constant(Int(1), 0);
+            // At 2, the ip is at 5 once its operand is read
+            chunk.push_raw_byte(OpCode::Loop as Byte, 0);
+            let (higher, lower) = Jump { distance }.to_bytes();
+            chunk.push_raw_byte(higher, 0);
+            chunk.push_raw_byte(lower, 0);
+            chunk.write_code(OpCode::Return, 0);
+            interpret(&chunk)
+        };
+
+        // Before the start of the code
+        assert!(matches!(run(6), Err(InterpretError::JumpOutOfBounds)));
+        assert!(matches!(
+            run(u16::MAX),
+            Err(InterpretError::JumpOutOfBounds)
+        ));
+        // Into the operand of the constant
+        assert!(matches!(run(4), Err(InterpretError::JumpOutOfBounds)));
+        // Past the loop itself
+        assert!(matches!(run(1), Err(InterpretError::JumpOutOfBounds)));
+    }
+
+    #[test]
+    fn interpret_call_non_callable() {
+        for source in ["var f = 1; f();", "return \"f\"(1, 2);"] {
+            let chunk = Parser::parse(Tokenizer::new(source)).unwrap();
+            assert!(matches!(
+                interpret(&chunk),
+                Err(RuntimeErrorWithReason(
+                    "Can only call functions and classes"
+                ))
+            ));
+        }
+    }
+
+    #[test]
+    fn trace_stack_per_instruction() {
+        let chunk = Parser::parse(Tokenizer::new("return 1 + 2;")).unwrap();
+        let mut trace = Vec::new();
+        let mut vm = Vm::new(&chunk).with_trace_to(&mut trace);
+        assert_eq!(vm.run().unwrap(), Int(3));
+        drop(vm);
+
+        let expected = "       0        0 | Constant 1\n       0 | stack [ 1 ]\n       2        0 | Constant 2\n       2 | stack [ 1 ] [ 2 ]\n       4        0 | Add\n       4 | stack [ 3 ]\n       5        0 | Return\nReturn: 3\nGlobals: [ clock = <native fn> ] [ contains = <native fn> ] [ index_of = <native fn> ] [ map = <native fn> ] [ filter = <native fn> ] [ reduce = <native fn> ] [ to_json = <native fn> ] [ from_json = <native fn> ]\n";
+        assert_eq!(std::string::String::from_utf8(trace).unwrap(), expected);
+    }
+
+    #[test]
+    fn interpret_debug_keeps_stack_on_error() {
+        let chunk = Parser::parse(Tokenizer::new("var a = 1; return a + true;")).unwrap();
+        let (error, stack) = interpret_debug(&chunk).unwrap_err();
+
+        assert!(matches!(
+            error,
+            RuntimeErrorWithReason("Operands must be numbers")
+        ));
+        assert_eq!(stack, vec![Returned::Int(1), Returned::Bool(true)]);
+
+        let chunk = Parser::parse(Tokenizer::new("return 1 + 2;")).unwrap();
+        assert_eq!(interpret_debug(&chunk).unwrap(), Returned::Int(3));
+    }
+
+    #[test]
+    fn collect_preserving_globals_between_runs() {
+        let first = Parser::parse(Tokenizer::new("var greeting = \"hello\"; return nil;")).unwrap();
+        let second = Parser::parse(Tokenizer::new(
+            "var scratch = \"a\" + \"b\"; scratch = nil; return greeting;",
+        ))
+        .unwrap();
+
+        let mut vm = Vm::new(&first);
+        vm.run().unwrap();
+        vm.load(&second);
+        vm.run().unwrap();
+        assert_eq!(vm.heap.size(), 4);
+
+        vm.collect_preserving_globals();
+        assert_eq!(vm.heap.size(), 1);
+        assert_eq!(vm.watch("greeting"), Some(Returned::from("hello")));
+    }
+
+    #[test]
+    fn configure_trace_and_fuel() {
+        let config = VmConfig {
+            trace: true,
+            fuel: Some(100),
+        };
+        let chunk = Parser::parse(Tokenizer::new("while (true) {}")).unwrap();
+        let mut vm = Vm::with_config(&chunk, config);
+        assert!(vm.trace.is_some());
+        assert!(matches!(
+            vm.run(),
+            Err(RuntimeErrorWithReason("Out of fuel"))
+        ));
+
+        // Just enough for the 4 instructions
+        let chunk = Parser::parse(Tokenizer::new("return 1 + 2;")).unwrap();
+        let config = VmConfig {
+            fuel: Some(4),
+            ..Default::default()
+        };
+        let mut vm = Vm::with_config(&chunk, config);
+        assert!(vm.trace.is_none());
+        assert_eq!(vm.run().unwrap(), Int(3));
+        let mut vm = Vm::new(&chunk).configure(VmConfig {
+            fuel: Some(3),
+            ..config
+        });
+        assert!(matches!(
+            vm.run(),
+            Err(RuntimeErrorWithReason("Out of fuel"))
+        ));
+    }
+
+    #[test]
+    fn disassemble_only_when_tracing() {
+        let chunk = Parser::parse(Tokenizer::new("print 1 + 2;")).unwrap();
+
+        let (mut output, mut trace) = (Vec::new(), Vec::new());
+        let mut vm = Vm::new(&chunk)
+            .with_output(&mut output)
+            .with_trace_to(&mut trace);
+        vm.run().unwrap();
+        drop(vm);
+        let trace = std::string::String::from_utf8(trace).unwrap();
+        assert!(
+            trace.contains("       0        0 | Constant 1\n"),
+            "{}",
+            trace
+        );
+        assert!(trace.contains("       4        0 | Add\n"), "{}", trace);
+        assert!(trace.contains("Return: nil\n"), "{}", trace);
+        assert_eq!(output, b"3\n");
+
+        // Only what the program prints
+        let mut output = Vec::new();
+        let mut vm = Vm::new(&chunk).with_output(&mut output);
+        vm.run().unwrap();
+        drop(vm);
+        assert_eq!(output, b"3\n");
+    }
+
+    #[test]
+    fn trace_globals_when_done() {
+        let chunk = Parser::parse(Tokenizer::new("var answer = 42;")).unwrap();
+        let mut trace = Vec::new();
+        let mut vm = Vm::new(&chunk).with_trace_to(&mut trace);
+        vm.run().unwrap();
+        drop(vm);
+
+        let trace = std::string::String::from_utf8(trace).unwrap();
+        assert!(
+            trace.ends_with("[ from_json = <native fn> ] [ answer = 42 ]\n"),
+            "{}",
+            trace
+        );
+    }
+
+    #[test]
+    fn trace_heap_after_collecting() {
+        let chunk = Parser::parse(Tokenizer::new(
+            "var kept = \"a\" + \"b\"; var scratch = \"c\" + \"d\"; scratch = nil;",
+        ))
+        .unwrap();
+        let mut trace = Vec::new();
+        let mut vm = Vm::new(&chunk).with_trace_to(&mut trace);
+        vm.run().unwrap();
+        vm.collect_preserving_globals();
+        drop(vm);
+
+        let trace = std::string::String::from_utf8(trace).unwrap();
+        let bytes = estimated_size(&Obj::String { str: "ab".into() }) + 2 * size_of::<usize>();
+        assert!(
+            trace.ends_with(&format!(
+                "      gc | 1 objects, {} bytes, 6 allocations\n",
+                bytes
+            )),
+            "{}",
+            trace
+        );
+    }
+
+    #[test]
+    fn collect_garbage_while_running() {
+        let chunk = Parser::parse(Tokenizer::new(
+            "var kept = \"k\" + \"ept\"; var s = \"\"; var i = 0;
+             while (i < 2000) { s = s + \"x\"; i = i + 1; }
+             return kept;",
+        ))
+        .unwrap();
+
+        let mut vm = Vm::new(&chunk);
+        let it = vm.run().map(Returned::from).unwrap();
+        assert_eq!(it, Returned::from("kept"));
+        // Every iteration makes a string never seen before, only the last one is still in use
+        assert!(vm.heap.allocation_count() > GC_THRESHOLD);
+        assert!(vm.heap.size() <= GC_THRESHOLD, "{}", vm.heap.size());
+    }
+
     fn interpret_result<T>(cases: Vec<(&str, T)>)
     where
         Returned: From<T>,
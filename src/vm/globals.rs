@@ -0,0 +1,184 @@
+use crate::opcode::Value;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// Tells the globals apart, chunks remember which globals their slots are linked to
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Globals keep track of the global variables together with a generation
+/// that is bumped on every write so cached reads can detect they are stale.
+/// Every name gets a slot once, either looked up by name or by the slot directly.
+
+#[derive(Debug)]
+pub struct Globals {
+    id: u64,
+    slots: HashMap<String, usize>,
+    values: Vec<Option<Value>>, // None until defined
+    generation: u64,
+}
+
+impl Globals {
+    pub fn new() -> Self {
+        Self {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            slots: HashMap::new(),
+            values: Vec::new(),
+            generation: 0,
+        }
+    }
+
+    /// A copy to change without affecting these globals
+    pub fn snapshot(&self) -> Self {
+        Self {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            slots: self.slots.clone(),
+            values: self.values.clone(),
+            generation: 0,
+        }
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Value> {
+        self.slots.get(name).and_then(|it| self.get_slot(*it))
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &Value> {
+        self.values.iter().flatten()
+    }
+
+    /// The defined globals with their name, in the order they got their slot
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &Value)> {
+        let mut names = self.slots.iter().collect::<Vec<_>>();
+        names.sort_by_key(|(_, slot)| **slot);
+        names
+            .into_iter()
+            .filter_map(|(name, slot)| Some((name.as_str(), self.get_slot(*slot)?)))
+    }
+
+    /// Defines (or redefines) the global
+    pub fn insert(&mut self, name: String, value: Value) {
+        let slot = self.slot(&name);
+        self.define_slot(slot, value);
+    }
+
+    /// Overwrites an existing global, returns false if it has not been defined
+    pub fn set(&mut self, name: String, value: Value) -> bool {
+        match self.slots.get(&name) {
+            Some(it) => self.set_slot(*it, value),
+            None => false,
+        }
+    }
+
+    /// Where the global is stored, reserves a slot for globals not defined yet
+    pub fn slot(&mut self, name: &str) -> usize {
+        match self.slots.get(name) {
+            Some(it) => *it,
+            None => {
+                self.values.push(None);
+                self.slots.insert(name.to_string(), self.values.len() - 1);
+                self.values.len() - 1
+            }
+        }
+    }
+
+    pub fn get_slot(&self, slot: usize) -> Option<&Value> {
+        self.values.get(slot).and_then(|it| it.as_ref())
+    }
+
+    pub fn define_slot(&mut self, slot: usize, value: Value) {
+        self.generation += 1;
+        self.values[slot] = Some(value);
+    }
+
+    pub fn set_slot(&mut self, slot: usize, value: Value) -> bool {
+        match self.values.get_mut(slot) {
+            Some(Some(it)) => {
+                self.generation += 1;
+                *it = value;
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Inline cache for `GetGlobal`, one slot per instruction address.
+/// A slot is only valid as long as the globals generation did not change.
+
+#[derive(Debug)]
+pub struct GlobalCache(Vec<Option<(u64, Value)>>);
+
+impl GlobalCache {
+    pub fn new() -> Self {
+        GlobalCache(Vec::new())
+    }
+
+    pub fn get(&self, at: usize, generation: u64) -> Option<&Value> {
+        match self.0.get(at) {
+            Some(Some((cached, value))) if *cached == generation => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn insert(&mut self, at: usize, generation: u64, value: Value) {
+        if self.0.len() <= at {
+            self.0.resize(at + 1, None);
+        }
+        self.0[at] = Some((generation, value));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writes_bump_generation() {
+        let mut globals = Globals::new();
+        let start = globals.generation();
+
+        globals.insert("x".to_string(), Value::Number(1.0));
+        assert!(globals.generation() > start);
+
+        let defined = globals.generation();
+        assert!(globals.set("x".to_string(), Value::Number(2.0)));
+        assert!(globals.generation() > defined);
+
+        let set = globals.generation();
+        assert!(!globals.set("unknown".to_string(), Value::Nil));
+        assert_eq!(globals.generation(), set);
+    }
+
+    #[test]
+    fn slots_and_names_share_values() {
+        let mut globals = Globals::new();
+        let slot = globals.slot("x");
+        assert_eq!(globals.get("x"), None);
+        assert!(!globals.set_slot(slot, Value::Nil));
+
+        globals.define_slot(slot, Value::Number(1.0));
+        assert_eq!(globals.get("x"), Some(&Value::Number(1.0)));
+
+        assert!(globals.set("x".to_string(), Value::Number(2.0)));
+        assert_eq!(globals.get_slot(slot), Some(&Value::Number(2.0)));
+        assert_eq!(globals.slot("x"), slot);
+        assert_eq!(globals.values().count(), 1);
+    }
+
+    #[test]
+    fn cache_is_stale_after_generation_changes() {
+        let mut cache = GlobalCache::new();
+        cache.insert(5, 1, Value::Number(1.0));
+
+        assert_eq!(cache.get(5, 1), Some(&Value::Number(1.0)));
+        assert_eq!(cache.get(5, 2), None);
+        assert_eq!(cache.get(4, 1), None);
+    }
+}
@@ -0,0 +1,346 @@
+use crate::opcode::{Obj, Runtime, Value};
+use crate::vm::InterpretError;
+use crate::vm::InterpretError::RuntimeErrorWithReason;
+use std::cell::RefCell;
+use std::fmt::Write;
+use std::rc::Rc;
+
+// Json for the values lox has, arrays become json arrays. Lox has no maps so nothing becomes a
+// json object and reading one is an error.
+
+// Deeper arrays are rejected before reading them overflows the rust stack
+const MAX_DEPTH: usize = 256;
+
+pub fn to_json(value: &Value) -> Result<String, InterpretError> {
+    let mut json = String::new();
+    write_value(&mut json, value, &mut Vec::new())?;
+    Ok(json)
+}
+
+// `seen` are the arrays we are inside of, meeting one of them again means it contains itself
+fn write_value(
+    json: &mut String,
+    value: &Value,
+    seen: &mut Vec<*const Obj>,
+) -> Result<(), InterpretError> {
+    match value {
+        Value::Nil => json.push_str("null"),
+        Value::Bool(it) => json.push_str(if *it { "true" } else { "false" }),
+        Value::Int(it) => json.push_str(&it.to_string()),
+        // Debug keeps the `.0` of whole numbers, they read back as numbers and not as ints
+        Value::Number(it) if it.is_finite() => write!(json, "{:?}", it).unwrap(),
+        Value::Number(_) => Err(RuntimeErrorWithReason(
+            "Json has no infinite or NaN numbers",
+        ))?,
+        Value::Object(it) => match it.as_ref() {
+            Obj::String { str } => write_string(json, str),
+            Obj::Array { .. } if seen.contains(&Rc::as_ptr(it)) => Err(RuntimeErrorWithReason(
+                "Can't convert an array that contains itself to json",
+            ))?,
+            Obj::Array { elements } => {
+                seen.push(Rc::as_ptr(it));
+                json.push('[');
+                for (i, it) in elements.borrow().iter().enumerate() {
+                    if i > 0 {
+                        json.push(',');
+                    }
+                    write_value(json, it, seen)?;
+                }
+                json.push(']');
+                seen.pop();
+            }
+            Obj::Function { .. } | Obj::Native { .. } => {
+                Err(RuntimeErrorWithReason("Can't convert functions to json"))?
+            }
+        },
+    }
+    Ok(())
+}
+
+fn write_string(json: &mut String, str: &str) {
+    json.push('"');
+    for it in str.chars() {
+        match it {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            '\u{8}' => json.push_str("\\b"),
+            '\u{c}' => json.push_str("\\f"),
+            it if it < ' ' => write!(json, "\\u{:04x}", it as u32).unwrap(),
+            it => json.push(it),
+        }
+    }
+    json.push('"');
+}
+
+pub fn from_json(runtime: &mut dyn Runtime, json: &str) -> Result<Value, InterpretError> {
+    let mut reader = Reader {
+        runtime,
+        json,
+        at: 0,
+    };
+    let value = reader.value(0)?;
+    reader.skip_whitespace();
+    match reader.at == json.len() {
+        true => Ok(value),
+        false => Err(MALFORMED),
+    }
+}
+
+const MALFORMED: InterpretError = RuntimeErrorWithReason("Malformed json");
+
+struct Reader<'a> {
+    runtime: &'a mut dyn Runtime,
+    json: &'a str,
+    // Byte offset of the next character to read
+    at: usize,
+}
+
+impl Reader<'_> {
+    fn value(&mut self, depth: usize) -> Result<Value, InterpretError> {
+        self.skip_whitespace();
+        match self.peek().ok_or(MALFORMED)? {
+            b'n' => self.literal("null", Value::Nil),
+            b't' => self.literal("true", Value::Bool(true)),
+            b'f' => self.literal("false", Value::Bool(false)),
+            b'"' => {
+                let it = self.string()?;
+                Ok(self.runtime.intern_value(it))
+            }
+            b'[' => self.array(depth),
+            b'{' => Err(RuntimeErrorWithReason(
+                "Lox has no maps to read json objects into",
+            )),
+            b'-' | b'0'..=b'9' => self.number(),
+            _ => Err(MALFORMED),
+        }
+    }
+
+    fn literal(&mut self, literal: &str, value: Value) -> Result<Value, InterpretError> {
+        match self.json[self.at..].starts_with(literal) {
+            true => {
+                self.at += literal.len();
+                Ok(value)
+            }
+            false => Err(MALFORMED),
+        }
+    }
+
+    fn array(&mut self, depth: usize) -> Result<Value, InterpretError> {
+        if depth == MAX_DEPTH {
+            Err(RuntimeErrorWithReason("Json nested too deep"))?
+        }
+        self.at += 1; // [
+        let mut elements = Vec::new();
+        self.skip_whitespace();
+        if self.peek() == Some(b']') {
+            self.at += 1;
+        } else {
+            loop {
+                elements.push(self.value(depth + 1)?);
+                self.skip_whitespace();
+                match self.advance().ok_or(MALFORMED)? {
+                    b',' => continue,
+                    b']' => break,
+                    _ => Err(MALFORMED)?,
+                }
+            }
+        }
+        Ok(self.runtime.alloc_value(Obj::Array {
+            elements: RefCell::new(elements),
+        }))
+    }
+
+    // Without fraction or exponent it is an int, when it fits
+    fn number(&mut self) -> Result<Value, InterpretError> {
+        let start = self.at;
+        self.skip(b'-');
+        match self.advance() {
+            Some(b'0') => {}
+            Some(b'1'..=b'9') => self.digits(),
+            _ => Err(MALFORMED)?,
+        }
+        let mut whole = true;
+        if self.skip(b'.') {
+            whole = false;
+            self.at_least_one_digit()?;
+        }
+        if self.skip(b'e') || self.skip(b'E') {
+            whole = false;
+            let _ = self.skip(b'+') || self.skip(b'-');
+            self.at_least_one_digit()?;
+        }
+        let it = &self.json[start..self.at];
+        match it.parse::<i64>() {
+            Ok(it) if whole => Ok(Value::Int(it)),
+            _ => it.parse::<f64>().map(Value::Number).map_err(|_| MALFORMED),
+        }
+    }
+
+    fn at_least_one_digit(&mut self) -> Result<(), InterpretError> {
+        match self.advance() {
+            Some(b'0'..=b'9') => {
+                self.digits();
+                Ok(())
+            }
+            _ => Err(MALFORMED),
+        }
+    }
+
+    fn digits(&mut self) {
+        while matches!(self.peek(), Some(b'0'..=b'9')) {
+            self.at += 1;
+        }
+    }
+
+    fn string(&mut self) -> Result<String, InterpretError> {
+        self.at += 1; // "
+        let mut str = String::new();
+        loop {
+            // Copy the run up to the next quote or escape as is, it is valid utf-8 already
+            let start = self.at;
+            while matches!(self.peek(), Some(it) if it != b'"' && it != b'\\' && it >= b' ') {
+                self.at += 1;
+            }
+            str.push_str(&self.json[start..self.at]);
+            match self.advance().ok_or(MALFORMED)? {
+                b'"' => return Ok(str),
+                b'\\' => str.push(self.escape()?),
+                // Control characters have to be escaped
+                _ => Err(MALFORMED)?,
+            }
+        }
+    }
+
+    fn escape(&mut self) -> Result<char, InterpretError> {
+        let it = match self.advance().ok_or(MALFORMED)? {
+            b'"' => '"',
+            b'\\' => '\\',
+            b'/' => '/',
+            b'b' => '\u{8}',
+            b'f' => '\u{c}',
+            b'n' => '\n',
+            b'r' => '\r',
+            b't' => '\t',
+            b'u' => {
+                let high = self.hex()?;
+                // Outside the basic plane a character is written as two escaped surrogates
+                let code = if (0xD800..0xDC00).contains(&high) {
+                    if !self.json[self.at..].starts_with("\\u") {
+                        Err(MALFORMED)?
+                    }
+                    self.at += 2;
+                    let low = self.hex()?;
+                    if !(0xDC00..0xE000).contains(&low) {
+                        Err(MALFORMED)?
+                    }
+                    0x10000 + ((high - 0xD800) << 10) + (low - 0xDC00)
+                } else {
+                    high
+                };
+                char::from_u32(code).ok_or(MALFORMED)?
+            }
+            _ => Err(MALFORMED)?,
+        };
+        Ok(it)
+    }
+
+    fn hex(&mut self) -> Result<u32, InterpretError> {
+        let it = self.json.get(self.at..self.at + 4).ok_or(MALFORMED)?;
+        if !it.bytes().all(|it| it.is_ascii_hexdigit()) {
+            Err(MALFORMED)?
+        }
+        self.at += 4;
+        u32::from_str_radix(it, 16).map_err(|_| MALFORMED)
+    }
+
+    fn skip_whitespace(&mut self) {
+        while matches!(self.peek(), Some(b' ' | b'\t' | b'\n' | b'\r')) {
+            self.at += 1;
+        }
+    }
+
+    fn skip(&mut self, byte: u8) -> bool {
+        let it = self.peek() == Some(byte);
+        if it {
+            self.at += 1;
+        }
+        it
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.json.as_bytes().get(self.at).copied()
+    }
+
+    fn advance(&mut self) -> Option<u8> {
+        let it = self.peek();
+        self.at += 1;
+        it
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn array(elements: Vec<Value>) -> Value {
+        Value::Object(Rc::new(Obj::Array {
+            elements: RefCell::new(elements),
+        }))
+    }
+
+    fn string(str: &str) -> Value {
+        Value::Object(Rc::new(Obj::String {
+            str: str.to_string(),
+        }))
+    }
+
+    #[test]
+    fn to_json_scalars() {
+        for (value, expected) in [
+            (Value::Nil, "null"),
+            (Value::Bool(true), "true"),
+            (Value::Bool(false), "false"),
+            (Value::Int(-42), "-42"),
+            (Value::Number(1.5), "1.5"),
+            (Value::Number(2.0), "2.0"),
+            (string("lox"), "\"lox\""),
+            (
+                string("\"a\\b\"\n\t\u{1}é"),
+                "\"\\\"a\\\\b\\\"\\n\\t\\u0001é\"",
+            ),
+        ] {
+            assert_eq!(to_json(&value).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn to_json_arrays() {
+        let shared = array(vec![Value::Int(1)]);
+        let value = array(vec![shared.clone(), array(vec![]), shared, string("x")]);
+        assert_eq!(to_json(&value).unwrap(), "[[1],[],[1],\"x\"]");
+    }
+
+    #[test]
+    fn to_json_errors() {
+        let cyclic = array(vec![]);
+        if let Some(Obj::Array { elements }) = cyclic.as_object() {
+            elements.borrow_mut().push(array(vec![cyclic.clone()]));
+        }
+        assert!(matches!(
+            to_json(&cyclic),
+            Err(RuntimeErrorWithReason(
+                "Can't convert an array that contains itself to json"
+            ))
+        ));
+        // Break the cycle so the test doesn't leak it
+        if let Some(Obj::Array { elements }) = cyclic.as_object() {
+            elements.borrow_mut().clear();
+        }
+
+        assert!(to_json(&Value::Number(f64::NAN)).is_err());
+        assert!(to_json(&Value::Number(f64::INFINITY)).is_err());
+    }
+}
@@ -0,0 +1,144 @@
+use crate::opcode::{NativeFn, Obj, Runtime, Value};
+use crate::vm::globals::Globals;
+use crate::vm::json;
+use crate::vm::InterpretError;
+use crate::vm::InterpretError::RuntimeErrorWithReason;
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Natives are globals bound to functions implemented in Rust
+
+// The natives every program can call
+pub fn define_all(globals: &mut Globals) {
+    define(globals, "clock", clock);
+    define(globals, "contains", contains);
+    define(globals, "index_of", index_of);
+    define(globals, "map", map);
+    define(globals, "filter", filter);
+    define(globals, "reduce", reduce);
+    define(globals, "to_json", to_json);
+    define(globals, "from_json", from_json);
+}
+
+pub fn define(globals: &mut Globals, name: &str, function: NativeFn) {
+    let native = Obj::Native {
+        name: name.to_string(),
+        function,
+    };
+    globals.insert(name.to_string(), Value::Object(Rc::new(native)));
+}
+
+// Seconds since the unix epoch, handy to time a script
+fn clock(_: &mut dyn Runtime, _: &[Value]) -> Result<Value, InterpretError> {
+    let it = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    Ok(Value::Number(it.as_secs_f64()))
+}
+
+// `contains(haystack, needle)` is the needle part of the haystack string
+fn contains(_: &mut dyn Runtime, arguments: &[Value]) -> Result<Value, InterpretError> {
+    let (haystack, needle) = search_arguments(arguments)?;
+    Ok(Value::Bool(haystack.contains(needle)))
+}
+
+// `index_of(haystack, needle)` the position in characters of the needle or -1
+fn index_of(_: &mut dyn Runtime, arguments: &[Value]) -> Result<Value, InterpretError> {
+    let (haystack, needle) = search_arguments(arguments)?;
+    let it = match haystack.find(needle) {
+        Some(at) => haystack[..at].chars().count() as i64,
+        None => -1,
+    };
+    Ok(Value::Int(it))
+}
+
+// `to_json(value)` the value as a json string
+fn to_json(runtime: &mut dyn Runtime, arguments: &[Value]) -> Result<Value, InterpretError> {
+    let [value] = arguments else {
+        Err(RuntimeErrorWithReason("Wrong number of arguments"))?
+    };
+    let it = json::to_json(value)?;
+    Ok(runtime.intern_value(it))
+}
+
+// `from_json(str)` the value the json string describes
+fn from_json(runtime: &mut dyn Runtime, arguments: &[Value]) -> Result<Value, InterpretError> {
+    match arguments {
+        [json] if json.is_string() => json::from_json(runtime, json.as_string()),
+        [_] => Err(RuntimeErrorWithReason("Can only read json from a string")),
+        _ => Err(RuntimeErrorWithReason("Wrong number of arguments")),
+    }
+}
+
+fn search_arguments(arguments: &[Value]) -> Result<(&str, &str), InterpretError> {
+    match arguments {
+        [haystack, needle] if haystack.is_string() && needle.is_string() => {
+            Ok((haystack.as_string(), needle.as_string()))
+        }
+        [_, _] => Err(RuntimeErrorWithReason("Can only search in strings")),
+        _ => Err(RuntimeErrorWithReason("Wrong number of arguments")),
+    }
+}
+
+// `map(array, function)` a new array with the result of the function for every element
+fn map(runtime: &mut dyn Runtime, arguments: &[Value]) -> Result<Value, InterpretError> {
+    let [array, function] = arguments else {
+        Err(RuntimeErrorWithReason("Wrong number of arguments"))?
+    };
+    let mut mapped = Vec::new();
+    for it in elements_to_call_with(array, function)? {
+        mapped.push(runtime.call_value(function, &[it])?);
+    }
+    Ok(array_value(runtime, mapped))
+}
+
+// `filter(array, predicate)` a new array with the elements the predicate is truthy for
+fn filter(runtime: &mut dyn Runtime, arguments: &[Value]) -> Result<Value, InterpretError> {
+    let [array, predicate] = arguments else {
+        Err(RuntimeErrorWithReason("Wrong number of arguments"))?
+    };
+    let mut kept = Vec::new();
+    for it in elements_to_call_with(array, predicate)? {
+        if runtime
+            .call_value(predicate, std::slice::from_ref(&it))?
+            .is_truthy()
+        {
+            kept.push(it);
+        }
+    }
+    Ok(array_value(runtime, kept))
+}
+
+// `reduce(array, function, initial)` folds the elements into one value, left to right
+fn reduce(runtime: &mut dyn Runtime, arguments: &[Value]) -> Result<Value, InterpretError> {
+    let [array, function, initial] = arguments else {
+        Err(RuntimeErrorWithReason("Wrong number of arguments"))?
+    };
+    let mut it = initial.clone();
+    for element in elements_to_call_with(array, function)? {
+        it = runtime.call_value(function, &[it, element])?;
+    }
+    Ok(it)
+}
+
+// A copy of the elements, the function may change the array while we go over it. The function
+// is checked up front so an empty array rejects what can't be called too.
+fn elements_to_call_with(array: &Value, function: &Value) -> Result<Vec<Value>, InterpretError> {
+    let elements = match array.as_object() {
+        Some(Obj::Array { elements }) => elements.borrow().clone(),
+        _ => Err(RuntimeErrorWithReason("Expected an array"))?,
+    };
+    match function.as_object() {
+        Some(Obj::Function { .. } | Obj::Native { .. }) => Ok(elements),
+        _ => Err(RuntimeErrorWithReason(
+            "Can only call functions and classes",
+        )),
+    }
+}
+
+fn array_value(runtime: &mut dyn Runtime, elements: Vec<Value>) -> Value {
+    runtime.alloc_value(Obj::Array {
+        elements: RefCell::new(elements),
+    })
+}
@@ -18,7 +18,7 @@ impl Stack {
 
     pub fn peek(&self, offset: usize) -> Option<&Value> {
         // Peek from the back of the vec as values are popped from the back
-        let offset = self.0.len() - 1 - offset;
+        let offset = self.0.len().checked_sub(offset + 1)?;
         self.0.get(offset)
     }
 
@@ -26,11 +26,73 @@ impl Stack {
         self.0.get(at)
     }
 
-    pub fn set(&mut self, at: usize, value: Value) {
-        self.0[at] = value;
+    // None when there is no value at `at` to replace
+    pub fn set(&mut self, at: usize, value: Value) -> Option<()> {
+        *self.0.get_mut(at)? = value;
+        Some(())
+    }
+
+    // Takes the top `n` values off the stack in the order they were pushed
+    pub fn pop_n(&mut self, n: usize) -> Option<Vec<Value>> {
+        let at = self.0.len().checked_sub(n)?;
+        Some(self.0.split_off(at))
+    }
+
+    // All values, bottom first
+    pub fn values(&self) -> &[Value] {
+        &self.0
+    }
+
+    // The last `count` values, bottom first
+    pub fn top(&self, count: usize) -> &[Value] {
+        &self.0[self.0.len().saturating_sub(count)..]
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    // Drops everything from `len` up
+    pub fn truncate(&mut self, len: usize) {
+        self.0.truncate(len)
     }
 
     pub fn is_empty(&self) -> bool {
         self.0.is_empty()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pop_n_keeps_push_order() {
+        let mut stack = Stack::new();
+        for it in 1..=4 {
+            stack.push(Value::Number(it as f64));
+        }
+
+        let it = stack.pop_n(3).unwrap();
+        assert_eq!(
+            it,
+            vec![Value::Number(2.0), Value::Number(3.0), Value::Number(4.0)]
+        );
+        assert_eq!(stack.top(4), &[Value::Number(1.0)]);
+        assert_eq!(stack.pop_n(2), None);
+        assert_eq!(stack.pop_n(0), Some(vec![]));
+    }
+
+    #[test]
+    fn out_of_bounds_is_none() {
+        let mut stack = Stack::new();
+        assert_eq!(stack.peek(0), None);
+        assert_eq!(stack.set(0, Value::Nil), None);
+
+        stack.push(Value::Nil);
+        assert_eq!(stack.peek(0), Some(&Value::Nil));
+        assert_eq!(stack.peek(8), None);
+        assert_eq!(stack.set(0, Value::Bool(true)), Some(()));
+        assert_eq!(stack.set(1, Value::Nil), None);
+    }
+}
@@ -0,0 +1,22 @@
+use std::process::Command;
+
+fn run(arguments: &[&str]) -> String {
+    let output = Command::new(env!("CARGO_BIN_EXE_rlox"))
+        .args(arguments)
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{:?}", output);
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[test]
+fn runs_without_printing_globals() {
+    let stdout = run(&["-e", "var answer = 42; print answer;"]);
+    assert!(stdout.lines().any(|it| it == "42"), "{}", stdout);
+    assert!(!stdout.contains("Globals:"), "{}", stdout);
+}
+
+#[test]
+fn runs_without_tracing() {
+    assert_eq!(run(&["-e", "print 1 + 2;"]), "3\nnil\n");
+}